@@ -0,0 +1,274 @@
+//! Visitor access control: credentials and IP allow/deny lists checked before visitor
+//! traffic is forwarded. A successful login is remembered in a signed cookie.
+
+use std::{
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose, Engine as _};
+use ipnet::IpNet;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{cookie_value, escape_html, secrets_match};
+
+/// Cookie remembering a visitor who already logged in to a session.
+pub const AUTH_COOKIE: &str = "tunly_auth";
+
+/// Path (below the session prefix) the passphrase form posts to.
+pub const LOGIN_PATH: &str = "_tunly/auth";
+
+// Visitor login lifetime
+const COOKIE_TTL_SECS: u64 = 12 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VisitorAuth {
+    /// HTTP basic auth with a fixed user and password
+    Basic { user: String, pass: String },
+    /// Shared passphrase entered in a login form (or as any basic-auth password)
+    Passphrase(String),
+}
+
+impl VisitorAuth {
+    /// Parse `basic <user>:<pass>` or `passphrase <secret>` as sent by the client on /ws.
+    pub fn parse(value: &str) -> Result<Self, &'static str> {
+        let (scheme, rest) = value.trim().split_once(' ').ok_or("invalid visitor auth")?;
+        match scheme.to_ascii_lowercase().as_str() {
+            "basic" => match rest.split_once(':') {
+                Some((user, pass)) if !user.is_empty() && !pass.is_empty() => Ok(Self::Basic {
+                    user: user.to_string(),
+                    pass: pass.to_string(),
+                }),
+                _ => Err("basic visitor auth must be user:pass"),
+            },
+            "passphrase" if !rest.is_empty() => Ok(Self::Passphrase(rest.to_string())),
+            _ => Err("invalid visitor auth"),
+        }
+    }
+
+    pub fn to_header_value(&self) -> String {
+        match self {
+            Self::Basic { user, pass } => format!("basic {}:{}", user, pass),
+            Self::Passphrase(p) => format!("passphrase {}", p),
+        }
+    }
+
+    /// Changes whenever the credentials do, so old login cookies stop working.
+    fn fingerprint(&self) -> String {
+        Sha256::digest(self.to_header_value().as_bytes())[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn accepts(&self, user: &str, pass: &str) -> bool {
+        match self {
+            Self::Basic { user: u, pass: p } => secrets_match(u, user) & secrets_match(p, pass),
+            Self::Passphrase(p) => secrets_match(p, pass),
+        }
+    }
+
+    /// Check a submitted passphrase (login form).
+    pub fn accepts_passphrase(&self, pass: &str) -> bool {
+        matches!(self, Self::Passphrase(_)) && self.accepts("", pass)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VisitorClaims {
+    sub: String, // sid
+    cred: String,
+    exp: usize,
+}
+
+/// Outcome of checking a visitor request against the session's credentials.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Valid login cookie
+    Cookie,
+    /// Valid `Authorization: Basic`; the response should set a login cookie
+    Credentials,
+    Denied,
+}
+
+pub fn check(auth: &VisitorAuth, secret: &[u8], sid: &str, headers: &HeaderMap) -> Access {
+    if let Some(cookie) = cookie_value(headers, AUTH_COOKIE) {
+        if let Ok(data) = decode::<VisitorClaims>(
+            &cookie,
+            &DecodingKey::from_secret(secret),
+            &Validation::default(),
+        ) {
+            if data.claims.sub == sid && data.claims.cred == auth.fingerprint() {
+                return Access::Cookie;
+            }
+        }
+    }
+    if has_tunnel_credentials(auth, headers) {
+        return Access::Credentials;
+    }
+    Access::Denied
+}
+
+// Whether `Authorization: Basic` carries credentials `auth` accepts
+fn has_tunnel_credentials(auth: &VisitorAuth, headers: &HeaderMap) -> bool {
+    let basic = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|b64| general_purpose::STANDARD.decode(b64.trim()).ok())
+        .and_then(|raw| String::from_utf8(raw).ok());
+    basic
+        .as_deref()
+        .and_then(|b| b.split_once(':'))
+        .is_some_and(|(user, pass)| auth.accepts(user, pass))
+}
+
+/// Remove the tunnel's own credentials (its basic auth and login cookie) from a visitor
+/// request that passed [`check`], so the local app never sees them. Other cookies and an
+/// `Authorization` header meant for the app are left alone.
+pub fn strip_credentials(auth: &VisitorAuth, headers: &mut HeaderMap) {
+    if has_tunnel_credentials(auth, headers) {
+        headers.remove(header::AUTHORIZATION);
+    }
+    let values: Vec<HeaderValue> = headers.get_all(header::COOKIE).iter().cloned().collect();
+    headers.remove(header::COOKIE);
+    for value in values {
+        let Ok(raw) = value.to_str() else {
+            headers.append(header::COOKIE, value);
+            continue;
+        };
+        let kept: Vec<&str> = raw
+            .split(';')
+            .map(str::trim)
+            .filter(|c| !c.is_empty() && c.split('=').next() != Some(AUTH_COOKIE))
+            .collect();
+        if let Ok(value) = HeaderValue::from_str(&kept.join("; ")) {
+            if !kept.is_empty() {
+                headers.append(header::COOKIE, value);
+            }
+        }
+    }
+}
+
+/// `Set-Cookie` value logging a visitor in to `sid`; `path` scopes it to the session's URLs.
+pub fn login_cookie(
+    auth: &VisitorAuth,
+    secret: &[u8],
+    sid: &str,
+    path: &str,
+) -> Option<HeaderValue> {
+    let exp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + COOKIE_TTL_SECS;
+    let claims = VisitorClaims {
+        sub: sid.to_string(),
+        cred: auth.fingerprint(),
+        exp: exp as usize,
+    };
+    let token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret),
+    )
+    .ok()?;
+    HeaderValue::from_str(&format!(
+        "{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Lax",
+        AUTH_COOKIE, token, path, COOKIE_TTL_SECS
+    ))
+    .ok()
+}
+
+/// 401 for a visitor without valid credentials: a basic-auth prompt, or the passphrase form.
+pub fn challenge(auth: &VisitorAuth, login_action: &str, next: &str) -> Response {
+    match auth {
+        VisitorAuth::Basic { .. } => (
+            StatusCode::UNAUTHORIZED,
+            [
+                (
+                    header::WWW_AUTHENTICATE,
+                    "Basic realm=\"tunly\", charset=\"UTF-8\"",
+                ),
+                (header::CACHE_CONTROL, "no-store"),
+            ],
+            "authentication required",
+        )
+            .into_response(),
+        VisitorAuth::Passphrase(_) => {
+            let html = format!(
+                "<!doctype html><meta charset=\"utf-8\"><title>Protected tunnel</title><style>body{{font-family:system-ui,-apple-system,Segoe UI,Roboto,Ubuntu,\"Helvetica Neue\",Arial,sans-serif;padding:20px}}</style><h1>This tunnel is protected</h1><form method=\"post\" action=\"{}\"><input type=\"hidden\" name=\"next\" value=\"{}\"><input type=\"password\" name=\"passphrase\" placeholder=\"Passphrase\" autofocus> <button type=\"submit\">Continue</button></form>",
+                escape_html(login_action),
+                escape_html(next)
+            );
+            (
+                StatusCode::UNAUTHORIZED,
+                [
+                    (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+                    (header::CACHE_CONTROL, "no-store"),
+                ],
+                html,
+            )
+                .into_response()
+        }
+    }
+}
+
+/// CIDR allow/deny lists for visitor IPs. Deny entries win; a non-empty allow list admits
+/// only the addresses it covers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// An address we could not determine only passes when no lists are set.
+    pub fn permits(&self, ip: Option<IpAddr>) -> bool {
+        let Some(ip) = ip.map(|ip| ip.to_canonical()) else {
+            return self.is_empty();
+        };
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Parse a CIDR (`10.0.0.0/8`) or a bare address (`203.0.113.7`, taken as a single host).
+pub fn parse_net(value: &str) -> Result<IpNet, String> {
+    let value = value.trim();
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid CIDR or IP address: {}", value))
+}
+
+/// Parse a comma-separated list of CIDRs or addresses, as sent by the client on /ws.
+pub fn parse_net_list(value: &str) -> Result<Vec<IpNet>, String> {
+    value
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(parse_net)
+        .collect()
+}
+
+/// 403 for a visitor whose address is not allowed to reach the tunnel.
+pub fn forbidden(ip: &str) -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        [(header::CACHE_CONTROL, "no-store")],
+        format!("forbidden: {} is not allowed to access this tunnel", ip),
+    )
+        .into_response()
+}
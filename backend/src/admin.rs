@@ -0,0 +1,348 @@
+//! Operator API under `/admin/api`, enabled by setting an admin token (`--admin-token` or
+//! `[policy] admin_token`). Every request carries `Authorization: Bearer <admin token>`.
+//!
+//! - `GET /sessions`: connected tunnels
+//! - `GET /sessions/{sid}` / `GET /sessions/{sid}/log`: one tunnel and its access log
+//! - `DELETE /sessions/{sid}`: disconnect a tunnel; its sid stays blocked for the resume grace
+//! - `GET /tokens`, `DELETE /tokens[?sid=]`: outstanding ephemeral tokens
+//! - `DELETE /tokens/{jti}`: revoke an ephemeral or access token, disconnecting its tunnels
+//! - `POST /tokens`: mint an ephemeral token
+//! - `GET /stats`: server-wide counters
+
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc},
+    time::{Instant, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    generate_secret, generate_session_id, issue_token, secrets_match, store, AccessLogEntry,
+    AppState, AuthMode, CloseReason, Reservation, SessionState,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub sid: String,
+    /// Unix seconds
+    pub created_at: u64,
+    /// Seconds since the last message in either direction
+    pub idle_secs: u64,
+    pub client_ip: Option<String>,
+    /// Holder of the access token the client connected with
+    pub owner: Option<String>,
+    pub requests: u64,
+    pub in_flight: usize,
+    pub tcp_port: Option<u16>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub jti: String,
+    pub sid: String,
+    pub ip: String,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Revoked {
+    pub revoked: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccessLog {
+    /// Entries ever logged for the session; `entries` holds the most recent ones
+    pub total: u64,
+    pub entries: Vec<AccessLogEntry>,
+}
+
+/// `POST /tokens` body; every field is optional.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MintRequest {
+    /// Session (or name) the token is for; random when unset
+    pub sid: Option<String>,
+    /// Lifetime, defaulting to `[limits] token_ttl_secs`
+    pub ttl_secs: Option<u64>,
+    /// Bind the token to this client IP; usable from anywhere when unset
+    pub ip: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MintedToken {
+    pub jti: String,
+    pub token: String,
+    pub session: String,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stats {
+    pub sessions: usize,
+    pub tcp_sessions: usize,
+    pub in_flight: usize,
+    pub outstanding_tokens: usize,
+    pub proxy_requests_total: u64,
+    pub tcp_connections_total: u64,
+    pub ip_denied_total: u64,
+    pub rate_limited_total: u64,
+    pub rate_limit_buckets: usize,
+    pub draining: bool,
+}
+
+pub fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/{sid}", get(get_session).delete(kill_session))
+        .route("/sessions/{sid}/log", get(session_log))
+        .route(
+            "/tokens",
+            get(list_tokens).post(mint_token).delete(revoke_tokens),
+        )
+        .route("/tokens/{jti}", axum::routing::delete(revoke_token))
+        .route("/stats", get(stats))
+        .layer(axum::middleware::from_fn_with_state(state, require_admin))
+}
+
+/// Without a configured admin token the API does not exist.
+async fn require_admin(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let Some(expected) = state.settings().admin_token.clone() else {
+        return (StatusCode::NOT_FOUND, "not found").into_response();
+    };
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if !provided.is_some_and(|token| secrets_match(&expected, token)) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "invalid admin token",
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+async fn session_info(sid: &str, sess: &SessionState) -> SessionInfo {
+    let created_at = sess
+        .created_at
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let idle_secs = sess.last_seen.lock().await.elapsed().as_secs();
+    let in_flight = sess.pending.lock().await.len() + sess.response_bodies.lock().await.len();
+    SessionInfo {
+        sid: sid.to_string(),
+        created_at,
+        idle_secs,
+        client_ip: sess.client_ip.clone(),
+        owner: sess.credential.as_ref().map(|c| c.sub.clone()),
+        requests: sess.requests.load(Ordering::Relaxed),
+        in_flight,
+        tcp_port: sess.tcp_port,
+    }
+}
+
+async fn find_session(state: &AppState, sid: &str) -> Result<Arc<SessionState>, Response> {
+    let maybe = { state.sessions.read().await.get(sid).cloned() };
+    maybe.ok_or_else(|| (StatusCode::NOT_FOUND, "session not found").into_response())
+}
+
+async fn list_sessions(State(state): State<Arc<AppState>>) -> Json<Vec<SessionInfo>> {
+    let mut entries: Vec<_> = {
+        let sessions = state.sessions.read().await;
+        sessions
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    };
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut list = Vec::with_capacity(entries.len());
+    for (sid, sess) in entries {
+        list.push(session_info(&sid, &sess).await);
+    }
+    Json(list)
+}
+
+async fn get_session(
+    State(state): State<Arc<AppState>>,
+    Path(sid): Path<String>,
+) -> Result<Json<SessionInfo>, Response> {
+    let sess = find_session(&state, &sid).await?;
+    Ok(Json(session_info(&sid, &sess).await))
+}
+
+/// Oldest first, like the ring buffer itself.
+async fn session_log(
+    State(state): State<Arc<AppState>>,
+    Path(sid): Path<String>,
+) -> Result<Json<AccessLog>, Response> {
+    let sess = find_session(&state, &sid).await?;
+    let log = sess.access_log.lock().await;
+    Ok(Json(AccessLog {
+        total: sess.access_logged.load(Ordering::Relaxed),
+        entries: log.clone(),
+    }))
+}
+
+async fn kill_session(
+    State(state): State<Arc<AppState>>,
+    Path(sid): Path<String>,
+) -> Result<StatusCode, Response> {
+    let sess = find_session(&state, &sid).await?;
+    // Block the sid and drop the refresh credential first so the client cannot come back; the
+    // reservation's fresh secret is known to nobody, so it cannot be resumed either
+    state.reservations.lock().await.insert(
+        sid.clone(),
+        Reservation {
+            secret: generate_secret(),
+            expires: Some(Instant::now() + state.settings().resume_grace()),
+            tcp_port: None,
+            credential: None,
+            client_key: None,
+            blocked: true,
+        },
+    );
+    state.store.drop_refresh(&sid).await;
+    sess.close(CloseReason::Terminated);
+    tracing::info!("Admin: disconnecting session {}", sid);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_tokens(State(state): State<Arc<AppState>>) -> Json<Vec<TokenInfo>> {
+    let now = Instant::now();
+    let issued = state.issued_tokens.lock().await;
+    let mut list: Vec<_> = issued
+        .iter()
+        .filter(|(_, (_, exp, _))| *exp > now)
+        .map(|(jti, (ip, exp, sid))| TokenInfo {
+            jti: jti.clone(),
+            sid: sid.clone(),
+            ip: ip.clone(),
+            expires_in: exp.duration_since(now).as_secs(),
+        })
+        .collect();
+    list.sort_by_key(|t| t.expires_in);
+    Json(list)
+}
+
+/// Outstanding ephemeral tokens are simply dropped; any other jti is taken to be an access
+/// token and recorded as revoked, ending the tunnels it opened.
+async fn revoke_token(
+    State(state): State<Arc<AppState>>,
+    Path(jti): Path<String>,
+) -> Result<StatusCode, Response> {
+    if state.issued_tokens.lock().await.remove(&jti).is_some() {
+        tracing::info!("Admin: revoked token {}", jti);
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    if let Err(e) = state.store.revoke(&jti).await {
+        tracing::error!("Failed to revoke token {}: {}", jti, e);
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to revoke token").into_response());
+    }
+    let holds = |c: &Option<crate::tokens::Credential>| c.as_ref().is_some_and(|c| c.jti == jti);
+    state
+        .reservations
+        .lock()
+        .await
+        .retain(|_, r| !holds(&r.credential));
+    let mut ended = 0;
+    for sess in state.sessions.read().await.values() {
+        if holds(&sess.credential) {
+            sess.close(CloseReason::Revoked);
+            ended += 1;
+        }
+    }
+    tracing::info!(
+        "Admin: revoked access token {} ({} session(s) ended)",
+        jti,
+        ended
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn mint_token(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<MintRequest>,
+) -> Result<Json<MintedToken>, Response> {
+    if let AuthMode::Fixed(_) = state.auth_mode {
+        return Err((
+            StatusCode::CONFLICT,
+            "server uses a fixed token; ephemeral tokens are disabled",
+        )
+            .into_response());
+    }
+    let sid = match req.sid.filter(|s| !s.is_empty()) {
+        Some(sid) => {
+            store::validate_name(&sid)
+                .map_err(|msg| (StatusCode::BAD_REQUEST, msg).into_response())?;
+            sid
+        }
+        None => generate_session_id(),
+    };
+    let ttl = req
+        .ttl_secs
+        .unwrap_or(state.settings().limits.token_ttl_secs);
+    let ip = req.ip.unwrap_or_default();
+    match issue_token(&state, &sid, &ip, None, ttl).await {
+        Ok((jti, token)) => {
+            tracing::info!("Admin: minted token {} for session {}", jti, sid);
+            Ok(Json(MintedToken {
+                jti,
+                token,
+                session: sid,
+                expires_in: ttl,
+            }))
+        }
+        Err(e) => {
+            tracing::error!("Failed to encode JWT: {}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "token generation failed").into_response())
+        }
+    }
+}
+
+/// Revoke every outstanding token, or only those for `?sid=`.
+async fn revoke_tokens(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Json<Revoked> {
+    let sid = params.get("sid");
+    let mut issued = state.issued_tokens.lock().await;
+    let before = issued.len();
+    issued.retain(|_, (_, _, token_sid)| sid.is_some_and(|s| s != token_sid));
+    let revoked = before - issued.len();
+    tracing::info!("Admin: revoked {} token(s)", revoked);
+    Json(Revoked { revoked })
+}
+
+async fn stats(State(state): State<Arc<AppState>>) -> Json<Stats> {
+    let sessions: Vec<_> = state.sessions.read().await.values().cloned().collect();
+    let mut in_flight = 0;
+    for sess in &sessions {
+        in_flight += sess.pending.lock().await.len() + sess.response_bodies.lock().await.len();
+    }
+    let metrics = &state.metrics;
+    Json(Stats {
+        sessions: sessions.len(),
+        tcp_sessions: sessions.iter().filter(|s| s.tcp_port.is_some()).count(),
+        in_flight,
+        outstanding_tokens: state.issued_tokens.lock().await.len(),
+        proxy_requests_total: metrics.proxy_requests.get() as u64,
+        tcp_connections_total: metrics.tcp_connections.get() as u64,
+        ip_denied_total: metrics.ip_denied.get() as u64,
+        rate_limited_total: metrics.rate_limited.get() as u64,
+        rate_limit_buckets: state.rate_limits.len().await,
+        draining: state.is_draining(),
+    })
+}
@@ -1,20 +1,36 @@
 use std::{
+    collections::HashMap,
     fs,
     io::{self, Write},
+    path::PathBuf,
     time::{Duration, Instant},
 };
 
-use base64::{engine::general_purpose, Engine as _};
 use clap::Parser;
 use futures::{SinkExt, StreamExt};
-use rand::Rng;
+use ipnet::IpNet;
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::AbortHandle;
 use tokio::time::sleep;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::{Error as WsError, Message};
-use tunly::{ClientToServer, ProxyRequest, ProxyResponse, ServerToClient};
+use tunly::access::VisitorAuth;
+use tunly::pop::{self, Identity};
+use tunly::{
+    flow, frame, generate_session_id, BodyChunk, ClientToServer, CloseReason, ErrorMessage,
+    Features, Hello, MessageCode, ProxyRequest, ProxyResponse, RequestStart, ResponseStart,
+    ServerToClient, Severity, StreamEnd, TcpOpen, WsClose, WsFrame, WsFrameKind, WsOpen, WsOpened,
+    ALLOW_CIDR_HEADER, COMPRESSION_CODECS, DENY_CIDR_HEADER, FEATURES_HEADER, MIN_PROTOCOL_VERSION,
+    NAME_KEY_HEADER, PROTOCOL_HEADER, PROTOCOL_VERSION, PUBLIC_HOST_HEADER, RESUME_HEADER,
+    STREAM_CHUNK_SIZE, TCP_PORT_HEADER, VISITOR_AUTH_HEADER,
+};
+
+type RequestBodyTx = mpsc::Sender<Result<Vec<u8>, io::Error>>;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 struct TokenSession {
@@ -48,12 +64,54 @@ struct ClientArgs {
     /// Optional: URL to fetch token (JSON {token, session, expires_in} or plain text). Useful for ephemeral tokens, e.g. https://app.tunly.online/token
     #[arg(long)]
     token_url: Option<String>,
+
+    /// Expose --local as a raw TCP tunnel (SSH, Postgres, Redis...) on a server-allocated port
+    #[arg(long, default_value_t = false)]
+    tcp: bool,
+
+    /// Max proxied requests sent to the local target at once; further requests wait their turn
+    #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u32).range(1..))]
+    max_in_flight: u32,
+
+    /// Max proxied requests waiting for a free --max-in-flight slot; further requests are refused with 503
+    #[arg(long, default_value_t = 128)]
+    max_queued: u32,
+
+    /// Give up on the local target after this many seconds without progress (connecting, or waiting for the next part of its answer)
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    local_timeout_secs: u64,
+
+    /// Request a stable, named session (e.g. acme-demo) instead of a random one
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Key that claims --name and proves ownership later; defaults to the token on fixed-token servers
+    #[arg(long, env = "TUNLY_NAME_KEY")]
+    name_key: Option<String>,
+
+    /// Require visitors to log in with HTTP basic auth, e.g. --basic-auth user:pass
+    #[arg(long, value_parser = parse_basic_auth, conflicts_with = "passphrase")]
+    basic_auth: Option<VisitorAuth>,
+
+    /// Require visitors to enter a shared passphrase (env: TUNLY_PASSPHRASE)
+    #[arg(long, env = "TUNLY_PASSPHRASE")]
+    passphrase: Option<String>,
+
+    /// Only let visitors from these CIDRs in, e.g. --allow-cidr 203.0.113.0/24,198.51.100.7
+    #[arg(long, value_delimiter = ',', value_parser = tunly::access::parse_net)]
+    allow_cidr: Vec<IpNet>,
+
+    /// Turn away visitors from these CIDRs (wins over --allow-cidr)
+    #[arg(long, value_delimiter = ',', value_parser = tunly::access::parse_net)]
+    deny_cidr: Vec<IpNet>,
+
+    /// Key file proving this client's identity, created if missing; keeps reclaiming the same session across restarts. A fresh key is used per run otherwise (env: TUNLY_IDENTITY)
+    #[arg(long, env = "TUNLY_IDENTITY")]
+    identity: Option<PathBuf>,
 }
 
-fn generate_session_id() -> String {
-    let mut bytes = [0u8; 16];
-    rand::rng().fill_bytes(&mut bytes);
-    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+fn parse_basic_auth(value: &str) -> Result<VisitorAuth, String> {
+    VisitorAuth::parse(&format!("basic {}", value)).map_err(str::to_string)
 }
 
 #[tokio::main]
@@ -65,7 +123,10 @@ async fn main() {
 
     tracing::info!("Running Tunly Client. Press Ctrl+C to exit.");
 
+    let local_timeout = Duration::from_secs(args.local_timeout_secs);
     let http = reqwest::Client::builder()
+        .connect_timeout(local_timeout)
+        .read_timeout(local_timeout)
         .build()
         .expect("failed to build http client");
 
@@ -81,8 +142,33 @@ async fn main() {
         format!("/{}", args.path)
     };
 
+    // Key we prove possession of when connecting, instead of relying on a stable IP
+    let identity = match &args.identity {
+        Some(path) => Identity::load_or_create(path)
+            .unwrap_or_else(|e| panic!("failed to load --identity {}: {}", path.display(), e)),
+        None => Identity::generate(),
+    };
+    let api_base = format!(
+        "{}://{}{}",
+        if args.use_wss { "https" } else { "http" },
+        remote_host,
+        path.rsplit_once('/').map_or("", |(prefix, _)| prefix)
+    );
+    let challenge_url = format!("{}/challenge", api_base);
+    let refresh_url = format!("{}/token/refresh", api_base);
+
+    // Ephemeral tokens are bound to our key (and named sessions to that name)
+    let token_url = args.token_url.clone().map(|mut url| {
+        let sep = if url.contains('?') { '&' } else { '?' };
+        url = format!("{}{}pubkey={}", url, sep, identity.public_key());
+        if let Some(name) = &args.name {
+            url = format!("{}&name={}", url, urlencoding::encode(name));
+        }
+        url
+    });
+
     // Acquire token/session
-    let mut token_session = if let Some(url) = args.token_url.clone() {
+    let mut token_session = if let Some(url) = token_url.clone() {
         match http.get(&url).send().await {
             Ok(resp) => {
                 match resp.error_for_status() {
@@ -152,16 +238,34 @@ async fn main() {
     };
 
     let mut attempt: u32 = 0;
+    // Secret from the last successful connect; lets us reattach to the same sid
+    let mut resume_secret: Option<String> = None;
+    // Local target chosen on first connect, reused when resuming
+    let mut chosen_local: Option<String> = None;
+    // Latest refresh credential from the server; traded for a new token after a disconnect
+    let mut refresh_token: Option<String> = None;
 
     loop {
         // If session is still missing (e.g. manual token), generate one now.
         if token_session.session.trim().is_empty() {
-            token_session.session = generate_session_id();
+            token_session.session = new_session_id(&args);
         }
 
-        // If token missing, try auto-fetch from token-url first (Ephemeral mode)
+        // If token missing, try the refresh credential, then auto-fetch from token-url
+        // (Ephemeral mode)
         if token_session.token.trim().is_empty() {
-            if let Some(url) = args.token_url.clone() {
+            if let Some(credential) = refresh_token.take() {
+                match exchange_refresh_token(&http, &refresh_url, &credential).await {
+                    Ok(ts) => {
+                        tracing::info!("Got a new token for session {}", ts.session);
+                        token_session = ts;
+                    }
+                    Err(e) => tracing::warn!("Refresh credential not accepted: {}", e),
+                }
+            }
+        }
+        if token_session.token.trim().is_empty() {
+            if let Some(url) = token_url.clone() {
                 match http.get(&url).send().await {
                     Ok(resp) => match resp.error_for_status() {
                         Ok(ok) => {
@@ -231,10 +335,14 @@ async fn main() {
         }
 
         // Build current ws URL with session
-        let ws_url = format!(
-            "{}://{}{}?sid={}",
-            scheme, remote_host, path, token_session.session
+        let sid_param = if args.name.is_some() { "name" } else { "sid" };
+        let mut ws_url = format!(
+            "{}://{}{}?{}={}",
+            scheme, remote_host, path, sid_param, token_session.session
         );
+        if args.tcp {
+            ws_url.push_str("&tcp=1");
+        }
 
         attempt += 1;
         tracing::info!("Connecting to {} (attempt #{})...", ws_url, attempt);
@@ -257,6 +365,49 @@ async fn main() {
                 .parse()
                 .unwrap(),
         );
+        // Advertise optional protocol features (server answers with the accepted subset)
+        req.headers_mut().insert(
+            FEATURES_HEADER,
+            Features::supported().to_header_value().parse().unwrap(),
+        );
+        let visitor_auth = args
+            .basic_auth
+            .clone()
+            .or_else(|| args.passphrase.clone().map(VisitorAuth::Passphrase));
+        if let Some(auth) = visitor_auth.and_then(|a| a.to_header_value().parse().ok()) {
+            req.headers_mut().insert(VISITOR_AUTH_HEADER, auth);
+        }
+        for (name, nets) in [
+            (ALLOW_CIDR_HEADER, &args.allow_cidr),
+            (DENY_CIDR_HEADER, &args.deny_cidr),
+        ] {
+            if nets.is_empty() {
+                continue;
+            }
+            let list = nets.iter().map(IpNet::to_string).collect::<Vec<_>>();
+            if let Ok(value) = list.join(",").parse() {
+                req.headers_mut().insert(name, value);
+            }
+        }
+        if let Some(key) = args.name_key.as_ref().and_then(|k| k.parse().ok()) {
+            req.headers_mut().insert(NAME_KEY_HEADER, key);
+        }
+        if let Some(secret) = resume_secret.as_ref().and_then(|s| s.parse().ok()) {
+            req.headers_mut().insert(RESUME_HEADER, secret);
+        }
+        // Servers without proof of possession have no /challenge; connect without a proof then
+        if let Some(challenge) = fetch_challenge(&http, &challenge_url).await {
+            let signature = identity.sign(&challenge, &token_session.session);
+            for (name, value) in [
+                (pop::CLIENT_KEY_HEADER, identity.public_key()),
+                (pop::CHALLENGE_HEADER, challenge),
+                (pop::SIGNATURE_HEADER, signature),
+            ] {
+                if let Ok(value) = value.parse() {
+                    req.headers_mut().insert(name, value);
+                }
+            }
+        }
 
         match tokio_tungstenite::connect_async(req).await {
             Ok((ws_stream, resp)) => {
@@ -273,28 +424,80 @@ async fn main() {
                         "Connected! WebSocket compression: DISABLED (not supported by server)"
                     );
                 }
+                let features = resp
+                    .headers()
+                    .get(FEATURES_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .map(Features::parse)
+                    .unwrap_or_default();
+                if features.streaming {
+                    tracing::info!("Streaming bodies: ENABLED");
+                }
+                if features.binary {
+                    tracing::info!("Binary framing: ENABLED");
+                }
+
+                if resume_secret.is_some() {
+                    tracing::info!("Session {} resumed", token_session.session);
+                }
+                resume_secret = resp
+                    .headers()
+                    .get(RESUME_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
 
                 // Token valid; ask for local address before starting proxying
-                let default_local = args.local.clone();
-                let input_prompt = format!("Enter local address (default {}): ", default_local);
-                print!("{}", input_prompt);
-                let _ = io::stdout().flush();
-                let mut line = String::new();
-                let _ = io::stdin().read_line(&mut line);
-                let line = line.trim();
-                let local = if line.is_empty() {
-                    default_local
+                let local = if let Some(local) = chosen_local.clone() {
+                    local
                 } else {
-                    line.to_string()
+                    let default_local = args.local.clone();
+                    let input_prompt = format!("Enter local address (default {}): ", default_local);
+                    print!("{}", input_prompt);
+                    let _ = io::stdout().flush();
+                    let mut line = String::new();
+                    let _ = io::stdin().read_line(&mut line);
+                    let line = line.trim();
+                    if line.is_empty() {
+                        default_local
+                    } else {
+                        line.to_string()
+                    }
                 };
+                chosen_local = Some(local.clone());
                 let local_base = format!("http://{}", local);
 
-                let public_http = if scheme == "wss" {
-                    format!("https://{}/s/{}/", remote_host, token_session.session)
-                } else {
-                    format!("http://{}/s/{}/", remote_host, token_session.session)
-                };
-                tracing::info!("Public URL: {}", public_http);
+                // Servers that announce a protocol version answer our hello with the public
+                // URLs; for older ones they are guessed from the server address
+                let handshake = resp.headers().contains_key(PROTOCOL_HEADER);
+                if !handshake {
+                    let tcp_port = resp
+                        .headers()
+                        .get(TCP_PORT_HEADER)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.parse::<u16>().ok());
+                    let guessed = if let Some(port) = tcp_port {
+                        let public_host = remote_host
+                            .rsplit_once(':')
+                            .map(|(host, _)| host)
+                            .unwrap_or(&remote_host);
+                        vec![format!("tcp://{}:{}", public_host, port)]
+                    } else {
+                        let http_scheme = if scheme == "wss" { "https" } else { "http" };
+                        let mut urls = vec![format!(
+                            "{}://{}/s/{}/",
+                            http_scheme, remote_host, token_session.session
+                        )];
+                        if let Some(host) = resp
+                            .headers()
+                            .get(PUBLIC_HOST_HEADER)
+                            .and_then(|v| v.to_str().ok())
+                        {
+                            urls.push(format!("{}://{}/", http_scheme, host));
+                        }
+                        urls
+                    };
+                    print_public_urls(&guessed);
+                }
                 if token_session.expires_in > 0 {
                     tracing::info!("Note: token expires in ~{}s", token_session.expires_in);
                 }
@@ -325,6 +528,42 @@ async fn main() {
                     }
                 });
 
+                // request id -> body sender for streamed requests in flight
+                let mut request_bodies: HashMap<u64, RequestBodyTx> = HashMap::new();
+                // stream id -> relayed visitor WebSocket or TCP connection
+                let mut streams: HashMap<u64, mpsc::Sender<ServerToClient>> = HashMap::new();
+                // request id -> task running the local request, aborted on `cancel`
+                let mut requests: HashMap<u64, AbortHandle> = HashMap::new();
+
+                let out = Outbound {
+                    tx: out_tx.clone(),
+                    binary: features.binary,
+                    notices: features.notices,
+                    credit: features.credit,
+                    windows: Arc::new(flow::SendWindows::default()),
+                };
+                let limiter = Arc::new(Semaphore::new(args.max_in_flight as usize));
+                // Requests running or waiting for `limiter`; beyond that they are refused
+                let admitted = Arc::new(Semaphore::new(
+                    (args.max_in_flight + args.max_queued) as usize,
+                ));
+
+                if handshake {
+                    let hello = ClientToServer::Hello(Hello {
+                        protocol_version: PROTOCOL_VERSION,
+                        client_version: env!("CARGO_PKG_VERSION").to_string(),
+                        features: Features::supported()
+                            .names()
+                            .into_iter()
+                            .map(String::from)
+                            .collect(),
+                        compression: COMPRESSION_CODECS.iter().map(|c| c.to_string()).collect(),
+                    });
+                    if let Err(e) = out.send(hello).await {
+                        tracing::error!("Failed to send hello: {}", e);
+                    }
+                }
+
                 while let Some(msg_res) = ws_rx.next().await {
                     let msg = match msg_res {
                         Ok(m) => m,
@@ -333,50 +572,281 @@ async fn main() {
                             break;
                         }
                     };
-                    match msg {
-                        Message::Text(txt) => match serde_json::from_str::<ServerToClient>(&txt) {
-                            Ok(ServerToClient::ProxyRequest(req_msg)) => {
-                                let resp_msg = handle_proxy(&http, &local_base, req_msg).await;
-                                let text =
-                                    serde_json::to_string(&ClientToServer::ProxyResponse(resp_msg))
-                                        .expect("serialize response");
-                                if let Err(e) = out_tx.send(Message::Text(text.into())).await {
+                    // Servers answer in binary frames once negotiated; accept both anyway
+                    let parsed = match msg {
+                        Message::Text(txt) => serde_json::from_str::<ServerToClient>(&txt)
+                            .map_err(frame::FrameError::from),
+                        Message::Binary(bin) => frame::decode_binary::<ServerToClient>(&bin),
+                        Message::Ping(p) => {
+                            let _ = out_tx.send(Message::Pong(p)).await;
+                            continue;
+                        }
+                        Message::Close(frame) => {
+                            let reason = frame.and_then(|f| CloseReason::from_code(f.code.into()));
+                            match reason {
+                                // Another client owns the session now; reconnecting would
+                                // only take it back and forth
+                                Some(CloseReason::TakenOver) => {
+                                    tracing::error!(
+                                        "Session {} was taken over by another connection",
+                                        token_session.session
+                                    );
+                                    std::process::exit(1);
+                                }
+                                // The operator wants this tunnel gone; the server keeps the
+                                // sid blocked for a while anyway
+                                Some(CloseReason::Terminated) => {
+                                    tracing::error!(
+                                        "Session {} was disconnected by the server operator",
+                                        token_session.session
+                                    );
+                                    std::process::exit(1);
+                                }
+                                Some(CloseReason::Incompatible) => {
+                                    tracing::error!(
+                                        "Server refused tunly-client {} (protocol {}): {}; upgrade the client or server so they match",
+                                        env!("CARGO_PKG_VERSION"),
+                                        PROTOCOL_VERSION,
+                                        CloseReason::Incompatible.message()
+                                    );
+                                    std::process::exit(1);
+                                }
+                                Some(reason) => {
+                                    println!("Server closed connection: {}", reason.message())
+                                }
+                                None => println!("Server closed connection"),
+                            }
+                            break;
+                        }
+                        _ => continue,
+                    };
+                    match parsed {
+                        Ok(ServerToClient::ProxyRequest(req_msg)) => {
+                            let id = req_msg.id;
+                            let Ok(ticket) = admitted.clone().try_acquire_owned() else {
+                                refuse_overloaded(&out, id, false).await;
+                                continue;
+                            };
+                            let (http, local_base, out) =
+                                (http.clone(), local_base.clone(), out.clone());
+                            let limiter = limiter.clone();
+                            // Requests wait for a slot in their own task, so the read loop keeps
+                            // taking body chunks and cancels while the local target is saturated
+                            let task = tokio::spawn(async move {
+                                let _ticket = ticket;
+                                let _permit = limiter.acquire_owned().await.unwrap();
+                                let resp_msg =
+                                    handle_proxy(&http, &local_base, req_msg, out.notices).await;
+                                if let Err(e) = out.send(resp_msg).await {
                                     tracing::error!("Failed to send response over WS: {}", e);
-                                    break;
                                 }
+                            });
+                            track_request(&mut requests, id, task.abort_handle());
+                        }
+                        Ok(ServerToClient::RequestStart(start)) => {
+                            let id = start.id;
+                            let Ok(ticket) = admitted.clone().try_acquire_owned() else {
+                                refuse_overloaded(&out, id, true).await;
+                                continue;
+                            };
+                            let body_rx = if start.has_body {
+                                let (tx, rx) = mpsc::channel(flow::RECV_BUFFER);
+                                request_bodies.insert(id, tx);
+                                Some(rx)
+                            } else {
+                                None
+                            };
+                            let (http, local_base, out) =
+                                (http.clone(), local_base.clone(), out.clone());
+                            let limiter = limiter.clone();
+                            let task = tokio::spawn(async move {
+                                let _ticket = ticket;
+                                let _permit = limiter.acquire_owned().await.unwrap();
+                                handle_stream(http, local_base, start, body_rx, out).await;
+                            });
+                            track_request(&mut requests, id, task.abort_handle());
+                        }
+                        Ok(ServerToClient::RequestBody(chunk)) => {
+                            let id = chunk.id;
+                            // Queued uploads buffer up to their window; the server waits for
+                            // credit before sending more, so this never has to wait
+                            if let Some(tx) = request_bodies.get(&id) {
+                                if flow::offer(tx, Ok(chunk.into_data())).is_err() {
+                                    // Local request already finished, or the server overran
+                                    // the window: fail the upload in the slot kept for its end
+                                    let err = io::Error::other("request body overran its window");
+                                    let _ = tx.try_send(Err(err));
+                                    request_bodies.remove(&id);
+                                }
+                            }
+                        }
+                        Ok(ServerToClient::RequestEnd(end)) => {
+                            if let (Some(tx), Some(err)) =
+                                (request_bodies.remove(&end.id), end.error)
+                            {
+                                let _ = tx.try_send(Err(io::Error::other(err)));
                             }
-                            Err(e) => {
-                                tracing::error!("Failed to parse server message: {}", e);
+                        }
+                        Ok(ServerToClient::WsOpen(open)) => {
+                            let (tx, rx) = mpsc::channel(flow::RECV_BUFFER);
+                            let id = open.id;
+                            track_stream(&mut streams, id, tx);
+                            let task =
+                                tokio::spawn(handle_ws(local.clone(), open, rx, out.clone()));
+                            track_request(&mut requests, id, task.abort_handle());
+                        }
+                        Ok(ServerToClient::WsFrame(frame)) => {
+                            let id = frame.id;
+                            let msg = ServerToClient::WsFrame(frame);
+                            feed_stream(&mut streams, &mut requests, &out, id, msg).await;
+                        }
+                        Ok(ServerToClient::TcpData(chunk)) => {
+                            let id = chunk.id;
+                            let msg = ServerToClient::TcpData(chunk);
+                            feed_stream(&mut streams, &mut requests, &out, id, msg).await;
+                        }
+                        Ok(ServerToClient::WsClose(close)) => {
+                            if let Some(tx) = streams.remove(&close.id) {
+                                // The end always fits: a slot is kept free for it
+                                let _ = tx.try_send(ServerToClient::WsClose(close));
                             }
+                        }
+                        Ok(ServerToClient::TcpOpen(open)) => {
+                            let (tx, rx) = mpsc::channel(flow::RECV_BUFFER);
+                            let id = open.id;
+                            track_stream(&mut streams, id, tx);
+                            let task =
+                                tokio::spawn(handle_tcp(local.clone(), open, rx, out.clone()));
+                            track_request(&mut requests, id, task.abort_handle());
+                        }
+                        Ok(ServerToClient::TcpClose(end)) => {
+                            if let Some(tx) = streams.remove(&end.id) {
+                                let _ = tx.try_send(ServerToClient::TcpClose(end));
+                            }
+                        }
+                        Ok(ServerToClient::RefreshToken(refresh)) => {
+                            tracing::debug!(
+                                "Received refresh credential (valid for {}s)",
+                                refresh.expires_in
+                            );
+                            refresh_token = Some(refresh.token);
+                        }
+                        Ok(ServerToClient::Cancel(cancel)) => {
+                            // Dropping the reqwest future (or relay) closes the local connection
+                            request_bodies.remove(&cancel.id);
+                            streams.remove(&cancel.id);
+                            if let Some(task) = requests.remove(&cancel.id) {
+                                task.abort();
+                                tracing::info!("Request {} cancelled by server", cancel.id);
+                            }
+                        }
+                        Ok(ServerToClient::Credit(credit)) => out.windows.grant(&credit),
+                        Ok(ServerToClient::Welcome(welcome)) => {
+                            if welcome.protocol_version < MIN_PROTOCOL_VERSION {
+                                tracing::error!(
+                                    "Server {} speaks protocol {}, this client needs at least {}; upgrade the server",
+                                    welcome.server_version,
+                                    welcome.protocol_version,
+                                    MIN_PROTOCOL_VERSION
+                                );
+                                std::process::exit(1);
+                            }
+                            tracing::info!(
+                                "Server {} (protocol {}), features: {}",
+                                welcome.server_version,
+                                welcome.protocol_version,
+                                welcome.features.join(", ")
+                            );
+                            print_public_urls(&welcome.public_urls);
+                            tracing::debug!("Session limits: {:?}", welcome.limits);
+                        }
+                        Ok(ServerToClient::Notice(notice)) => match notice.severity {
+                            Severity::Info => tracing::info!(
+                                "Server notice [{}]: {}",
+                                notice.code.as_str(),
+                                notice.message
+                            ),
+                            Severity::Warning => tracing::warn!(
+                                "Server notice [{}]: {}",
+                                notice.code.as_str(),
+                                notice.message
+                            ),
+                            Severity::Error => tracing::error!(
+                                "Server notice [{}]: {}",
+                                notice.code.as_str(),
+                                notice.message
+                            ),
                         },
-                        Message::Ping(p) => {
-                            let _ = out_tx.send(Message::Pong(p)).await;
+                        Ok(ServerToClient::Error(error)) => {
+                            tracing::error!(
+                                "Server error [{}]: {}",
+                                error.code.as_str(),
+                                error.message
+                            );
                         }
-                        Message::Close(_) => {
-                            println!("Server closed connection");
-                            break;
+                        Ok(ServerToClient::GoingAway(notice)) => {
+                            // Keep answering requests already sent; the server closes the
+                            // connection once they are done and we reconnect as usual
+                            tracing::warn!(
+                                "Server is shutting down (draining for up to {}s), will reconnect",
+                                notice.drain_secs
+                            );
+                        }
+                        Err(e) => {
+                            tracing::error!("Failed to parse server message: {}", e);
                         }
-                        _ => {}
                     }
                 }
 
                 heartbeat.abort();
                 writer.abort();
+                // Their answers could not be delivered any more
+                for (_, task) in requests.drain() {
+                    task.abort();
+                }
+                out.windows.close_all();
 
-                // After a disconnect, generate a new session ID for next attempt
-                token_session.session = generate_session_id();
+                // After a disconnect, keep the session ID so the public URL survives if we can
+                // resume it; otherwise start over with a new one
+                if resume_secret.is_some() {
+                    tracing::info!(
+                        "Connection lost, resuming session {}...",
+                        token_session.session
+                    );
+                } else {
+                    token_session.session = new_session_id(&args);
+                }
                 // Reset attempts so backoff starts small again
                 attempt = 0;
             }
             Err(e) => {
                 if let WsError::Http(resp) = &e {
                     let code = resp.status().as_u16();
-                    if code == 401 || code == 403 {
-                        println!("Token is invalid or has expired.");
+                    if code == 409 && args.name.is_some() {
+                        tracing::error!(
+                            "Name '{}' is reserved by another client (wrong --name-key?)",
+                            token_session.session
+                        );
+                        std::process::exit(1);
+                    }
+                    if code == 409 {
                         println!(
-                            "Get a new token at https://{} and enter it again.",
-                            remote_host
+                            "Session is still reserved by another client, starting a new one."
                         );
+                        resume_secret = None;
+                        token_session.session = new_session_id(&args);
+                        attempt = 0;
+                        continue;
+                    }
+                    if code == 401 || code == 403 {
+                        resume_secret = None;
+                        if refresh_token.is_none() {
+                            println!("Token is invalid or has expired.");
+                            println!(
+                                "Get a new token at https://{} and enter it again.",
+                                remote_host
+                            );
+                        }
                         token_session.token.clear();
                         // Reset attempt for fresh start after reprompt
                         attempt = 0;
@@ -390,58 +860,162 @@ async fn main() {
                 // Exponential backoff before reconnect (max 15s)
                 let backoff = 2u64.saturating_pow(attempt.min(4));
                 sleep(Duration::from_secs(backoff.min(15))).await;
-                // Refresh session for next attempt
-                token_session.session = generate_session_id();
+                // Refresh session for next attempt, unless we may still resume it
+                if resume_secret.is_none() {
+                    token_session.session = new_session_id(&args);
+                }
             }
         }
     }
 }
 
+// Log where visitors reach the tunnel, as announced by the server
+fn print_public_urls(urls: &[String]) {
+    for (i, url) in urls.iter().enumerate() {
+        if let Some(endpoint) = url.strip_prefix("tcp://") {
+            tracing::info!("Public TCP endpoint: {}", endpoint);
+        } else if i == 0 {
+            tracing::info!("Public URL: {}", url);
+        } else {
+            tracing::info!("Public URL (own host): {}", url);
+        }
+    }
+}
+
+// Remember the task answering request `id`, forgetting tasks that have finished
+fn track_request(requests: &mut HashMap<u64, AbortHandle>, id: u64, task: AbortHandle) {
+    requests.retain(|_, t| !t.is_finished());
+    requests.insert(id, task);
+}
+
+// Remember the relay for stream `id`, forgetting relays that have ended
+fn track_stream(
+    streams: &mut HashMap<u64, mpsc::Sender<ServerToClient>>,
+    id: u64,
+    tx: mpsc::Sender<ServerToClient>,
+) {
+    streams.retain(|_, t| !t.is_closed());
+    streams.insert(id, tx);
+}
+
+// Hand data for stream `id` to its local relay without waiting: one local peer that stops
+// reading must not stall the tunnel. A relay whose buffer overran its window is stopped and the
+// server told to close the visitor's side.
+async fn feed_stream(
+    streams: &mut HashMap<u64, mpsc::Sender<ServerToClient>>,
+    requests: &mut HashMap<u64, AbortHandle>,
+    out: &Outbound,
+    id: u64,
+    msg: ServerToClient,
+) {
+    let Some(tx) = streams.get(&id) else {
+        return;
+    };
+    let Err(msg) = flow::offer(tx, msg) else {
+        return;
+    };
+    let closed = tx.is_closed();
+    streams.remove(&id);
+    if closed {
+        return;
+    }
+    tracing::warn!("Stream {} overran its window; closing it", id);
+    if let Some(task) = requests.remove(&id) {
+        task.abort();
+    }
+    let reason = "stream overran its window".to_string();
+    let end = match msg {
+        ServerToClient::TcpData(_) => ClientToServer::TcpClose(StreamEnd {
+            id,
+            error: Some(reason),
+        }),
+        _ => ClientToServer::WsClose(WsClose {
+            id,
+            code: Some(1011),
+            reason,
+        }),
+    };
+    let _ = out.send(end).await;
+}
+
+// Answer request `id` with 503 when too many requests are already waiting for the local target;
+// `streamed` says whether the server expects a streamed response
+async fn refuse_overloaded(out: &Outbound, id: u64, streamed: bool) {
+    tracing::warn!("Request {} refused: too many requests queued", id);
+    let msg = "tunnel client overloaded";
+    if out.notices {
+        let error = ErrorMessage {
+            id: Some(id),
+            code: MessageCode::Overloaded,
+            message: msg.to_string(),
+        };
+        let _ = out.send(ClientToServer::Error(error)).await;
+        return;
+    }
+    let headers = vec![("content-type".into(), "text/plain".into())];
+    if !streamed {
+        let (body_b64, is_compressed) = tunly::compress_body(msg.as_bytes());
+        let resp = ClientToServer::ProxyResponse(ProxyResponse {
+            id,
+            status: 503,
+            headers,
+            body_b64,
+            is_compressed,
+        });
+        let _ = out.send(resp).await;
+        return;
+    }
+    let head = ClientToServer::ResponseStart(ResponseStart {
+        id,
+        status: 503,
+        headers,
+    });
+    let _ = out.send(head).await;
+    let body = ClientToServer::ResponseBody(BodyChunk::new(id, msg.as_bytes()));
+    let _ = out.send(body).await;
+    let _ = out
+        .send(ClientToServer::ResponseEnd(StreamEnd { id, error: None }))
+        .await;
+}
+
+// Fetch a challenge to sign from the server, if it offers them
+async fn fetch_challenge(http: &reqwest::Client, url: &str) -> Option<String> {
+    let resp = http.get(url).send().await.ok()?.error_for_status().ok()?;
+    let body: pop::ChallengeResponse = resp.json().await.ok()?;
+    Some(body.challenge)
+}
+
+// Trade a refresh credential for a new token bound to the same session
+async fn exchange_refresh_token(
+    http: &reqwest::Client,
+    url: &str,
+    credential: &str,
+) -> reqwest::Result<TokenSession> {
+    http.post(url)
+        .bearer_auth(credential)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
+
+// Session id for a fresh start: the requested name, or a random id
+fn new_session_id(args: &ClientArgs) -> String {
+    args.name.clone().unwrap_or_else(generate_session_id)
+}
+
 async fn handle_proxy(
     http: &reqwest::Client,
     local_base: &str,
     req_msg: ProxyRequest,
-) -> ProxyResponse {
+    notices: bool,
+) -> ClientToServer {
     tracing::info!("-> CLIENT received proxy request for URI: {}", &req_msg.uri);
-    // Build URL to local server
-    let url = if req_msg.uri.starts_with('/') {
-        format!("{}{}", local_base, req_msg.uri)
-    } else {
-        format!("{}/{}", local_base.trim_end_matches('/'), req_msg.uri)
-    };
-
     let method = req_msg.method.as_str();
     let start = Instant::now();
 
-    let mut builder = http.request(
-        reqwest::Method::from_bytes(method.as_bytes()).unwrap_or(reqwest::Method::GET),
-        &url,
-    );
-
-    // Headers
-    let mut headers = HeaderMap::new();
-    for (k, v) in req_msg.headers.iter() {
-        if is_hop_by_hop(k) {
-            continue;
-        }
-        if k.eq_ignore_ascii_case("host") {
-            // Rewrite host to local target
-            let local_host = local_base
-                .trim_start_matches("http://")
-                .trim_start_matches("https://");
-            let host_parts: Vec<&str> = local_host.split(':').collect();
-            let port = host_parts.get(1).unwrap_or(&"80");
-            if let Ok(val) = HeaderValue::from_str(&format!("localhost:{}", port)) {
-                headers.insert(HeaderName::from_static("host"), val);
-            }
-            continue;
-        }
-        if let (Ok(name), Ok(value)) = (HeaderName::try_from(k.as_str()), HeaderValue::from_str(v))
-        {
-            headers.insert(name, value);
-        }
-    }
-    builder = builder.headers(headers);
+    let mut builder = local_request(http, local_base, method, &req_msg.uri, &req_msg.headers);
 
     // Body
     let body = tunly::decompress_body(&req_msg.body_b64, req_msg.is_compressed);
@@ -466,16 +1040,15 @@ async fn handle_proxy(
                 status,
                 dur_ms
             );
-            ProxyResponse {
+            ClientToServer::ProxyResponse(ProxyResponse {
                 id: req_msg.id,
                 status,
                 headers: resp_headers,
                 body_b64,
                 is_compressed,
-            }
+            })
         }
         Err(err) => {
-            let msg = format!("upstream error: {}", err);
             let dur_ms = start.elapsed().as_millis();
             tracing::info!(
                 "LOCAL {} {} -> 502 in {}ms ({})",
@@ -484,16 +1057,495 @@ async fn handle_proxy(
                 dur_ms,
                 err
             );
+            if notices {
+                return ClientToServer::Error(upstream_error(req_msg.id, &err));
+            }
+            let msg = format!("upstream error: {}", err);
             let (body_b64, is_compressed) = tunly::compress_body(msg.as_bytes());
-            ProxyResponse {
+            ClientToServer::ProxyResponse(ProxyResponse {
                 id: req_msg.id,
                 status: 502,
                 headers: vec![("content-type".into(), "text/plain".into())],
                 body_b64,
                 is_compressed,
+            })
+        }
+    }
+}
+
+// Structured answer for request `id` when the local target could not be reached
+fn upstream_error(id: u64, err: &reqwest::Error) -> ErrorMessage {
+    let code = if err.is_timeout() {
+        MessageCode::UpstreamTimeout
+    } else if err.is_connect() {
+        MessageCode::UpstreamUnreachable
+    } else {
+        MessageCode::UpstreamFailed
+    };
+    ErrorMessage {
+        id: Some(id),
+        code,
+        message: err.to_string(),
+    }
+}
+
+// Streamed counterpart of handle_proxy: request body arrives as chunks on `body_rx`,
+// response head and body chunks are sent back as they come from the local server.
+async fn handle_stream(
+    http: reqwest::Client,
+    local_base: String,
+    start_msg: RequestStart,
+    body_rx: Option<mpsc::Receiver<Result<Vec<u8>, io::Error>>>,
+    out: Outbound,
+) {
+    tracing::info!(
+        "-> CLIENT received streamed request for URI: {}",
+        &start_msg.uri
+    );
+    let id = start_msg.id;
+    let method = start_msg.method.as_str();
+    let start = Instant::now();
+
+    let mut builder = local_request(
+        &http,
+        &local_base,
+        method,
+        &start_msg.uri,
+        &start_msg.headers,
+    );
+    if let Some(rx) = body_rx {
+        // Hand the server credit as the local target takes chunks
+        let window = flow::RecvWindow::new(id, out.credit);
+        let stream = futures::stream::unfold(
+            (rx, window, out.clone()),
+            |(mut rx, mut window, out)| async move {
+                let item = rx.recv().await?;
+                if let Some(credit) = window.taken() {
+                    let _ = out.send(ClientToServer::Credit(credit)).await;
+                }
+                Some((item, (rx, window, out)))
+            },
+        );
+        builder = builder.body(reqwest::Body::wrap_stream(stream));
+    }
+
+    let mut resp = match builder.send().await {
+        Ok(resp) => resp,
+        Err(err) => {
+            tracing::info!(
+                "LOCAL {} {} -> 502 in {}ms ({})",
+                method,
+                start_msg.uri,
+                start.elapsed().as_millis(),
+                err
+            );
+            if out.notices {
+                let _ = out
+                    .send(ClientToServer::Error(upstream_error(id, &err)))
+                    .await;
+                return;
+            }
+            let msg = format!("upstream error: {}", err);
+            let _ = out
+                .send(ClientToServer::ResponseStart(ResponseStart {
+                    id,
+                    status: 502,
+                    headers: vec![("content-type".into(), "text/plain".into())],
+                }))
+                .await;
+            let _ = out
+                .send(ClientToServer::ResponseBody(BodyChunk::new(
+                    id,
+                    msg.as_bytes(),
+                )))
+                .await;
+            let _ = out
+                .send(ClientToServer::ResponseEnd(StreamEnd { id, error: None }))
+                .await;
+            return;
+        }
+    };
+
+    let status = resp.status().as_u16();
+    tracing::info!(
+        "LOCAL {} {} -> {} in {}ms",
+        method,
+        start_msg.uri,
+        status,
+        start.elapsed().as_millis()
+    );
+    let head = ClientToServer::ResponseStart(ResponseStart {
+        id,
+        status,
+        headers: headers_to_vec(resp.headers()),
+    });
+    if out.send(head).await.is_err() {
+        return;
+    }
+
+    let window = out.window(id);
+    let mut error = None;
+    loop {
+        match resp.chunk().await {
+            Ok(Some(bytes)) => {
+                for piece in bytes.chunks(STREAM_CHUNK_SIZE) {
+                    if !window.reserve().await {
+                        return;
+                    }
+                    let msg = ClientToServer::ResponseBody(BodyChunk::new(id, piece));
+                    if out.send(msg).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            Ok(None) => break,
+            Err(err) => {
+                tracing::warn!("LOCAL {} {} body aborted: {}", method, start_msg.uri, err);
+                error = Some(format!("upstream body error: {}", err));
+                break;
+            }
+        }
+    }
+    let _ = out
+        .send(ClientToServer::ResponseEnd(StreamEnd { id, error }))
+        .await;
+}
+
+// Open a WebSocket to the local target for a visitor upgrade and relay frames both ways
+async fn handle_ws(
+    local: String,
+    open: WsOpen,
+    mut frames_rx: mpsc::Receiver<ServerToClient>,
+    out: Outbound,
+) {
+    tracing::info!(
+        "-> CLIENT received websocket upgrade for URI: {}",
+        &open.uri
+    );
+    let id = open.id;
+    let url = if open.uri.starts_with('/') {
+        format!("ws://{}{}", local, open.uri)
+    } else {
+        format!("ws://{}/{}", local, open.uri)
+    };
+
+    let mut req = match url.as_str().into_client_request() {
+        Ok(req) => req,
+        Err(err) => {
+            let _ = out
+                .send(ClientToServer::WsOpened(WsOpened {
+                    id,
+                    status: 502,
+                    protocol: None,
+                    error: Some(format!("invalid upstream url: {}", err)),
+                }))
+                .await;
+            return;
+        }
+    };
+    for (k, v) in open.headers.iter() {
+        if is_hop_by_hop(k) || k.eq_ignore_ascii_case("host") {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(k.as_str()), HeaderValue::from_str(v))
+        {
+            req.headers_mut().insert(name, value);
+        }
+    }
+
+    let (ws, resp) = match tokio_tungstenite::connect_async(req).await {
+        Ok(ok) => ok,
+        Err(err) => {
+            let status = match &err {
+                WsError::Http(resp) => resp.status().as_u16(),
+                _ => 502,
+            };
+            tracing::info!("LOCAL WS {} -> {} ({})", open.uri, status, err);
+            let _ = out
+                .send(ClientToServer::WsOpened(WsOpened {
+                    id,
+                    status,
+                    protocol: None,
+                    error: Some(format!("upstream error: {}", err)),
+                }))
+                .await;
+            return;
+        }
+    };
+    tracing::info!("LOCAL WS {} -> 101", open.uri);
+    let protocol = resp
+        .headers()
+        .get("sec-websocket-protocol")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    let opened = ClientToServer::WsOpened(WsOpened {
+        id,
+        status: 101,
+        protocol,
+        error: None,
+    });
+    if out.send(opened).await.is_err() {
+        return;
+    }
+
+    let (mut local_tx, mut local_rx) = ws.split();
+
+    // Ends with whether the server's side is closed already
+    let to_local = async {
+        let mut received = flow::RecvWindow::new(id, out.credit);
+        while let Some(msg) = frames_rx.recv().await {
+            if matches!(msg, ServerToClient::WsFrame(_)) {
+                if let Some(credit) = received.taken() {
+                    let _ = out.send(ClientToServer::Credit(credit)).await;
+                }
+            }
+            let ws_msg = match msg {
+                ServerToClient::WsFrame(frame) => match frame.kind {
+                    WsFrameKind::Text => match String::from_utf8(frame.data) {
+                        Ok(text) => Message::Text(text.into()),
+                        Err(_) => continue,
+                    },
+                    WsFrameKind::Binary => Message::Binary(frame.data.into()),
+                },
+                ServerToClient::WsClose(close) => {
+                    let _ = local_tx
+                        .send(Message::Close(Some(CloseFrame {
+                            code: close.code.unwrap_or(1000).into(),
+                            reason: close.reason.into(),
+                        })))
+                        .await;
+                    return true;
+                }
+                _ => continue,
+            };
+            if local_tx.send(ws_msg).await.is_err() {
+                return false;
+            }
+        }
+        let _ = local_tx.close().await;
+        true
+    };
+
+    let window = out.window(id);
+    let from_local = async {
+        let mut close = WsClose {
+            id,
+            code: None,
+            reason: String::new(),
+        };
+        while let Some(Ok(msg)) = local_rx.next().await {
+            let frame = match msg {
+                Message::Text(text) => WsFrame {
+                    id,
+                    kind: WsFrameKind::Text,
+                    data: text.as_bytes().to_vec(),
+                },
+                Message::Binary(data) => WsFrame {
+                    id,
+                    kind: WsFrameKind::Binary,
+                    data: data.to_vec(),
+                },
+                Message::Close(frame) => {
+                    if let Some(frame) = frame {
+                        close.code = Some(frame.code.into());
+                        close.reason = frame.reason.to_string();
+                    }
+                    break;
+                }
+                _ => continue,
+            };
+            if !window.reserve().await || out.send(ClientToServer::WsFrame(frame)).await.is_err() {
+                return;
+            }
+        }
+        let _ = out.send(ClientToServer::WsClose(close)).await;
+    };
+
+    // from_local tells the server itself when the local socket closes
+    let server_closed = tokio::select! {
+        closed = to_local => closed,
+        _ = from_local => true,
+    };
+    if !server_closed {
+        let close = WsClose {
+            id,
+            code: Some(1011),
+            reason: "local websocket failed".to_string(),
+        };
+        let _ = out.send(ClientToServer::WsClose(close)).await;
+    }
+}
+
+// Connect to the local target for a public TCP connection and relay bytes both ways
+async fn handle_tcp(
+    local: String,
+    open: TcpOpen,
+    mut rx: mpsc::Receiver<ServerToClient>,
+    out: Outbound,
+) {
+    let id = open.id;
+    let start = Instant::now();
+    let stream = match tokio::net::TcpStream::connect(&local).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            tracing::info!("LOCAL TCP {} -> connect failed: {}", open.peer, err);
+            let _ = out
+                .send(ClientToServer::TcpClose(StreamEnd {
+                    id,
+                    error: Some(format!("upstream connect error: {}", err)),
+                }))
+                .await;
+            return;
+        }
+    };
+    tracing::info!("LOCAL TCP {} connected to {}", open.peer, local);
+    let (mut reader, mut writer) = stream.into_split();
+
+    // local -> server; EOF is forwarded as `tcp_close` (half-close)
+    let window = out.window(id);
+    let upstream = async {
+        let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+        let error = loop {
+            if !window.reserve().await {
+                return;
+            }
+            match reader.read(&mut buf).await {
+                Ok(0) => break None,
+                Ok(n) => {
+                    let msg = ClientToServer::TcpData(BodyChunk::new(id, &buf[..n]));
+                    if out.send(msg).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => break Some(e.to_string()),
+            }
+        };
+        let _ = out
+            .send(ClientToServer::TcpClose(StreamEnd { id, error }))
+            .await;
+    };
+
+    // server -> local
+    let downstream = async {
+        let mut received = flow::RecvWindow::new(id, out.credit);
+        while let Some(msg) = rx.recv().await {
+            match msg {
+                ServerToClient::TcpData(chunk) => {
+                    if let Some(credit) = received.taken() {
+                        let _ = out.send(ClientToServer::Credit(credit)).await;
+                    }
+                    if let Err(e) = writer.write_all(&chunk.into_data()).await {
+                        return Some(e.to_string());
+                    }
+                }
+                ServerToClient::TcpClose(end) => {
+                    let _ = writer.shutdown().await;
+                    return end.error;
+                }
+                _ => {}
+            }
+        }
+        Some("tunnel closed".to_string())
+    };
+
+    tokio::pin!(upstream, downstream);
+    let error = tokio::select! {
+        error = &mut downstream => {
+            if error.is_none() {
+                upstream.await;
+            }
+            error
+        }
+        _ = &mut upstream => downstream.await,
+    };
+    tracing::info!(
+        "LOCAL TCP {} closed after {}ms{}",
+        open.peer,
+        start.elapsed().as_millis(),
+        error.map(|e| format!(" ({})", e)).unwrap_or_default()
+    );
+}
+
+// Handle to the single writer task that encodes messages in the negotiated framing
+#[derive(Clone)]
+struct Outbound {
+    tx: mpsc::Sender<Message>,
+    binary: bool,
+    // The server takes `error` messages for requests the local target failed
+    notices: bool,
+    // The server grants credit for the chunks we send on each stream
+    credit: bool,
+    windows: Arc<flow::SendWindows>,
+}
+
+impl Outbound {
+    // Window for sending chunks on stream `id`
+    fn window(&self, id: u64) -> flow::SendWindow {
+        self.windows.open(id, self.credit)
+    }
+
+    async fn send(&self, msg: ClientToServer) -> Result<(), mpsc::error::SendError<Message>> {
+        let ws_msg = if self.binary {
+            Message::Binary(
+                frame::encode_binary(msg)
+                    .expect("encode client message")
+                    .into(),
+            )
+        } else {
+            Message::Text(
+                serde_json::to_string(&msg)
+                    .expect("serialize client message")
+                    .into(),
+            )
+        };
+        self.tx.send(ws_msg).await
+    }
+}
+
+// Build a request against the local target, rewriting Host and dropping hop-by-hop headers
+fn local_request(
+    http: &reqwest::Client,
+    local_base: &str,
+    method: &str,
+    uri: &str,
+    req_headers: &[(String, String)],
+) -> reqwest::RequestBuilder {
+    // Build URL to local server
+    let url = if uri.starts_with('/') {
+        format!("{}{}", local_base, uri)
+    } else {
+        format!("{}/{}", local_base.trim_end_matches('/'), uri)
+    };
+
+    let builder = http.request(
+        reqwest::Method::from_bytes(method.as_bytes()).unwrap_or(reqwest::Method::GET),
+        &url,
+    );
+
+    // Headers
+    let mut headers = HeaderMap::new();
+    for (k, v) in req_headers.iter() {
+        if is_hop_by_hop(k) {
+            continue;
+        }
+        if k.eq_ignore_ascii_case("host") {
+            // Rewrite host to local target
+            let local_host = local_base
+                .trim_start_matches("http://")
+                .trim_start_matches("https://");
+            let host_parts: Vec<&str> = local_host.split(':').collect();
+            let port = host_parts.get(1).unwrap_or(&"80");
+            if let Ok(val) = HeaderValue::from_str(&format!("localhost:{}", port)) {
+                headers.insert(HeaderName::from_static("host"), val);
             }
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(k.as_str()), HeaderValue::from_str(v))
+        {
+            headers.insert(name, value);
         }
     }
+    builder.headers(headers)
 }
 
 fn headers_to_vec(headers: &HeaderMap) -> Vec<(String, String)> {
@@ -0,0 +1,372 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::{Parser, Subcommand};
+use reqwest::{Method, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use tunly::admin::{AccessLog, MintRequest, MintedToken, SessionInfo, Stats, TokenInfo};
+use tunly::AccessLogEntry;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "tunly-ctl",
+    about = "Operate a Tunly server through its admin API"
+)]
+struct CtlArgs {
+    /// Server base URL (env: TUNLY_SERVER)
+    #[arg(long, env = "TUNLY_SERVER", default_value = "http://127.0.0.1:8080")]
+    server: String,
+
+    /// Admin token configured on the server with --admin-token (env: TUNLY_ADMIN_TOKEN)
+    #[arg(long, env = "TUNLY_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: String,
+
+    /// Print raw JSON instead of tables
+    #[arg(long, global = true, default_value_t = false)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Connected tunnels
+    #[command(subcommand)]
+    Sessions(SessionsCommand),
+    /// Ephemeral tokens
+    #[command(subcommand)]
+    Tokens(TokensCommand),
+    /// Session access logs
+    #[command(subcommand)]
+    Logs(LogsCommand),
+    /// Server-wide counters
+    Stats,
+}
+
+#[derive(Subcommand, Debug)]
+enum SessionsCommand {
+    /// List connected sessions
+    List,
+    /// Disconnect a session; its client stops and the sid stays blocked for the resume grace period
+    Kill { sid: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum TokensCommand {
+    /// List outstanding tokens
+    List,
+    /// Mint a token (ephemeral-mode servers only)
+    Mint {
+        /// Lifetime in seconds (default: the server's token_ttl_secs)
+        #[arg(long)]
+        ttl: Option<u64>,
+        /// Session or name the token is for (default: random)
+        #[arg(long)]
+        sid: Option<String>,
+        /// Only accept the token from this client IP
+        #[arg(long)]
+        ip: Option<String>,
+    },
+    /// Revoke a token by its jti
+    Revoke { jti: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum LogsCommand {
+    /// Print a session's recent requests
+    Tail {
+        sid: String,
+        /// Keep printing new requests as they arrive
+        #[arg(short, long, default_value_t = false)]
+        follow: bool,
+        /// Poll interval in seconds with --follow
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+enum CtlError {
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("server answered {0}: {1}")]
+    Status(StatusCode, String),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+}
+
+struct Admin {
+    http: reqwest::Client,
+    base: String,
+    token: String,
+}
+
+impl Admin {
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&impl Serialize>,
+    ) -> Result<Option<T>, CtlError> {
+        let url = format!("{}/admin/api{}", self.base, path);
+        let mut req = self.http.request(method, url).bearer_auth(&self.token);
+        if let Some(body) = body {
+            req = req.json(body);
+        }
+        let resp = req.send().await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let text = resp.text().await.unwrap_or_default();
+            return Err(CtlError::Status(status, text));
+        }
+        if status == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Ok(Some(resp.json().await?))
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, CtlError> {
+        let value = self.call(Method::GET, path, None::<&()>).await?;
+        value.ok_or_else(|| CtlError::Status(StatusCode::NO_CONTENT, "empty response".into()))
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), CtlError> {
+        self.call::<serde_json::Value>(Method::DELETE, path, None::<&()>)
+            .await
+            .map(|_| ())
+    }
+}
+
+/// Print rows under `headers`, padding each column to its widest cell.
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.len());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{:<w$}", c, w = *w))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(headers.to_vec());
+    for row in rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<(), CtlError> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// Compact age, e.g. `42s`, `7m`, `3h`.
+fn age(secs: u64) -> String {
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m", s / 60),
+        s if s < 86400 => format!("{}h", s / 3600),
+        s => format!("{}d", s / 86400),
+    }
+}
+
+fn log_rows(entries: &[AccessLogEntry]) -> Vec<Vec<String>> {
+    entries
+        .iter()
+        .map(|e| {
+            vec![
+                e.method.clone(),
+                e.status.to_string(),
+                format!("{}ms", e.dur_ms),
+                e.uri.clone(),
+            ]
+        })
+        .collect()
+}
+
+async fn run(args: CtlArgs) -> Result<(), CtlError> {
+    let admin = Admin {
+        http: reqwest::Client::builder().build()?,
+        base: args.server.trim_end_matches('/').to_string(),
+        token: args.admin_token,
+    };
+    match args.command {
+        Command::Sessions(SessionsCommand::List) => {
+            let sessions: Vec<SessionInfo> = admin.get("/sessions").await?;
+            if args.json {
+                return print_json(&sessions);
+            }
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let rows: Vec<_> = sessions
+                .iter()
+                .map(|s| {
+                    vec![
+                        s.sid.clone(),
+                        s.tcp_port
+                            .map_or("http".to_string(), |p| format!("tcp:{}", p)),
+                        s.client_ip.clone().unwrap_or_else(|| "-".to_string()),
+                        age(now.saturating_sub(s.created_at)),
+                        age(s.idle_secs),
+                        s.requests.to_string(),
+                        s.in_flight.to_string(),
+                    ]
+                })
+                .collect();
+            print_table(
+                &[
+                    "SID",
+                    "TYPE",
+                    "CLIENT",
+                    "AGE",
+                    "IDLE",
+                    "REQUESTS",
+                    "IN FLIGHT",
+                ],
+                &rows,
+            );
+        }
+        Command::Sessions(SessionsCommand::Kill { sid }) => {
+            admin
+                .delete(&format!("/sessions/{}", urlencoding::encode(&sid)))
+                .await?;
+            println!("Disconnected {}", sid);
+        }
+        Command::Tokens(TokensCommand::List) => {
+            let tokens: Vec<TokenInfo> = admin.get("/tokens").await?;
+            if args.json {
+                return print_json(&tokens);
+            }
+            let rows: Vec<_> = tokens
+                .iter()
+                .map(|t| {
+                    vec![
+                        t.jti.clone(),
+                        t.sid.clone(),
+                        if t.ip.is_empty() { "any" } else { &t.ip }.to_string(),
+                        age(t.expires_in),
+                    ]
+                })
+                .collect();
+            print_table(&["JTI", "SID", "IP", "EXPIRES IN"], &rows);
+        }
+        Command::Tokens(TokensCommand::Mint { ttl, sid, ip }) => {
+            let req = MintRequest {
+                sid,
+                ttl_secs: ttl,
+                ip,
+            };
+            let minted: MintedToken = admin
+                .call(Method::POST, "/tokens", Some(&req))
+                .await?
+                .ok_or_else(|| CtlError::Status(StatusCode::NO_CONTENT, "empty response".into()))?;
+            if args.json {
+                return print_json(&minted);
+            }
+            print_table(
+                &["JTI", "SID", "EXPIRES IN"],
+                &[vec![minted.jti, minted.session, age(minted.expires_in)]],
+            );
+            println!("\n{}", minted.token);
+        }
+        Command::Tokens(TokensCommand::Revoke { jti }) => {
+            admin
+                .delete(&format!("/tokens/{}", urlencoding::encode(&jti)))
+                .await?;
+            println!("Revoked {}", jti);
+        }
+        Command::Logs(LogsCommand::Tail {
+            sid,
+            follow,
+            interval,
+        }) => {
+            let path = format!("/sessions/{}/log", urlencoding::encode(&sid));
+            let log: AccessLog = admin.get(&path).await?;
+            if args.json {
+                for entry in &log.entries {
+                    println!("{}", serde_json::to_string(entry)?);
+                }
+            } else {
+                print_table(
+                    &["METHOD", "STATUS", "TIME", "URI"],
+                    &log_rows(&log.entries),
+                );
+            }
+            if !follow {
+                return Ok(());
+            }
+            let mut seen = log.total;
+            loop {
+                tokio::time::sleep(Duration::from_secs(interval.max(1))).await;
+                let log: AccessLog = admin.get(&path).await?;
+                // Entries that scrolled out of the server's buffer between polls are lost
+                let new = (log.total.saturating_sub(seen) as usize).min(log.entries.len());
+                seen = log.total;
+                let fresh = &log.entries[log.entries.len() - new..];
+                for entry in fresh {
+                    if args.json {
+                        println!("{}", serde_json::to_string(entry)?);
+                    } else {
+                        println!(
+                            "{}  {}  {}ms  {}",
+                            entry.method, entry.status, entry.dur_ms, entry.uri
+                        );
+                    }
+                }
+            }
+        }
+        Command::Stats => {
+            let stats: Stats = admin.get("/stats").await?;
+            if args.json {
+                return print_json(&stats);
+            }
+            let rows = vec![
+                vec!["sessions".to_string(), stats.sessions.to_string()],
+                vec!["tcp sessions".to_string(), stats.tcp_sessions.to_string()],
+                vec![
+                    "requests in flight".to_string(),
+                    stats.in_flight.to_string(),
+                ],
+                vec![
+                    "outstanding tokens".to_string(),
+                    stats.outstanding_tokens.to_string(),
+                ],
+                vec![
+                    "proxy requests".to_string(),
+                    stats.proxy_requests_total.to_string(),
+                ],
+                vec![
+                    "tcp connections".to_string(),
+                    stats.tcp_connections_total.to_string(),
+                ],
+                vec!["ip denied".to_string(), stats.ip_denied_total.to_string()],
+                vec![
+                    "rate limited".to_string(),
+                    stats.rate_limited_total.to_string(),
+                ],
+                vec![
+                    "rate limit buckets".to_string(),
+                    stats.rate_limit_buckets.to_string(),
+                ],
+                vec!["draining".to_string(), stats.draining.to_string()],
+            ];
+            print_table(&["METRIC", "VALUE"], &rows);
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args = CtlArgs::parse();
+    if let Err(e) = run(args).await {
+        eprintln!("tunly-ctl: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -1,25 +1,41 @@
 use std::{
-    collections::HashMap,
     net::SocketAddr,
-    sync::{atomic::AtomicU64, Arc},
+    ops::RangeInclusive,
+    path::PathBuf,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use clap::Parser;
+use axum::{
+    serve::{Listener, ListenerExt},
+    Router,
+};
+use clap::{Parser, Subcommand};
+use ipnet::IpNet;
 use rand::Rng;
-use tokio::sync::{Mutex, RwLock};
-use tunly::{AppState, AuthMode, Metrics, SESSION_IDLE_TTL_SECS};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tunly::config::{ConfigError, ConfigFile, Settings};
+use tunly::keys::KeySet;
+use tunly::proxy_protocol::ProxyProtocolListener;
+use tunly::tls::{self, TlsListener};
+use tunly::tokens::{self, AccessClaims};
+use tunly::{access, forwarded, tcp, AppState, AuthMode, CloseReason, MessageCode, Severity};
 
 #[derive(Parser, Debug, Clone)]
 #[command(name = "tunly-server", about = "Tunly Server")]
 struct ServerArgs {
-    /// Host to bind, e.g. 0.0.0.0
-    #[arg(long, default_value = "0.0.0.0")]
-    host: String,
+    /// (Optional) TOML config file; flags and environment variables override it. [limits] and [policy] are reloaded on SIGHUP (env: TUNLY_CONFIG)
+    #[arg(long, env = "TUNLY_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    /// Host to bind, e.g. 0.0.0.0 (default: 0.0.0.0)
+    #[arg(long)]
+    host: Option<String>,
 
-    /// Port to bind, e.g. 8080
-    #[arg(long, env = "PORT", default_value_t = 8080)]
-    port: u16,
+    /// Port to bind, e.g. 8080 (default: 8080)
+    #[arg(long, env = "PORT")]
+    port: Option<u16>,
 
     /// Legacy combined bind (overrides host/port if provided), e.g. 0.0.0.0:9000
     #[arg(long)]
@@ -29,8 +45,8 @@ struct ServerArgs {
     #[arg(long)]
     token: Option<String>,
 
-    /// Custom JWT secret key. If not provided, a random one is generated on startup.
-    #[arg(long, env = "TUNLY_JWT_SECRET")]
+    /// Custom JWT secret key (HS256, for tokens without a kid). If not provided, a random one is generated on startup.
+    #[arg(long, env = "TUNLY_JWT_SECRET", global = true)]
     jwt_secret: Option<String>,
 
     /// Allow token via query parameter for WS (not recommended). Default: false
@@ -40,52 +56,365 @@ struct ServerArgs {
     /// (Optional) Internal key to restrict /token access (env: TUNLY_INTERNAL_KEY)
     #[arg(long, env = "TUNLY_INTERNAL_KEY")]
     internal_key: Option<String>,
+
+    /// (Optional) Bearer token for the operator API under /admin/api; disabled if unset (env: TUNLY_ADMIN_TOKEN)
+    #[arg(long, env = "TUNLY_ADMIN_TOKEN")]
+    admin_token: Option<String>,
+
+    /// (Optional) Public port range for raw TCP tunnels, e.g. 20000-20100. TCP mode is disabled if unset.
+    #[arg(long, env = "TUNLY_TCP_PORTS", value_parser = tcp::parse_port_range)]
+    tcp_ports: Option<RangeInclusive<u16>>,
+
+    /// Seconds a disconnected session stays reserved for its client to resume it (default: 120)
+    #[arg(long, env = "TUNLY_RESUME_GRACE_SECS")]
+    resume_grace_secs: Option<u64>,
+
+    /// Seconds in-flight requests get to finish after SIGTERM before the server exits (default: 30)
+    #[arg(long, env = "TUNLY_DRAIN_TIMEOUT_SECS")]
+    drain_timeout_secs: Option<u64>,
+
+    /// (Optional) Base domain for Host routing, e.g. tunnel.example.com serves sessions at <sid>.tunnel.example.com (needs a wildcard DNS record)
+    #[arg(long, env = "TUNLY_DOMAIN")]
+    domain: Option<String>,
+
+    /// (Optional) Canonical base URL announced to clients, e.g. https://tunnel.example.com; defaults to the scheme and Host of the client's /ws request
+    #[arg(long, env = "TUNLY_PUBLIC_URL")]
+    public_url: Option<String>,
+
+    /// (Optional) JSON file persisting reserved names across restarts (env: TUNLY_STATE_FILE)
+    #[arg(long, env = "TUNLY_STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// (Optional) Only admit visitors from these CIDRs on every tunnel, comma-separated (env: TUNLY_ALLOW_CIDR)
+    #[arg(long, env = "TUNLY_ALLOW_CIDR", value_delimiter = ',', value_parser = access::parse_net)]
+    allow_cidr: Vec<IpNet>,
+
+    /// (Optional) Turn away visitors from these CIDRs on every tunnel, comma-separated (env: TUNLY_DENY_CIDR)
+    #[arg(long, env = "TUNLY_DENY_CIDR", value_delimiter = ',', value_parser = access::parse_net)]
+    deny_cidr: Vec<IpNet>,
+
+    /// (Optional) Reverse proxies/load balancers whose Forwarded / X-Forwarded-For headers are believed, comma-separated CIDRs (env: TUNLY_TRUSTED_PROXIES). Without it the peer address is used as is.
+    #[arg(long, env = "TUNLY_TRUSTED_PROXIES", value_delimiter = ',', value_parser = access::parse_net)]
+    trusted_proxies: Vec<IpNet>,
+
+    /// Require a HAProxy PROXY protocol (v1 or v2) header on every connection and take the visitor address from it (env: TUNLY_PROXY_PROTOCOL)
+    #[arg(long, env = "TUNLY_PROXY_PROTOCOL", default_value_t = false)]
+    proxy_protocol: bool,
+
+    /// (Optional) PEM certificate chain; with --tls-key the main listener serves HTTPS/WSS. Reloaded on SIGHUP or when the file changes (env: TUNLY_TLS_CERT)
+    #[arg(long, env = "TUNLY_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+
+    /// (Optional) PEM private key for --tls-cert (env: TUNLY_TLS_KEY)
+    #[arg(long, env = "TUNLY_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+
+    /// (Optional) Additional plain-HTTP listener when TLS is enabled, e.g. 0.0.0.0:80 (env: TUNLY_HTTP_BIND)
+    #[arg(long, env = "TUNLY_HTTP_BIND")]
+    http_bind: Option<SocketAddr>,
+
+    #[command(subcommand)]
+    command: Option<ServerCommand>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ServerCommand {
+    /// Long-lived access tokens, signed with the server's current key
+    #[command(subcommand)]
+    Token(TokenCommand),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum TokenCommand {
+    /// Mint an access token offline and print it; revoke it later with `tunly-ctl tokens revoke <jti>`
+    Create {
+        /// Who the token belongs to, e.g. alice@example.com
+        #[arg(long)]
+        sub: String,
+        /// Lifetime, e.g. 3600, 12h or 90d
+        #[arg(long, default_value = "30d", value_parser = tokens::parse_ttl)]
+        ttl: Duration,
+        /// Session ids or names the token may use (`*` wildcards), repeatable; any if unset
+        #[arg(long = "sid")]
+        sids: Vec<String>,
+        /// Tunnels the token may keep open at once (0 = unlimited)
+        #[arg(long, default_value_t = 0)]
+        max_sessions: u32,
+    },
+}
+
+/// Run a `tunly-server token ...` command and exit.
+fn run_command(command: ServerCommand, keys: KeySet) {
+    match command {
+        ServerCommand::Token(TokenCommand::Create {
+            sub,
+            ttl,
+            sids,
+            max_sessions,
+        }) => {
+            if !keys.can_sign() {
+                eprintln!(
+                    "token create needs the server's --jwt-secret, [auth] jwt_secret or an [[auth.keys]] private key"
+                );
+                std::process::exit(1);
+            }
+            let claims = AccessClaims::new(&sub, ttl, sids, max_sessions);
+            let token = claims
+                .sign(&keys)
+                .unwrap_or_else(|e| panic!("failed to sign token: {}", e));
+            eprintln!(
+                "jti: {}\nsub: {}\nexpires in: {}s",
+                claims.jti,
+                claims.sub,
+                ttl.as_secs()
+            );
+            println!("{}", token);
+        }
+    }
+}
+
+/// Reloadable settings: the config file's [limits] and [policy], overridden by flags.
+fn settings(args: &ServerArgs, file: &ConfigFile) -> Result<Settings, ConfigError> {
+    let mut settings = file.settings()?;
+    settings.allow_token_query |= args.allow_token_query;
+    if args.internal_key.is_some() {
+        settings.internal_key = args.internal_key.clone();
+    }
+    if args.admin_token.is_some() {
+        settings.admin_token = args.admin_token.clone();
+    }
+    if let Some(secs) = args.resume_grace_secs {
+        settings.limits.resume_grace_secs = secs;
+    }
+    if let Some(secs) = args.drain_timeout_secs {
+        settings.limits.drain_timeout_secs = secs;
+    }
+    if !args.allow_cidr.is_empty() {
+        settings.ip_filter.allow = args.allow_cidr.clone();
+    }
+    if !args.deny_cidr.is_empty() {
+        settings.ip_filter.deny = args.deny_cidr.clone();
+    }
+    if !args.trusted_proxies.is_empty() {
+        settings.trusted_proxies = args.trusted_proxies.clone();
+    }
+    Ok(settings)
+}
+
+/// Re-read the config file on SIGHUP and apply its limits and policies to live sessions.
+#[cfg(unix)]
+fn reload_on_sighup(state: Arc<AppState>, args: ServerArgs, path: PathBuf, loaded: ConfigFile) {
+    use tokio::signal::unix::{signal, SignalKind};
+    tokio::spawn(async move {
+        let mut hup = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+        while hup.recv().await.is_some() {
+            let reloaded =
+                ConfigFile::load(&path).and_then(|file| Ok((settings(&args, &file)?, file)));
+            match reloaded {
+                Ok((fresh, file)) => {
+                    if loaded.needs_restart(&file) {
+                        tracing::warn!(
+                            "{}: [server] and [auth] changes take effect after a restart",
+                            path.display()
+                        );
+                    }
+                    state.replace_settings(fresh);
+                    // Key files are re-read too, so a rotated PEM needs no config change
+                    match KeySet::load(&file.auth.keys, Some(&state.jwt_secret)) {
+                        Ok(keys) => state.replace_keys(keys),
+                        Err(e) => tracing::error!("Key reload failed, keeping current keys: {}", e),
+                    }
+                    tracing::info!("Reloaded {}", path.display());
+                }
+                Err(e) => tracing::error!("Config reload failed, keeping current settings: {}", e),
+            }
+        }
+    });
+}
+
+/// Resolves on SIGTERM (what deploys send) or Ctrl+C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = term.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Serve `app` on a listener whose peer address is a `SocketAddr`.
+async fn serve<L>(listener: L, app: Router)
+where
+    L: Listener<Addr = SocketAddr>,
+{
+    let svc = app.into_make_service_with_connect_info::<SocketAddr>();
+    // tap_io makes ConnectInfo<SocketAddr> available for any such listener
+    axum::serve(listener.tap_io(|_| {}), svc).await.unwrap();
+}
+
+/// Serve `app` on `tcp`, reading PROXY headers first if asked to and terminating TLS if configured.
+async fn serve_tcp(
+    tcp: TcpListener,
+    tls: Option<TlsAcceptor>,
+    proxy_protocol: bool,
+    state: Arc<AppState>,
+    app: Router,
+) {
+    // Only trusted proxies may claim a visitor address (anyone, if none are configured)
+    let admit = move |ip| {
+        let settings = state.settings();
+        settings.trusted_proxies.is_empty() || forwarded::is_trusted(&settings.trusted_proxies, ip)
+    };
+    let proxied = |tcp| ProxyProtocolListener::new(tcp, admit).unwrap();
+    match (tls, proxy_protocol) {
+        (None, false) => serve(tcp, app).await,
+        (None, true) => serve(proxied(tcp), app).await,
+        (Some(acceptor), false) => serve(TlsListener::new(tcp, acceptor), app).await,
+        (Some(acceptor), true) => serve(TlsListener::new(proxied(tcp), acceptor), app).await,
+    }
 }
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
     let args = ServerArgs::parse();
+    let file = match &args.config {
+        Some(path) => ConfigFile::load(path).unwrap_or_else(|e| panic!("{}", e)),
+        None => ConfigFile::default(),
+    };
+    let server = file.server.clone();
+    let configured_secret = args.jwt_secret.clone().or(file.auth.jwt_secret.clone());
+    if let Some(command) = args.command.clone() {
+        let keys = KeySet::load(
+            &file.auth.keys,
+            configured_secret.as_ref().map(|s| s.as_bytes()),
+        )
+        .unwrap_or_else(|e| panic!("{}", e));
+        run_command(command, keys);
+        return;
+    }
 
     // Auth mode: if --token or TUNLY_TOKEN provided => Fixed, else Ephemeral tokens via /token
     let auth_mode = if let Some(t) = args
         .token
         .clone()
         .or_else(|| std::env::var("TUNLY_TOKEN").ok())
+        .or_else(|| file.auth.token.clone())
     {
         AuthMode::Fixed(t)
     } else {
         AuthMode::Ephemeral
     };
 
-    let jwt_secret = if let Some(secret) = args.jwt_secret {
+    let jwt_secret = if let Some(secret) = configured_secret {
         secret.into_bytes()
     } else {
+        if file.auth.keys.is_empty() {
+            tracing::warn!(
+                "No --jwt-secret or [auth] keys configured; tokens will not survive a restart"
+            );
+        }
         let mut secret = vec![0u8; 32];
         rand::rng().fill_bytes(&mut secret);
         secret
     };
+    // The secret keeps signing ephemeral tokens when no configured key can
+    let keys = KeySet::load(&file.auth.keys, Some(&jwt_secret)).unwrap_or_else(|e| panic!("{}", e));
+    if !keys.kids().is_empty() {
+        tracing::info!("JWT keys: {}", keys.kids().join(", "));
+    }
 
-    let state = Arc::new(AppState {
-        _token: match &auth_mode {
-            AuthMode::Fixed(t) => t.clone(),
-            AuthMode::Ephemeral => String::new(),
-        },
-        req_id: AtomicU64::new(1),
-        auth_mode: auth_mode.clone(),
-        jwt_secret,
-        issued_tokens: Mutex::new(HashMap::new()),
-        sessions: RwLock::new(HashMap::new()),
-        rl: Mutex::new(HashMap::new()),
-        proxy_rl: Mutex::new(HashMap::new()),
-        allow_token_query: args.allow_token_query,
-        internal_key: args.internal_key,
-        metrics: Metrics::new(),
+    let bind_str = if let Some(b) = args.bind.clone().or(server.bind) {
+        b
+    } else {
+        let host = args.host.clone().or(server.host);
+        format!(
+            "{}:{}",
+            host.as_deref().unwrap_or("0.0.0.0"),
+            args.port.or(server.port).unwrap_or(8080)
+        )
+    };
+    let addr: SocketAddr = bind_str
+        .parse()
+        .expect("--bind must be like 0.0.0.0:9000 or use --host/--port");
+
+    let tcp_ports = args.tcp_ports.clone().or_else(|| {
+        server.tcp_ports.as_deref().map(|ports| {
+            tcp::parse_port_range(ports).unwrap_or_else(|e| panic!("invalid tcp_ports: {}", e))
+        })
     });
+    let tls_files = match (
+        args.tls_cert.clone().or(server.tls_cert),
+        args.tls_key.clone().or(server.tls_key),
+    ) {
+        (Some(cert), Some(key)) => Some((cert, key)),
+        (None, None) => None,
+        _ => panic!("--tls-cert and --tls-key must be set together"),
+    };
+    let http_bind = args.http_bind.or(server.http_bind);
+    if http_bind.is_some() && tls_files.is_none() {
+        panic!("--http-bind needs TLS on the main listener (--tls-cert/--tls-key)");
+    }
+    let proxy_protocol = args.proxy_protocol || server.proxy_protocol.unwrap_or(false);
+
+    let mut state = AppState::new(auth_mode, jwt_secret);
+    state.replace_keys(keys);
+    *state.settings_mut() = settings(&args, &file).unwrap_or_else(|e| panic!("{}", e));
+    state.tcp = tcp_ports.map(|ports| {
+        tracing::info!(
+            "TCP tunnels enabled on ports {}-{}",
+            ports.start(),
+            ports.end()
+        );
+        tcp::TcpConfig {
+            bind_ip: addr.ip(),
+            ports,
+        }
+    });
+    state.domain = args
+        .domain
+        .clone()
+        .or(server.domain)
+        .map(|d| d.trim().trim_matches('.').to_ascii_lowercase())
+        .filter(|d| !d.is_empty());
+    state.public_url = args
+        .public_url
+        .clone()
+        .or(server.public_url)
+        .map(|u| u.trim().trim_end_matches('/').to_string())
+        .filter(|u| !u.is_empty());
+    state.https = tls_files.is_some();
+    if let Some(path) = args.state_file.clone().or(server.state_file) {
+        state.store = tunly::store::Store::open(path.clone())
+            .unwrap_or_else(|e| panic!("failed to load --state-file {}: {}", path.display(), e));
+    }
+    let ip_filter = state.settings().ip_filter.clone();
+    if !ip_filter.is_empty() {
+        tracing::info!(
+            "Visitor IP lists: {} allowed, {} denied",
+            ip_filter.allow.len(),
+            ip_filter.deny.len()
+        );
+    }
+    if let Some(domain) = &state.domain {
+        tracing::info!("Host routing enabled for *.{}", domain);
+    }
+    let state = Arc::new(state);
 
     let app = tunly::create_app(state.clone());
 
-    // Background GC: periodically prune expired ephemeral tokens
+    #[cfg(unix)]
+    if let Some(path) = args.config.clone() {
+        reload_on_sighup(state.clone(), args.clone(), path, file.clone());
+    }
+
+    // Background GC: periodically prune expired ephemeral tokens, refresh credentials, session
+    // reservations and refilled rate-limit buckets
     {
         let gc_state = state.clone();
         tokio::spawn(async move {
@@ -100,6 +429,23 @@ async fn main() {
                 if removed > 0 {
                     tracing::info!("GC: removed {} expired token(s)", removed);
                 }
+                drop(issued);
+
+                gc_state.store.expire_refresh().await;
+
+                let mut reservations = gc_state.reservations.lock().await;
+                let before = reservations.len();
+                reservations.retain(|_, r| r.is_live(now));
+                let released = before.saturating_sub(reservations.len());
+                if released > 0 {
+                    tracing::info!("GC: released {} expired session reservation(s)", released);
+                }
+                drop(reservations);
+
+                let evicted = gc_state.rate_limits.evict_idle().await;
+                if evicted > 0 {
+                    tracing::debug!("GC: evicted {} idle rate-limit bucket(s)", evicted);
+                }
             }
         });
     }
@@ -120,18 +466,37 @@ async fn main() {
                         .map(|(k, v)| (k.clone(), v.clone()))
                         .collect()
                 };
+                let idle_ttl = gc_state.settings().limits.session_idle_ttl_secs;
                 let mut to_remove = Vec::new();
                 for (sid, sess) in entries {
                     let last = *sess.last_seen.lock().await;
-                    if now.duration_since(last).as_secs() >= SESSION_IDLE_TTL_SECS {
+                    let idle = now.duration_since(last).as_secs();
+                    if idle >= idle_ttl {
+                        sess.notify(
+                            Severity::Error,
+                            MessageCode::IdleTimeout,
+                            format!("session removed after {}s without traffic", idle),
+                        );
                         to_remove.push(sid);
+                    } else if idle + 60 >= idle_ttl {
+                        // Gone by the next pass unless traffic arrives
+                        sess.notify(
+                            Severity::Warning,
+                            MessageCode::IdleTimeout,
+                            format!(
+                                "no traffic for {}s; sessions are removed after {}s idle",
+                                idle, idle_ttl
+                            ),
+                        );
                     }
                 }
                 if !to_remove.is_empty() {
                     let mut sessions = gc_state.sessions.write().await;
                     let mut removed = 0usize;
                     for sid in to_remove {
-                        if sessions.remove(&sid).is_some() {
+                        // Closing the socket runs the usual disconnect cleanup
+                        if let Some(sess) = sessions.remove(&sid) {
+                            sess.close(CloseReason::IdleTimeout);
                             removed += 1;
                         }
                     }
@@ -143,18 +508,46 @@ async fn main() {
         });
     }
 
-    let bind_str = if let Some(b) = args.bind.clone() {
-        b
-    } else {
-        format!("{}:{}", args.host, args.port)
+    let tls = match tls_files {
+        Some((cert, key)) => {
+            let cert = Arc::new(
+                tls::ReloadableCert::load(cert, key)
+                    .unwrap_or_else(|e| panic!("failed to load TLS certificate: {}", e)),
+            );
+            cert.clone().watch();
+            Some(TlsAcceptor::from(Arc::new(tls::server_config(cert))))
+        }
+        None => None,
     };
-    let addr: SocketAddr = bind_str
-        .parse()
-        .expect("--bind must be like 0.0.0.0:9000 or use --host/--port");
-
-    tracing::info!("Tunly Server listening on http://{}", addr);
+    if proxy_protocol {
+        tracing::info!("Expecting PROXY protocol headers on incoming connections");
+    }
 
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    let svc = app.into_make_service_with_connect_info::<SocketAddr>();
-    axum::serve(listener, svc).await.unwrap();
+    let mut servers = tokio::task::JoinSet::new();
+    if let Some(http_addr) = http_bind {
+        tracing::info!("Tunly Server listening on http://{}", http_addr);
+        let listener = TcpListener::bind(http_addr).await.unwrap();
+        servers.spawn(serve_tcp(
+            listener,
+            None,
+            proxy_protocol,
+            state.clone(),
+            app.clone(),
+        ));
+    }
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    tracing::info!("Tunly Server listening on {}://{}", scheme, addr);
+    let listener = TcpListener::bind(addr).await.unwrap();
+    servers.spawn(serve_tcp(listener, tls, proxy_protocol, state.clone(), app));
+    // Listeners only return on error, which ends the process
+    tokio::select! {
+        _ = servers.join_next() => {}
+        _ = shutdown_signal() => {
+            let timeout = state.settings().drain_timeout();
+            tracing::info!("Shutting down, draining for up to {}s", timeout.as_secs());
+            if state.drain(timeout).await {
+                tracing::info!("In-flight requests finished");
+            }
+        }
+    }
 }
@@ -0,0 +1,250 @@
+//! `tunly-server --config tunly.toml`. The `[limits]` and `[policy]` sections and the
+//! `[[auth.keys]]` key set are reloaded on SIGHUP; the rest of `[server]` and `[auth]` is read at
+//! startup only. Flags and environment variables override the file.
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::{
+    access::{self, IpFilter},
+    keys::KeyConfig,
+    ratelimit::Quota,
+    PROXY_RL_MAX_PER_WINDOW, PROXY_RL_WINDOW_SECS, RESUME_GRACE_SECS, RL_MAX_KEYS,
+    RL_MAX_PER_WINDOW, RL_WINDOW_SECS, SESSION_IDLE_TTL_SECS, SESSION_RL_MAX_PER_WINDOW,
+    SESSION_RL_WINDOW_SECS, WS_RL_MAX_PER_WINDOW, WS_RL_WINDOW_SECS,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read {0}: {1}")]
+    Io(PathBuf, io::Error),
+    #[error("invalid {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("invalid setting: {0}")]
+    Invalid(String),
+}
+
+/// Tunable limits; every field can be changed on reload.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// `/token` requests allowed per IP and window
+    pub token_rate_max: u32,
+    pub token_rate_window_secs: u64,
+    /// Proxied requests allowed per visitor IP and window
+    pub proxy_rate_max: u32,
+    pub proxy_rate_window_secs: u64,
+    /// `/ws` connect attempts allowed per client IP and window
+    pub ws_rate_max: u32,
+    pub ws_rate_window_secs: u64,
+    /// Proxied requests allowed per session (all visitors together) and window
+    pub session_rate_max: u32,
+    pub session_rate_window_secs: u64,
+    /// Rate-limit buckets kept in memory at most
+    pub rate_limit_max_keys: usize,
+    /// Idle sessions are removed after this long
+    pub session_idle_ttl_secs: u64,
+    /// Request body cap for clients without streaming
+    pub max_body_bytes: usize,
+    /// How long to wait for the tunnel client to answer a request
+    pub upstream_timeout_secs: u64,
+    /// Lifetime of ephemeral tokens issued by `/token`
+    pub token_ttl_secs: u64,
+    /// Lifetime of refresh credentials sent over the tunnel; 0 sends none. Outstanding ones are
+    /// kept in the state file, when there is one, so they survive restarts
+    pub refresh_ttl_secs: u64,
+    /// Requests kept per session for `/s/<sid>/_log`
+    pub access_log_entries: usize,
+    /// How long a session id stays reserved after its client disconnects
+    pub resume_grace_secs: u64,
+    /// On shutdown, how long in-flight requests get to finish
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            token_rate_max: RL_MAX_PER_WINDOW,
+            token_rate_window_secs: RL_WINDOW_SECS,
+            proxy_rate_max: PROXY_RL_MAX_PER_WINDOW,
+            proxy_rate_window_secs: PROXY_RL_WINDOW_SECS,
+            ws_rate_max: WS_RL_MAX_PER_WINDOW,
+            ws_rate_window_secs: WS_RL_WINDOW_SECS,
+            session_rate_max: SESSION_RL_MAX_PER_WINDOW,
+            session_rate_window_secs: SESSION_RL_WINDOW_SECS,
+            rate_limit_max_keys: RL_MAX_KEYS,
+            session_idle_ttl_secs: SESSION_IDLE_TTL_SECS,
+            max_body_bytes: 2 * 1024 * 1024,
+            upstream_timeout_secs: 30,
+            token_ttl_secs: 300,
+            refresh_ttl_secs: 86400,
+            access_log_entries: 50,
+            resume_grace_secs: RESUME_GRACE_SECS,
+            drain_timeout_secs: 30,
+        }
+    }
+}
+
+/// Limits and policies in effect; swapped as a whole when the configuration is reloaded.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub limits: Limits,
+    /// Allow the token in the /ws query string
+    pub allow_token_query: bool,
+    /// (Optional) Key required in `X-Internal-Key` to call /token
+    pub internal_key: Option<String>,
+    /// (Optional) Bearer token for `/admin/api`; the admin API is off when unset
+    pub admin_token: Option<String>,
+    /// Server-wide visitor IP allow/deny lists, checked before any session's own
+    pub ip_filter: IpFilter,
+    /// Proxies whose `Forwarded` / `X-Forwarded-For` entries are believed
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl Settings {
+    pub fn resume_grace(&self) -> Duration {
+        Duration::from_secs(self.limits.resume_grace_secs)
+    }
+
+    pub fn upstream_timeout(&self) -> Duration {
+        Duration::from_secs(self.limits.upstream_timeout_secs)
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.limits.drain_timeout_secs)
+    }
+
+    pub fn token_quota(&self) -> Quota {
+        Quota::new(
+            self.limits.token_rate_max,
+            self.limits.token_rate_window_secs,
+        )
+    }
+
+    pub fn ws_quota(&self) -> Quota {
+        Quota::new(self.limits.ws_rate_max, self.limits.ws_rate_window_secs)
+    }
+
+    pub fn proxy_quota(&self) -> Quota {
+        Quota::new(
+            self.limits.proxy_rate_max,
+            self.limits.proxy_rate_window_secs,
+        )
+    }
+
+    pub fn session_quota(&self) -> Quota {
+        Quota::new(
+            self.limits.session_rate_max,
+            self.limits.session_rate_window_secs,
+        )
+    }
+}
+
+/// Listener and routing settings (restart to change).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub bind: Option<String>,
+    pub http_bind: Option<SocketAddr>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub proxy_protocol: Option<bool>,
+    pub domain: Option<String>,
+    pub public_url: Option<String>,
+    pub tcp_ports: Option<String>,
+    pub state_file: Option<PathBuf>,
+}
+
+/// Tunnel client authentication (restart to change, except `keys`).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+    /// Fixed token; ephemeral tokens via /token when unset
+    pub token: Option<String>,
+    pub jwt_secret: Option<String>,
+    /// Token signing keys, selected by `kid`; `jwt_secret` still checks tokens without one
+    pub keys: Vec<KeyConfig>,
+}
+
+/// Access policies (reloadable).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicySection {
+    pub allow_token_query: bool,
+    pub internal_key: Option<String>,
+    pub admin_token: Option<String>,
+    pub trusted_proxies: Vec<String>,
+    pub allow_cidr: Vec<String>,
+    pub deny_cidr: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub server: ServerSection,
+    pub auth: AuthSection,
+    pub limits: Limits,
+    pub policy: PolicySection,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let file: Self =
+            toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+        // Catch bad CIDRs now rather than on first use
+        file.settings()?;
+        Ok(file)
+    }
+
+    /// Runtime settings described by the `[limits]` and `[policy]` sections.
+    pub fn settings(&self) -> Result<Settings, ConfigError> {
+        let nets = |list: &[String]| {
+            list.iter()
+                .map(|s| access::parse_net(s))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(ConfigError::Invalid)
+        };
+        let limits = &self.limits;
+        if [
+            limits.proxy_rate_window_secs,
+            limits.token_rate_window_secs,
+            limits.ws_rate_window_secs,
+            limits.session_rate_window_secs,
+        ]
+        .contains(&0)
+        {
+            return Err(ConfigError::Invalid(
+                "rate limit windows must be at least 1 second".to_string(),
+            ));
+        }
+        Ok(Settings {
+            limits: self.limits.clone(),
+            allow_token_query: self.policy.allow_token_query,
+            internal_key: self.policy.internal_key.clone(),
+            admin_token: self.policy.admin_token.clone(),
+            ip_filter: IpFilter {
+                allow: nets(&self.policy.allow_cidr)?,
+                deny: nets(&self.policy.deny_cidr)?,
+            },
+            trusted_proxies: nets(&self.policy.trusted_proxies)?,
+        })
+    }
+
+    /// Whether `other` changes anything that only takes effect after a restart.
+    pub fn needs_restart(&self, other: &Self) -> bool {
+        self.server != other.server
+            || self.auth.token != other.auth.token
+            || self.auth.jwt_secret != other.auth.jwt_secret
+    }
+}
@@ -0,0 +1,125 @@
+//! Credit-based flow control for streams multiplexed over the tunnel.
+//!
+//! Each direction of a stream (a streamed body, a relayed WebSocket or TCP connection) starts
+//! with a window of [`STREAM_BUFFER_CHUNKS`] chunks. The sender spends one credit per chunk and
+//! waits once it has none left; the receiver hands credits back in `credit` messages as chunks
+//! are taken off its buffer. A tunnel read loop can then hand every chunk over without waiting:
+//! a buffer that is full anyway means the peer ignored its window, and only that stream is
+//! dropped instead of stalling every other stream on the tunnel.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::{Credit, STREAM_BUFFER_CHUNKS};
+
+/// Capacity of a stream's receive buffer: the window plus one slot kept free for the message
+/// that ends the stream, so an end or error always fits behind a full window.
+pub const RECV_BUFFER: usize = STREAM_BUFFER_CHUNKS + 1;
+
+/// Hand a chunk to a stream's receive buffer without waiting. `Err` gives the item back when
+/// the buffer holds a full window already (the sender overran it) or the receiver is gone.
+pub fn offer<T>(tx: &mpsc::Sender<T>, item: T) -> Result<(), T> {
+    if tx.capacity() <= RECV_BUFFER - STREAM_BUFFER_CHUNKS {
+        return Err(item);
+    }
+    tx.try_send(item).map_err(|e| e.into_inner())
+}
+
+/// Send side of one stream's window.
+#[derive(Debug, Clone)]
+pub struct SendWindow {
+    // None when the peer does not speak `credit`: chunks are sent as fast as the tunnel takes them
+    credits: Option<Arc<Semaphore>>,
+}
+
+impl SendWindow {
+    /// Wait for credit to send one more chunk; false once the stream was dropped.
+    pub async fn reserve(&self) -> bool {
+        match &self.credits {
+            Some(credits) => credits.acquire().await.map(|p| p.forget()).is_ok(),
+            None => true,
+        }
+    }
+}
+
+/// Send windows of a connection's streams, by stream id.
+#[derive(Debug, Default)]
+pub struct SendWindows {
+    windows: Mutex<HashMap<u64, Arc<Semaphore>>>,
+}
+
+impl SendWindows {
+    /// Window for sending on stream `id`; unlimited when the peer does not speak `credit`.
+    pub fn open(&self, id: u64, credit: bool) -> SendWindow {
+        if !credit {
+            return SendWindow { credits: None };
+        }
+        let credits = Arc::new(Semaphore::new(STREAM_BUFFER_CHUNKS));
+        let mut windows = self.windows.lock().unwrap();
+        // Forget streams whose sender is done
+        windows.retain(|_, w| Arc::strong_count(w) > 1);
+        windows.insert(id, credits.clone());
+        SendWindow {
+            credits: Some(credits),
+        }
+    }
+
+    /// Credit from the receiver; never grows a window past its initial size.
+    pub fn grant(&self, credit: &Credit) {
+        if let Some(w) = self.windows.lock().unwrap().get(&credit.id) {
+            let room = STREAM_BUFFER_CHUNKS.saturating_sub(w.available_permits());
+            w.add_permits((credit.chunks as usize).min(room));
+        }
+    }
+
+    /// Stop sending on stream `id`; a sender waiting for credit gives up.
+    pub fn close(&self, id: u64) {
+        if let Some(w) = self.windows.lock().unwrap().remove(&id) {
+            w.close();
+        }
+    }
+
+    /// Stop every sender, e.g. when the tunnel is gone.
+    pub fn close_all(&self) {
+        for (_, w) in self.windows.lock().unwrap().drain() {
+            w.close();
+        }
+    }
+}
+
+/// Receive side of one stream's window: counts chunks taken off the buffer and says when to
+/// hand credit back, in batches of half a window.
+#[derive(Debug)]
+pub struct RecvWindow {
+    id: u64,
+    credit: bool,
+    taken: u32,
+}
+
+impl RecvWindow {
+    /// `credit` is whether the peer speaks it; otherwise no credit is ever handed back.
+    pub fn new(id: u64, credit: bool) -> Self {
+        Self {
+            id,
+            credit,
+            taken: 0,
+        }
+    }
+
+    /// Call after taking a chunk off the buffer; returns the credit to send, if any is due.
+    pub fn taken(&mut self) -> Option<Credit> {
+        if !self.credit {
+            return None;
+        }
+        self.taken += 1;
+        if (self.taken as usize) < STREAM_BUFFER_CHUNKS / 2 {
+            return None;
+        }
+        Some(Credit {
+            id: self.id,
+            chunks: std::mem::take(&mut self.taken),
+        })
+    }
+}
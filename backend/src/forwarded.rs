@@ -0,0 +1,104 @@
+//! Resolving the visitor's address behind reverse proxies. Forwarding headers are only
+//! believed when they were added by a proxy in `--trusted-proxies`.
+
+use std::net::IpAddr;
+
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+
+pub fn is_trusted(trusted: &[IpNet], ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    trusted.iter().any(|net| net.contains(&ip))
+}
+
+/// Visitor address for a request that arrived from `peer`.
+///
+/// The forwarding chain (`Forwarded`, or `X-Forwarded-For` when there is none) is walked
+/// right to left, starting at the peer, for as long as each hop is a trusted proxy. The first
+/// untrusted hop is the visitor; anything to its left could have been made up by them.
+pub fn client_ip(trusted: &[IpNet], peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let mut client = peer.to_canonical();
+    if !is_trusted(trusted, client) {
+        return client;
+    }
+    for hop in forwarding_chain(headers).into_iter().rev() {
+        // An obfuscated or garbled hop ends the chain at the last proxy we know
+        let Some(ip) = hop else {
+            break;
+        };
+        client = ip.to_canonical();
+        if !is_trusted(trusted, client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Scheme the nearest proxy received the request with (`Forwarded: proto=` or
+/// `X-Forwarded-Proto`), if `peer` is a trusted proxy that said so.
+pub fn proto(trusted: &[IpNet], peer: IpAddr, headers: &HeaderMap) -> Option<String> {
+    if !is_trusted(trusted, peer) {
+        return None;
+    }
+    let nearest = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .last()
+            .map(str::to_string)
+    };
+    let proto = match nearest("forwarded") {
+        Some(element) => element
+            .split(';')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("proto"))?
+            .1
+            .to_string(),
+        None => nearest("x-forwarded-proto")?,
+    };
+    Some(proto.trim().trim_matches('"').to_ascii_lowercase())
+}
+
+/// Hops listed by the forwarding headers, left (original client) to right (nearest proxy).
+fn forwarding_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<&str> = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .flat_map(|v| v.split(','))
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect();
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+/// Parse a node such as `192.0.2.43`, `"192.0.2.43:47011"` or `"[2001:db8::1]:4711"`.
+/// Obfuscated identifiers and `unknown` yield `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.split_once(':')?.0.parse().ok()
+}
@@ -0,0 +1,68 @@
+//! Binary WebSocket framing for tunnel messages.
+//!
+//! A binary frame is `[u32 BE header length][JSON header][raw payload]`. The JSON header
+//! is the regular message with its payload field emptied, so body bytes travel as-is
+//! instead of being base64 encoded inside the JSON.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{ClientToServer, ServerToClient};
+
+#[derive(Debug, thiserror::Error)]
+pub enum FrameError {
+    #[error("frame too short")]
+    Truncated,
+    #[error("invalid frame header: {0}")]
+    Header(#[from] serde_json::Error),
+}
+
+/// Messages that may carry a raw payload outside the JSON header.
+pub trait FramePayload {
+    fn payload_mut(&mut self) -> Option<&mut Vec<u8>>;
+}
+
+impl FramePayload for ServerToClient {
+    fn payload_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            ServerToClient::RequestBody(chunk) => Some(&mut chunk.data),
+            ServerToClient::WsFrame(frame) => Some(&mut frame.data),
+            ServerToClient::TcpData(chunk) => Some(&mut chunk.data),
+            _ => None,
+        }
+    }
+}
+
+impl FramePayload for ClientToServer {
+    fn payload_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            ClientToServer::ResponseBody(chunk) => Some(&mut chunk.data),
+            ClientToServer::WsFrame(frame) => Some(&mut frame.data),
+            ClientToServer::TcpData(chunk) => Some(&mut chunk.data),
+            _ => None,
+        }
+    }
+}
+
+pub fn encode_binary<T: Serialize + FramePayload>(mut msg: T) -> Result<Vec<u8>, FrameError> {
+    let payload = msg.payload_mut().map(std::mem::take).unwrap_or_default();
+    let header = serde_json::to_vec(&msg)?;
+    let mut frame = Vec::with_capacity(4 + header.len() + payload.len());
+    frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+pub fn decode_binary<T: DeserializeOwned + FramePayload>(frame: &[u8]) -> Result<T, FrameError> {
+    let len_bytes: [u8; 4] = frame
+        .get(..4)
+        .and_then(|b| b.try_into().ok())
+        .ok_or(FrameError::Truncated)?;
+    let header_len = u32::from_be_bytes(len_bytes) as usize;
+    let header = frame.get(4..4 + header_len).ok_or(FrameError::Truncated)?;
+    let mut msg: T = serde_json::from_slice(header)?;
+    if let Some(payload) = msg.payload_mut() {
+        *payload = frame[4 + header_len..].to_vec();
+    }
+    Ok(msg)
+}
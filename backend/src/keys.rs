@@ -0,0 +1,249 @@
+//! JWT signing keys: `[[auth.keys]]` entries from the config file plus the legacy `jwt_secret`.
+//! Tokens carry the id of the key that signed them in their `kid` header and are verified with
+//! that key only, so an old and a new key can both be accepted while tokens move over. Ed25519
+//! (`EdDSA`) and RSA (`RS256`) keys may be configured with just a public key, for tokens minted
+//! by a separate issuer that this server only checks.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyError {
+    #[error("failed to read {0}: {1}")]
+    Io(PathBuf, io::Error),
+    #[error("key {0}: {1}")]
+    Invalid(String, String),
+    #[error("no key can sign tokens (configure jwt_secret or a key with a private key)")]
+    NoSigningKey,
+    #[error("token signed with unknown key {0:?}")]
+    UnknownKey(String),
+    #[error("key {0} has been retired")]
+    Retired(String),
+    #[error(transparent)]
+    Jwt(#[from] jsonwebtoken::errors::Error),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum KeyAlgorithm {
+    #[default]
+    HS256,
+    EdDSA,
+    RS256,
+}
+
+impl From<KeyAlgorithm> for Algorithm {
+    fn from(alg: KeyAlgorithm) -> Self {
+        match alg {
+            KeyAlgorithm::HS256 => Algorithm::HS256,
+            KeyAlgorithm::EdDSA => Algorithm::EdDSA,
+            KeyAlgorithm::RS256 => Algorithm::RS256,
+        }
+    }
+}
+
+/// One `[[auth.keys]]` entry.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyConfig {
+    /// Sent as the `kid` header of tokens signed with this key
+    pub kid: String,
+    #[serde(default)]
+    pub alg: KeyAlgorithm,
+    /// Shared secret (HS256)
+    pub secret: Option<String>,
+    /// PEM public key (EdDSA, RS256); enough to verify tokens
+    pub public_key: Option<PathBuf>,
+    /// PEM private key (EdDSA, RS256); lets this server sign tokens as well
+    pub private_key: Option<PathBuf>,
+    /// Unix seconds from which the key signs new tokens, so it can reach every replica first
+    pub not_before: Option<u64>,
+    /// Unix seconds after which tokens signed with this key are refused
+    pub not_after: Option<u64>,
+}
+
+struct Key {
+    kid: Option<String>,
+    alg: Algorithm,
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+    not_before: u64,
+    not_after: Option<u64>,
+}
+
+impl Key {
+    fn is_retired(&self, now: u64) -> bool {
+        self.not_after.is_some_and(|t| now >= t)
+    }
+
+    fn can_sign(&self, now: u64) -> bool {
+        self.encoding.is_some() && self.not_before <= now && !self.is_retired(now)
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, KeyError> {
+    std::fs::read(path).map_err(|e| KeyError::Io(path.to_path_buf(), e))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl KeyConfig {
+    fn load(&self) -> Result<Key, KeyError> {
+        let invalid = |msg: &str| KeyError::Invalid(self.kid.clone(), msg.to_string());
+        if self.kid.is_empty() {
+            return Err(KeyError::Invalid(String::new(), "kid is empty".to_string()));
+        }
+        let (encoding, decoding) = match self.alg {
+            KeyAlgorithm::HS256 => {
+                if self.public_key.is_some() || self.private_key.is_some() {
+                    return Err(invalid("HS256 keys take a secret, not PEM files"));
+                }
+                let secret = self
+                    .secret
+                    .as_deref()
+                    .filter(|s| !s.is_empty())
+                    .ok_or_else(|| invalid("HS256 needs a secret"))?;
+                (
+                    Some(EncodingKey::from_secret(secret.as_bytes())),
+                    DecodingKey::from_secret(secret.as_bytes()),
+                )
+            }
+            KeyAlgorithm::EdDSA | KeyAlgorithm::RS256 => {
+                if self.secret.is_some() {
+                    return Err(invalid("only HS256 keys take a secret"));
+                }
+                let public = self
+                    .public_key
+                    .as_deref()
+                    .ok_or_else(|| invalid("public_key is required"))?;
+                let public = read_pem(public)?;
+                let private = self.private_key.as_deref().map(read_pem).transpose()?;
+                let bad_pem = |e: jsonwebtoken::errors::Error| invalid(&e.to_string());
+                if self.alg == KeyAlgorithm::EdDSA {
+                    (
+                        private
+                            .map(|pem| EncodingKey::from_ed_pem(&pem))
+                            .transpose()
+                            .map_err(bad_pem)?,
+                        DecodingKey::from_ed_pem(&public).map_err(bad_pem)?,
+                    )
+                } else {
+                    (
+                        private
+                            .map(|pem| EncodingKey::from_rsa_pem(&pem))
+                            .transpose()
+                            .map_err(bad_pem)?,
+                        DecodingKey::from_rsa_pem(&public).map_err(bad_pem)?,
+                    )
+                }
+            }
+        };
+        Ok(Key {
+            kid: Some(self.kid.clone()),
+            alg: self.alg.into(),
+            encoding,
+            decoding,
+            not_before: self.not_before.unwrap_or(0),
+            not_after: self.not_after,
+        })
+    }
+}
+
+/// Keys tokens are signed and verified with.
+#[derive(Default)]
+pub struct KeySet {
+    keys: Vec<Key>,
+}
+
+impl KeySet {
+    /// A single HS256 secret without a key id, as used before key sets existed.
+    pub fn from_secret(secret: &[u8]) -> Self {
+        Self {
+            keys: vec![Key {
+                kid: None,
+                alg: Algorithm::HS256,
+                encoding: Some(EncodingKey::from_secret(secret)),
+                decoding: DecodingKey::from_secret(secret),
+                not_before: 0,
+                not_after: None,
+            }],
+        }
+    }
+
+    /// The configured keys, followed by `legacy_secret` (if any) for tokens without a `kid`.
+    pub fn load(configs: &[KeyConfig], legacy_secret: Option<&[u8]>) -> Result<Self, KeyError> {
+        let mut keys = Vec::with_capacity(configs.len() + 1);
+        for config in configs {
+            if keys
+                .iter()
+                .any(|k: &Key| k.kid.as_ref() == Some(&config.kid))
+            {
+                return Err(KeyError::Invalid(
+                    config.kid.clone(),
+                    "kid is used twice".to_string(),
+                ));
+            }
+            keys.push(config.load()?);
+        }
+        let mut set = Self { keys };
+        if let Some(secret) = legacy_secret {
+            set.keys.extend(Self::from_secret(secret).keys);
+        }
+        Ok(set)
+    }
+
+    /// Ids of the configured keys, in order.
+    pub fn kids(&self) -> Vec<&str> {
+        self.keys.iter().filter_map(|k| k.kid.as_deref()).collect()
+    }
+
+    /// Whether some key can sign tokens right now.
+    pub fn can_sign(&self) -> bool {
+        let now = now();
+        self.keys.iter().any(|k| k.can_sign(now))
+    }
+
+    /// Sign with the most recently activated key that holds private material; among keys
+    /// activated at the same time, the first listed wins.
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, KeyError> {
+        let now = now();
+        let key = self
+            .keys
+            .iter()
+            .rev()
+            .filter(|k| k.can_sign(now))
+            .max_by_key(|k| k.not_before)
+            .ok_or(KeyError::NoSigningKey)?;
+        let mut header = Header::new(key.alg);
+        header.kid = key.kid.clone();
+        // can_sign guarantees the encoding key
+        let encoding = key.encoding.as_ref().ok_or(KeyError::NoSigningKey)?;
+        Ok(encode(&header, claims, encoding)?)
+    }
+
+    /// Claims of `token` if the key named by its `kid` header (and algorithm) verifies it.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Result<T, KeyError> {
+        let header = decode_header(token)?;
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.kid == header.kid && k.alg == header.alg)
+            .ok_or_else(|| KeyError::UnknownKey(header.kid.clone().unwrap_or_default()))?;
+        if key.is_retired(now()) {
+            return Err(KeyError::Retired(header.kid.unwrap_or_default()));
+        }
+        Ok(decode::<T>(token, &key.decoding, &Validation::new(key.alg))?.claims)
+    }
+}
@@ -1,38 +1,56 @@
 use std::{
     collections::HashMap,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use axum::{
+    body::HttpBody,
     extract::{
         connect_info::ConnectInfo,
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        Path, Query, State,
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        FromRequest, FromRequestParts, Path, Query, State,
     },
     http::{HeaderMap, Request, StatusCode, Uri},
     response::{IntoResponse, Response},
-    routing::{any, get},
+    routing::{any, get, post},
     Router,
 };
 use base64::{engine::general_purpose, Engine as _};
 use flate2::read::{ZlibDecoder, ZlibEncoder};
 use flate2::Compression;
 use futures::{SinkExt, StreamExt};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use ipnet::IpNet;
 use prometheus::{Counter, Encoder, Gauge, Histogram, HistogramOpts, Registry, TextEncoder};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::io::Read;
-use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
+use tokio::sync::{mpsc, oneshot, Mutex, Notify, RwLock};
 use tower_http::normalize_path::NormalizePathLayer;
 use tower_http::trace::TraceLayer;
 
-// Simple per-IP rate limit for /token: 10 requests per 60 seconds
+pub mod access;
+pub mod admin;
+pub mod config;
+pub mod flow;
+pub mod forwarded;
+pub mod frame;
+pub mod keys;
+pub mod pop;
+pub mod proxy_protocol;
+pub mod ratelimit;
+pub mod store;
+pub mod tcp;
+pub mod tls;
+pub mod tokens;
+
+use ratelimit::{Decision, RouteClass};
+
+// Per-IP rate limit for /token: 10 requests per 60 seconds
 pub const RL_WINDOW_SECS: u64 = 60;
 pub const RL_MAX_PER_WINDOW: u32 = 10;
 
@@ -40,15 +58,95 @@ pub const RL_MAX_PER_WINDOW: u32 = 10;
 pub const PROXY_RL_WINDOW_SECS: u64 = 60;
 pub const PROXY_RL_MAX_PER_WINDOW: u32 = 120;
 
+// Per-IP rate limit for /ws connect attempts: 30 per 60 seconds
+pub const WS_RL_WINDOW_SECS: u64 = 60;
+pub const WS_RL_MAX_PER_WINDOW: u32 = 30;
+
+// Per-session rate limit for proxy requests, all visitors together: 600 per 60 seconds
+pub const SESSION_RL_WINDOW_SECS: u64 = 60;
+pub const SESSION_RL_MAX_PER_WINDOW: u32 = 600;
+
+// Rate-limit buckets kept at most; the least recently used is dropped beyond this
+pub const RL_MAX_KEYS: usize = 100_000;
+
 // Session idle TTL (seconds) before being GC-removed if no activity
 pub const SESSION_IDLE_TTL_SECS: u64 = 600;
 
+// Max payload carried by a single streamed body chunk over the tunnel
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+// Per-request buffer (in chunks) between the tunnel and a streamed body
+pub const STREAM_BUFFER_CHUNKS: usize = 16;
+
+// Header used by client and server to negotiate optional protocol features on /ws
+pub const FEATURES_HEADER: &str = "x-tunly-features";
+
+// Response header naming the `MessageCode` when the client reported a failed request
+pub const ERROR_HEADER: &str = "x-tunly-error";
+
+// Response header on /ws: the server answers a `hello` with a `welcome` in this protocol version
+pub const PROTOCOL_HEADER: &str = "x-tunly-protocol";
+
+/// Tunnel protocol version spoken by this build. Peers that skip the `hello`/`welcome` exchange
+/// are treated as version 1, which only negotiates features through [`FEATURES_HEADER`].
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest version accepted in a `hello` or `welcome`.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Shortest time between two notices with the same code to one client.
+pub const NOTICE_INTERVAL_SECS: u64 = 10;
+
+/// Body compression codecs this build can encode and decode.
+pub const COMPRESSION_CODECS: &[&str] = &["zlib"];
+
+// Response header on /ws carrying the public port allocated to a TCP tunnel
+pub const TCP_PORT_HEADER: &str = "x-tunly-tcp-port";
+
+// Header carrying the session resume secret (server -> client on connect, client -> server on reconnect)
+pub const RESUME_HEADER: &str = "x-tunly-resume";
+
+// Default time a disconnected session id stays reserved for its client to resume
+pub const RESUME_GRACE_SECS: u64 = 120;
+
+// Response header on /ws carrying the session's public host when Host routing is enabled
+pub const PUBLIC_HOST_HEADER: &str = "x-tunly-public-host";
+
+// Request header on /ws with the key that claims (and later proves ownership of) a name
+pub const NAME_KEY_HEADER: &str = "x-tunly-name-key";
+
+// Request header on /ws with credentials visitors must present (`basic user:pass` or `passphrase <p>`)
+pub const VISITOR_AUTH_HEADER: &str = "x-tunly-visitor-auth";
+
+// Request headers on /ws with comma-separated CIDRs visitors must (not) come from
+pub const ALLOW_CIDR_HEADER: &str = "x-tunly-allow-cidr";
+pub const DENY_CIDR_HEADER: &str = "x-tunly-deny-cidr";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String, // sid
     pub ip: String,
     pub exp: usize,
     pub jti: String,
+    /// Client public key the token is bound to instead of an IP (proof of possession)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<String>,
+}
+
+/// `kind` claim of refresh credentials.
+pub const REFRESH_KIND: &str = "refresh";
+
+/// Refresh credential sent over an established tunnel; `/token/refresh` trades it, once, for a
+/// new ephemeral token for the same session.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshClaims {
+    pub kind: String,
+    pub sub: String, // sid
+    pub exp: usize,
+    pub jti: String,
+    /// Client key the session was opened with; the new token is bound to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -64,7 +162,7 @@ pub enum AuthMode {
     Ephemeral,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AccessLogEntry {
     pub method: String,
     pub uri: String,
@@ -72,13 +170,275 @@ pub struct AccessLogEntry {
     pub dur_ms: u128,
 }
 
+/// Optional protocol features negotiated per connection via `x-tunly-features`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features {
+    /// Chunked request/response bodies (`request_start`/`response_start` + body chunks)
+    pub streaming: bool,
+    /// Length-prefixed binary frames (see [`frame`]) instead of JSON text messages
+    pub binary: bool,
+    /// Relaying visitor WebSocket upgrades (`ws_open` + frames)
+    pub websocket: bool,
+    /// Raw TCP relay (`tcp_open` + data)
+    pub tcp: bool,
+    /// `refresh_token` messages, exchanged at `/token/refresh` after a disconnect
+    pub refresh: bool,
+    /// `cancel` messages for requests the visitor or the server gave up on
+    pub cancel: bool,
+    /// `notice` and `error` messages in both directions
+    pub notices: bool,
+    /// `credit` messages: per-stream flow-control windows (see [`flow`])
+    pub credit: bool,
+}
+
+impl Features {
+    /// Everything this build knows how to speak.
+    pub fn supported() -> Self {
+        Self {
+            streaming: true,
+            binary: true,
+            websocket: true,
+            tcp: true,
+            refresh: true,
+            cancel: true,
+            notices: true,
+            credit: true,
+        }
+    }
+
+    /// Parse a comma separated feature list; unknown entries are ignored.
+    pub fn parse(value: &str) -> Self {
+        let mut features = Self::default();
+        for name in value.split(',').map(|s| s.trim().to_ascii_lowercase()) {
+            match name.as_str() {
+                "stream" => features.streaming = true,
+                "binary" => features.binary = true,
+                "websocket" => features.websocket = true,
+                "tcp" => features.tcp = true,
+                "refresh" => features.refresh = true,
+                "cancel" => features.cancel = true,
+                "notices" => features.notices = true,
+                "credit" => features.credit = true,
+                _ => {}
+            }
+        }
+        features
+    }
+
+    pub fn intersect(self, other: Self) -> Self {
+        Self {
+            streaming: self.streaming && other.streaming,
+            binary: self.binary && other.binary,
+            websocket: self.websocket && other.websocket,
+            tcp: self.tcp && other.tcp,
+            refresh: self.refresh && other.refresh,
+            cancel: self.cancel && other.cancel,
+            notices: self.notices && other.notices,
+            credit: self.credit && other.credit,
+        }
+    }
+
+    pub fn to_header_value(self) -> String {
+        self.names().join(",")
+    }
+
+    /// Names of the enabled features, as used in the header and in `hello`/`welcome`.
+    pub fn names(self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.streaming {
+            names.push("stream");
+        }
+        if self.binary {
+            names.push("binary");
+        }
+        if self.websocket {
+            names.push("websocket");
+        }
+        if self.tcp {
+            names.push("tcp");
+        }
+        if self.refresh {
+            names.push("refresh");
+        }
+        if self.cancel {
+            names.push("cancel");
+        }
+        if self.notices {
+            names.push("notices");
+        }
+        if self.credit {
+            names.push("credit");
+        }
+        names
+    }
+}
+
+type BodySender = mpsc::Sender<Result<axum::body::Bytes, std::io::Error>>;
+type StreamSender = mpsc::Sender<ClientToServer>;
+
 #[derive(Debug)]
 pub struct SessionState {
     pub outbound_tx: mpsc::Sender<ServerToClient>,
     pub pending: Mutex<HashMap<u64, oneshot::Sender<ClientToServer>>>,
-    pub _created_at: Instant,
+    // request id -> visitor response body, fed by `response_body` chunks
+    pub response_bodies: Mutex<HashMap<u64, BodySender>>,
+    // stream id -> relayed visitor WebSocket or TCP connection, fed by frames/data from the client
+    pub streams: Mutex<HashMap<u64, StreamSender>>,
+    // Credit the client granted for chunks we send on its streams
+    pub windows: flow::SendWindows,
+    pub features: Features,
+    // Public port when this session is a raw TCP tunnel
+    pub tcp_port: Option<u16>,
+    // Credentials visitors must present before traffic is forwarded
+    pub visitor_auth: Option<access::VisitorAuth>,
+    // Visitor addresses this session accepts, on top of the server-wide lists
+    pub ip_filter: access::IpFilter,
+    // Address the tunnel client connected from
+    pub client_ip: Option<String>,
+    // Access token the client authenticated with, if any
+    pub credential: Option<tokens::Credential>,
+    pub created_at: SystemTime,
     pub last_seen: Mutex<Instant>,
     pub access_log: Mutex<Vec<AccessLogEntry>>, // ring buffer (last N)
+    // Entries ever logged, so readers can tell which ones are new
+    pub access_logged: AtomicU64,
+    // Visitor requests and connections handled over this tunnel
+    pub requests: AtomicU64,
+    // Signalled to drop the client connection (admin API, takeover); see `close`
+    pub terminate: Notify,
+    close_reason: std::sync::Mutex<Option<CloseReason>>,
+    // code -> when a notice with it was last sent; see `notify`
+    notices_sent: std::sync::Mutex<HashMap<MessageCode, Instant>>,
+}
+
+/// Why the server closed a tunnel client's connection, sent as the WebSocket close code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Disconnected by an operator
+    Terminated,
+    /// The access token the session was opened with was revoked
+    Revoked,
+    /// Another connection proved it owns the session and replaced this one
+    TakenOver,
+    /// The client's `hello` asked for a protocol version or codecs this server cannot speak
+    Incompatible,
+    /// Removed by the idle-session GC after `session_idle_ttl_secs` without traffic
+    IdleTimeout,
+}
+
+impl CloseReason {
+    pub fn code(self) -> u16 {
+        match self {
+            CloseReason::Terminated => 4000,
+            CloseReason::Revoked => 4001,
+            CloseReason::TakenOver => 4002,
+            CloseReason::Incompatible => 4003,
+            CloseReason::IdleTimeout => 4004,
+        }
+    }
+
+    pub fn from_code(code: u16) -> Option<Self> {
+        [
+            CloseReason::Terminated,
+            CloseReason::Revoked,
+            CloseReason::TakenOver,
+            CloseReason::Incompatible,
+            CloseReason::IdleTimeout,
+        ]
+        .into_iter()
+        .find(|r| r.code() == code)
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            CloseReason::Terminated => "disconnected by operator",
+            CloseReason::Revoked => "access token revoked",
+            CloseReason::TakenOver => "session taken over by another connection",
+            CloseReason::Incompatible => "incompatible protocol version or compression codecs",
+            CloseReason::IdleTimeout => "session idle for too long",
+        }
+    }
+}
+
+impl SessionState {
+    pub fn new(outbound_tx: mpsc::Sender<ServerToClient>, features: Features) -> Self {
+        Self {
+            outbound_tx,
+            pending: Mutex::new(HashMap::new()),
+            response_bodies: Mutex::new(HashMap::new()),
+            streams: Mutex::new(HashMap::new()),
+            windows: flow::SendWindows::default(),
+            features,
+            tcp_port: None,
+            visitor_auth: None,
+            ip_filter: access::IpFilter::default(),
+            client_ip: None,
+            credential: None,
+            created_at: SystemTime::now(),
+            last_seen: Mutex::new(Instant::now()),
+            access_log: Mutex::new(Vec::new()),
+            access_logged: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            terminate: Notify::new(),
+            close_reason: std::sync::Mutex::new(None),
+            notices_sent: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Close the client connection, telling the client why.
+    pub fn close(&self, reason: CloseReason) {
+        *self.close_reason.lock().unwrap() = Some(reason);
+        self.terminate.notify_one();
+    }
+
+    /// Tell the client about `code` if it understands notices and was not told about it in the
+    /// last [`NOTICE_INTERVAL_SECS`]. Never waits for a busy tunnel.
+    pub fn notify(&self, severity: Severity, code: MessageCode, message: String) {
+        if !self.features.notices {
+            return;
+        }
+        let now = Instant::now();
+        {
+            let mut sent = self.notices_sent.lock().unwrap();
+            if sent
+                .get(&code)
+                .is_some_and(|t| now.duration_since(*t).as_secs() < NOTICE_INTERVAL_SECS)
+            {
+                return;
+            }
+            sent.insert(code, now);
+        }
+        let notice = Notice {
+            severity,
+            code,
+            message,
+        };
+        let _ = self.outbound_tx.try_send(ServerToClient::Notice(notice));
+    }
+
+    /// Forget request `id` and, if the client understands it, tell it to stop working on it.
+    pub async fn cancel_request(&self, id: u64) {
+        self.pending.lock().await.remove(&id);
+        self.response_bodies.lock().await.remove(&id);
+        self.windows.close(id);
+        if self.features.cancel {
+            let _ = self
+                .outbound_tx
+                .send(ServerToClient::Cancel(Cancel { id }))
+                .await;
+        }
+    }
+
+    /// Append to the access log ring buffer, keeping the last `keep` entries.
+    pub async fn log_access(&self, entry: AccessLogEntry, keep: usize) {
+        let mut log = self.access_log.lock().await;
+        log.push(entry);
+        self.access_logged.fetch_add(1, Ordering::Relaxed);
+        if log.len() > keep {
+            let drop_n = log.len() - keep;
+            log.drain(0..drop_n);
+        }
+    }
 }
 
 pub struct Metrics {
@@ -86,6 +446,9 @@ pub struct Metrics {
     pub proxy_requests: Counter,
     pub proxy_latency_seconds: Histogram,
     pub active_sessions: Gauge,
+    pub tcp_connections: Counter,
+    pub ip_denied: Counter,
+    pub rate_limited: Counter,
 }
 
 impl Default for Metrics {
@@ -110,6 +473,11 @@ impl Metrics {
             "Current active WebSocket tunnel sessions",
         )
         .unwrap();
+        let tcp_connections = Counter::new(
+            "tcp_connections_total",
+            "Total public connections accepted by TCP tunnels",
+        )
+        .unwrap();
 
         registry.register(Box::new(proxy_requests.clone())).unwrap();
         registry
@@ -118,12 +486,31 @@ impl Metrics {
         registry
             .register(Box::new(active_sessions.clone()))
             .unwrap();
+        let ip_denied = Counter::new(
+            "ip_denied_total",
+            "Visitor requests and connections rejected by IP allow/deny lists",
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(tcp_connections.clone()))
+            .unwrap();
+        registry.register(Box::new(ip_denied.clone())).unwrap();
+        let rate_limited = Counter::new(
+            "rate_limited_total",
+            "Requests and connect attempts refused by rate limits",
+        )
+        .unwrap();
+        registry.register(Box::new(rate_limited.clone())).unwrap();
 
         Self {
             registry,
             proxy_requests,
             proxy_latency_seconds,
             active_sessions,
+            tcp_connections,
+            ip_denied,
+            rate_limited,
         }
     }
 }
@@ -134,32 +521,240 @@ pub struct AppState {
     pub req_id: AtomicU64,
     // Auth
     pub auth_mode: AuthMode,
+    /// HMAC secret for visitor login cookies; also verifies tokens without a `kid`
     pub jwt_secret: Vec<u8>,
     // token -> (ip, expiry, session) - used for revocation/single-use tracking in JWT mode
     pub issued_tokens: Mutex<HashMap<String, (String, Instant, String)>>,
+    // challenge id -> when it may be forgotten; each `/challenge` answer is accepted once
+    pub used_challenges: Mutex<HashMap<String, Instant>>,
     // session -> state
     pub sessions: RwLock<HashMap<String, Arc<SessionState>>>,
-    // rate limit map: ip -> (count, window_start)
-    pub rl: Mutex<HashMap<String, (u32, Instant)>>,
-    // proxy rate limit map: ip -> (count, window_start)
-    pub proxy_rl: Mutex<HashMap<String, (u32, Instant)>>,
-    // config: allow token in query string for WS
-    pub allow_token_query: bool,
-    /// (Optional) Internal key to restrict /token to frontend only
-    pub internal_key: Option<String>,
+    // token buckets for /token, /ws and proxied requests, by IP or session
+    pub rate_limits: ratelimit::RateLimiter,
     pub metrics: Metrics,
+    /// (Optional) Raw TCP tunnels; disabled when no port range is configured
+    pub tcp: Option<tcp::TcpConfig>,
+    // session -> resume secret and grace deadline
+    pub reservations: Mutex<HashMap<String, Reservation>>,
+    /// (Optional) Base domain for Host routing: `<sid>.<domain>` reaches the session at its root
+    pub domain: Option<String>,
+    /// (Optional) Canonical base URL of the server, e.g. `https://tunnel.example.com`; public URLs
+    /// are otherwise built from the Host header of the client's `/ws` request
+    pub public_url: Option<String>,
+    /// Whether the main listener terminates TLS, so public URLs default to `https`
+    pub https: bool,
+    /// Persisted server state (reserved names)
+    pub store: store::Store,
+    // Keys for tunnel client tokens; replaced as a whole on config reload
+    keys: std::sync::RwLock<Arc<keys::KeySet>>,
+    // Limits and policies; replaced as a whole on config reload
+    settings: std::sync::RwLock<Arc<config::Settings>>,
+    // Set once shutdown starts: new visitors and tunnels are turned away
+    draining: AtomicBool,
+}
+
+impl AppState {
+    /// State with default limits and options; callers adjust the public fields as needed.
+    pub fn new(auth_mode: AuthMode, jwt_secret: Vec<u8>) -> Self {
+        Self {
+            _token: match &auth_mode {
+                AuthMode::Fixed(t) => t.clone(),
+                AuthMode::Ephemeral => String::new(),
+            },
+            req_id: AtomicU64::new(1),
+            auth_mode,
+            keys: std::sync::RwLock::new(Arc::new(keys::KeySet::from_secret(&jwt_secret))),
+            jwt_secret,
+            issued_tokens: Mutex::new(HashMap::new()),
+            used_challenges: Mutex::new(HashMap::new()),
+            sessions: RwLock::new(HashMap::new()),
+            rate_limits: ratelimit::RateLimiter::default(),
+            metrics: Metrics::new(),
+            tcp: None,
+            reservations: Mutex::new(HashMap::new()),
+            domain: None,
+            public_url: None,
+            https: false,
+            store: store::Store::default(),
+            settings: std::sync::RwLock::new(Arc::new(config::Settings::default())),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Snapshot of the limits and policies currently in effect.
+    pub fn settings(&self) -> Arc<config::Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Adjust settings before the state is shared.
+    pub fn settings_mut(&mut self) -> &mut config::Settings {
+        Arc::make_mut(self.settings.get_mut().unwrap())
+    }
+
+    /// Swap in reloaded settings; requests already in flight finish with the old ones.
+    pub fn replace_settings(&self, settings: config::Settings) {
+        *self.settings.write().unwrap() = Arc::new(settings);
+    }
+
+    /// Take a token from the `class` bucket for `key`, with the quota currently configured.
+    pub async fn rate_limit(&self, class: ratelimit::RouteClass, key: &str) -> ratelimit::Decision {
+        use ratelimit::RouteClass;
+        let settings = self.settings();
+        let quota = match class {
+            RouteClass::Token => settings.token_quota(),
+            RouteClass::WsConnect => settings.ws_quota(),
+            RouteClass::Proxy => settings.proxy_quota(),
+            RouteClass::Session => settings.session_quota(),
+        };
+        self.rate_limits
+            .acquire(class, key, quota, settings.limits.rate_limit_max_keys)
+            .await
+    }
+
+    /// Keys currently used to sign and verify tunnel client tokens.
+    pub fn keys(&self) -> Arc<keys::KeySet> {
+        self.keys.read().unwrap().clone()
+    }
+
+    /// Swap in a new key set; tokens signed with a dropped key stop working.
+    pub fn replace_keys(&self, keys: keys::KeySet) {
+        *self.keys.write().unwrap() = Arc::new(keys);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Stop taking new visitors and tunnels, tell every client the server is going away and
+    /// wait up to `timeout` for requests already in flight. Returns whether they all finished.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.draining.store(true, Ordering::SeqCst);
+        let sessions: Vec<_> = self.sessions.read().await.values().cloned().collect();
+        let notice = ServerToClient::GoingAway(GoingAway {
+            drain_secs: timeout.as_secs(),
+        });
+        for sess in &sessions {
+            // A client too busy to take the notice still sees the connection close
+            let _ = sess.outbound_tx.try_send(notice.clone());
+        }
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut in_flight = 0;
+            for sess in &sessions {
+                in_flight += sess.pending.lock().await.len();
+                in_flight += sess.response_bodies.lock().await.len();
+            }
+            if in_flight == 0 {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                tracing::warn!("Drain timed out with {} requests in flight", in_flight);
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+/// How a visitor request addressed its session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Routing {
+    /// `/s/<sid>/...` on the server's own host
+    PathPrefix,
+    /// `<sid>.<domain>`, with the app served at its natural root
+    Host,
+}
+
+/// Resume state for a session id: kept while a client is attached and for a grace period after.
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    /// Rotated on every successful connect
+    pub secret: String,
+    /// `None` while a client is attached
+    pub expires: Option<Instant>,
+    /// Public TCP port to hand out again on resume
+    pub tcp_port: Option<u16>,
+    /// Access token the session was opened with; resuming stops working once it is revoked
+    pub credential: Option<tokens::Credential>,
+    /// Public key the client proved it holds; a fresh proof with it reclaims the session
+    pub client_key: Option<String>,
+    /// Set when an operator disconnected the session: nobody gets the sid until this expires
+    pub blocked: bool,
+}
+
+impl Reservation {
+    pub fn is_live(&self, now: Instant) -> bool {
+        self.expires.is_none_or(|exp| exp > now)
+    }
+}
+
+/// Per-connection options decided in `ws_handler` before the upgrade.
+#[derive(Debug, Default)]
+pub struct SessionSetup {
+    pub features: Features,
+    pub tcp: Option<tcp::TcpTunnel>,
+    /// Resume secret handed to this connection
+    pub resume_secret: String,
+    pub visitor_auth: Option<access::VisitorAuth>,
+    pub ip_filter: access::IpFilter,
+    pub client_ip: Option<String>,
+    pub credential: Option<tokens::Credential>,
+    /// Key the client proved it holds, carried over into refresh credentials
+    pub client_key: Option<String>,
+    /// Where visitors reach the session, announced in `welcome`
+    pub public_urls: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerToClient {
     ProxyRequest(ProxyRequest),
+    // Streaming variant: head first, then zero or more body chunks, then end
+    RequestStart(RequestStart),
+    RequestBody(BodyChunk),
+    RequestEnd(StreamEnd),
+    // Visitor WebSocket relay, multiplexed by stream id
+    WsOpen(WsOpen),
+    WsFrame(WsFrame),
+    WsClose(WsClose),
+    // Raw TCP relay; `tcp_close` marks the end of one direction
+    TcpOpen(TcpOpen),
+    TcpData(BodyChunk),
+    TcpClose(StreamEnd),
+    // The server is shutting down; in-flight requests still get answered
+    GoingAway(GoingAway),
+    // Credential for getting a new token after a disconnect; replaces any earlier one
+    RefreshToken(RefreshToken),
+    // Nobody is waiting for this request any more; stop working on it
+    Cancel(Cancel),
+    // Answer to the client's `hello`
+    Welcome(Welcome),
+    // Something the client's operator should know about (rate limits, idle reaping, ...)
+    Notice(Notice),
+    Error(ErrorMessage),
+    // The client took chunks of a stream off its buffer; we may send that many more
+    Credit(Credit),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ClientToServer {
     ProxyResponse(ProxyResponse),
+    ResponseStart(ResponseStart),
+    ResponseBody(BodyChunk),
+    ResponseEnd(StreamEnd),
+    WsOpened(WsOpened),
+    WsFrame(WsFrame),
+    WsClose(WsClose),
+    TcpData(BodyChunk),
+    TcpClose(StreamEnd),
+    // First message from clients speaking protocol version 2 or later
+    Hello(Hello),
+    Notice(Notice),
+    // With an `id`, answers that request: the local target could not be reached
+    Error(ErrorMessage),
+    Credit(Credit),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -183,23 +778,285 @@ pub struct ProxyResponse {
     pub is_compressed: bool,
 }
 
-pub fn compress_body(data: &[u8]) -> (String, bool) {
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RequestStart {
+    pub id: u64,
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    // false when the visitor sent no body; no body chunks or end will follow
+    #[serde(default)]
+    pub has_body: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResponseStart {
+    pub id: u64,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BodyChunk {
+    pub id: u64,
+    #[serde(with = "b64_bytes")]
+    pub data: Vec<u8>,
+    #[serde(default)]
+    pub is_compressed: bool,
+}
+
+impl BodyChunk {
+    pub fn new(id: u64, data: &[u8]) -> Self {
+        let (data, is_compressed) = compress_bytes(data);
+        Self {
+            id,
+            data,
+            is_compressed,
+        }
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        decompress_bytes(self.data, self.is_compressed)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StreamEnd {
+    pub id: u64,
+    // Set when the body was cut short (e.g. upstream connection reset)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WsOpen {
+    pub id: u64,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WsOpened {
+    pub id: u64,
+    // 101 when the local WebSocket handshake succeeded, otherwise the upstream status
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WsFrameKind {
+    Text,
+    Binary,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WsFrame {
+    pub id: u64,
+    pub kind: WsFrameKind,
+    #[serde(with = "b64_bytes")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WsClose {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<u16>,
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TcpOpen {
+    pub id: u64,
+    // Public peer address, for client-side logging
+    pub peer: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GoingAway {
+    // Longest the server waits for in-flight requests before closing the tunnel
+    pub drain_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RefreshToken {
+    pub token: String,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Cancel {
+    pub id: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Credit {
+    // Stream (request, WebSocket or TCP connection) the credit is for
+    pub id: u64,
+    pub chunks: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+/// What a `notice` or `error` is about; codes from newer peers read as `other`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageCode {
+    /// Visitors of the session are being refused with `429`
+    RateLimited,
+    /// A buffered request body was over `max_body_bytes` and refused with `413`
+    BodyTooLarge,
+    /// The session has been idle and is about to be (or was) removed
+    IdleTimeout,
+    /// Connecting to the local target failed
+    UpstreamUnreachable,
+    /// The local target did not answer in time
+    UpstreamTimeout,
+    /// Any other failure talking to the local target
+    UpstreamFailed,
+    /// tunly-client has too many requests waiting for the local target
+    Overloaded,
+    #[serde(other)]
+    Other,
+}
+
+impl MessageCode {
+    /// The code as it appears on the wire.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageCode::RateLimited => "rate_limited",
+            MessageCode::BodyTooLarge => "body_too_large",
+            MessageCode::IdleTimeout => "idle_timeout",
+            MessageCode::UpstreamUnreachable => "upstream_unreachable",
+            MessageCode::UpstreamTimeout => "upstream_timeout",
+            MessageCode::UpstreamFailed => "upstream_failed",
+            MessageCode::Overloaded => "overloaded",
+            MessageCode::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Notice {
+    pub severity: Severity,
+    pub code: MessageCode,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ErrorMessage {
+    // Request this error answers, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    pub code: MessageCode,
+    pub message: String,
+}
+
+impl ErrorMessage {
+    /// Status for the visitor when this error answers their request.
+    pub fn status(&self) -> StatusCode {
+        match self.code {
+            MessageCode::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+            MessageCode::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Hello {
+    pub protocol_version: u32,
+    // tunly-client package version, for logs
+    pub client_version: String,
+    // Everything the client supports; the active set is what `welcome` lists
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub compression: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Welcome {
+    // Version both sides speak on this connection
+    pub protocol_version: u32,
+    pub server_version: String,
+    pub session: String,
+    pub features: Vec<String>,
+    pub compression: Vec<String>,
+    // `http(s)://` URLs of an HTTP session, or `tcp://host:port` of a TCP tunnel
+    pub public_urls: Vec<String>,
+    pub limits: SessionLimits,
+}
+
+/// Limits that apply to a session, so the client can explain refusals.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SessionLimits {
+    // Request bodies of clients without streaming
+    pub max_body_bytes: usize,
+    pub upstream_timeout_secs: u64,
+    pub idle_ttl_secs: u64,
+    pub resume_grace_secs: u64,
+    // Proxied requests per window, all visitors together
+    pub rate_max: u32,
+    pub rate_window_secs: u64,
+}
+
+impl SessionLimits {
+    pub fn from_limits(limits: &config::Limits) -> Self {
+        Self {
+            max_body_bytes: limits.max_body_bytes,
+            upstream_timeout_secs: limits.upstream_timeout_secs,
+            idle_ttl_secs: limits.session_idle_ttl_secs,
+            resume_grace_secs: limits.resume_grace_secs,
+            rate_max: limits.session_rate_max,
+            rate_window_secs: limits.session_rate_window_secs,
+        }
+    }
+}
+
+// Raw bytes carried as standard base64 in JSON messages
+mod b64_bytes {
+    use base64::{engine::general_purpose, Engine as _};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&general_purpose::STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(d)?;
+        general_purpose::STANDARD
+            .decode(s)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Zlib-compress payloads of 1 KB or more when it actually saves space.
+pub fn compress_bytes(data: &[u8]) -> (Vec<u8>, bool) {
     if data.len() < 1024 {
-        return (general_purpose::STANDARD.encode(data), false);
+        return (data.to_vec(), false);
     }
     let mut encoder = ZlibEncoder::new(data, Compression::default());
     let mut compressed = Vec::new();
     if encoder.read_to_end(&mut compressed).is_ok() && compressed.len() < data.len() {
-        (general_purpose::STANDARD.encode(compressed), true)
+        (compressed, true)
     } else {
-        (general_purpose::STANDARD.encode(data), false)
+        (data.to_vec(), false)
     }
 }
 
-pub fn decompress_body(b64_data: &str, is_compressed: bool) -> Vec<u8> {
-    let raw = general_purpose::STANDARD
-        .decode(b64_data)
-        .unwrap_or_default();
+pub fn decompress_bytes(raw: Vec<u8>, is_compressed: bool) -> Vec<u8> {
     if !is_compressed {
         return raw;
     }
@@ -212,8 +1069,20 @@ pub fn decompress_body(b64_data: &str, is_compressed: bool) -> Vec<u8> {
     }
 }
 
-pub async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let encoder = TextEncoder::new();
+pub fn compress_body(data: &[u8]) -> (String, bool) {
+    let (payload, is_compressed) = compress_bytes(data);
+    (general_purpose::STANDARD.encode(payload), is_compressed)
+}
+
+pub fn decompress_body(b64_data: &str, is_compressed: bool) -> Vec<u8> {
+    let raw = general_purpose::STANDARD
+        .decode(b64_data)
+        .unwrap_or_default();
+    decompress_bytes(raw, is_compressed)
+}
+
+pub async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let encoder = TextEncoder::new();
     let metric_families = state.metrics.registry.gather();
     let mut buffer = vec![];
     encoder.encode(&metric_families, &mut buffer).unwrap();
@@ -225,11 +1094,15 @@ pub async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoRes
 }
 
 pub fn create_app(state: Arc<AppState>) -> Router {
+    let host_routing = axum::middleware::from_fn_with_state(state.clone(), host_router);
     Router::new()
         .route("/metrics", get(metrics_handler))
         .route("/ws", get(ws_handler))
         .route("/token", get(token_endpoint))
+        .route("/token/refresh", post(refresh_endpoint))
+        .route("/challenge", get(pop::challenge_endpoint))
         .route("/healthz", get(health))
+        .nest("/admin/api", admin::router(state.clone()))
         .route("/_next/{*path}", any(next_asset_redirect))
         .route("/s/{sid}/_log", get(session_log))
         .route("/s/{sid}/", any(proxy_handler_root))
@@ -237,17 +1110,63 @@ pub fn create_app(state: Arc<AppState>) -> Router {
         .route("/s/{sid}/{*path}", any(proxy_handler_path))
         .fallback(fallback_404)
         .layer(NormalizePathLayer::trim_trailing_slash())
+        // Outside path normalization so apps on a session subdomain see their paths untouched
+        .layer(host_routing)
         .layer(TraceLayer::new_for_http())
         .with_state(state)
 }
 
-pub fn extract_real_ip(addr: &SocketAddr, headers: &HeaderMap) -> String {
-    headers
-        .get("x-forwarded-for")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| s.split(',').next())
-        .map(|s| s.trim().to_string())
-        .unwrap_or_else(|| addr.ip().to_string())
+/// Route every request on `<sid>.<domain>` to its session, ahead of the server's own routes.
+async fn host_router(
+    State(state): State<Arc<AppState>>,
+    req: Request<axum::body::Body>,
+    next: axum::middleware::Next,
+) -> Response {
+    let sid = state.domain.as_deref().and_then(|domain| {
+        req.headers()
+            .get(axum::http::header::HOST)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|host| sid_from_host(host, domain))
+    });
+    let (Some(sid), Some(ConnectInfo(addr))) = (
+        sid,
+        req.extensions().get::<ConnectInfo<SocketAddr>>().copied(),
+    ) else {
+        return next.run(req).await;
+    };
+    let headers = req.headers().clone();
+    let path = req.uri().path().to_string();
+    proxy_logic(State(state), addr, headers, sid, path, Routing::Host, req).await
+}
+
+/// Extract `<sid>` from a `Host` of the form `<sid>.<domain>[:port]`.
+pub fn sid_from_host(host: &str, domain: &str) -> Option<String> {
+    let host = host.rsplit_once(':').map_or(host, |(h, _)| h);
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let label = host.strip_suffix(domain)?.strip_suffix('.')?;
+    is_dns_label(label).then(|| label.to_string())
+}
+
+/// True if `s` can be used as a single DNS label (and so as a session subdomain).
+pub fn is_dns_label(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= 63
+        && !s.starts_with('-')
+        && !s.ends_with('-')
+        && s.bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+/// Random session id; lowercase hex so it also works as a subdomain label.
+pub fn generate_session_id() -> String {
+    let mut bytes = [0u8; 16];
+    rand::rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Visitor IP as a string; forwarding headers count only from `--trusted-proxies`.
+pub fn extract_real_ip(trusted: &[IpNet], addr: &SocketAddr, headers: &HeaderMap) -> String {
+    forwarded::client_ip(trusted, addr.ip(), headers).to_string()
 }
 
 pub async fn ws_handler(
@@ -257,45 +1176,499 @@ pub async fn ws_handler(
     ws: WebSocketUpgrade,
     Query(params): Query<HashMap<String, String>>,
 ) -> Response {
-    let sid = match params.get("sid") {
-        Some(s) if !s.is_empty() => s.clone(),
+    if state.is_draining() {
+        return shutting_down();
+    }
+    // Connect attempts per client IP, before any token or signature work
+    let ip = {
+        let settings = state.settings();
+        extract_real_ip(&settings.trusted_proxies, &addr, &headers)
+    };
+    let rate = state.rate_limit(RouteClass::WsConnect, &ip).await;
+    if !rate.allowed {
+        state.metrics.rate_limited.inc();
+        return rate.too_many_requests("rate limit exceeded for tunnel connects");
+    }
+    // A requested name doubles as the session id
+    let requested_name = params.get("name").filter(|n| !n.is_empty()).cloned();
+    let sid = match (&requested_name, params.get("sid")) {
+        (Some(name), _) => name.clone(),
+        (None, Some(s)) if !s.is_empty() => s.clone(),
         _ => return (StatusCode::BAD_REQUEST, "missing sid").into_response(),
     };
+    if requested_name.is_some() {
+        if let Err(msg) = store::validate_name(&sid) {
+            return (StatusCode::BAD_REQUEST, msg).into_response();
+        }
+    }
+
+    // Key the client proved it holds, if it sent a signed challenge
+    let proven_key = match pop::verify(&state, &headers, &sid).await {
+        Ok(key) => key,
+        Err(resp) => return resp,
+    };
+
+    // A client reattaching to its own session presents the resume secret, or proves it holds
+    // the key that opened it, instead of a token
+    let resume = headers.get(RESUME_HEADER).and_then(|v| v.to_str().ok());
+    let resumed_with = state
+        .reservations
+        .lock()
+        .await
+        .get(&sid)
+        .filter(|r| r.is_live(Instant::now()))
+        .filter(|r| {
+            resume.is_some_and(|secret| secrets_match(&r.secret, secret))
+                || proven_key.is_some() && r.client_key == proven_key
+        })
+        .map(|r| (r.credential.clone(), r.client_key.clone()));
+    let resumed = match &resumed_with {
+        Some((Some(credential), _)) => !state.store.is_revoked(&credential.jti).await,
+        Some((None, _)) => true,
+        None => false,
+    };
+    let mut access = None;
+    if !resumed {
+        match check_token(
+            &state,
+            &addr,
+            &headers,
+            &params,
+            &sid,
+            proven_key.as_deref(),
+        )
+        .await
+        {
+            Ok(claims) => access = claims,
+            Err(resp) => return resp,
+        }
+    }
+    if let Some(claims) = &access {
+        if !claims.allows_sid(&sid) {
+            return (
+                StatusCode::FORBIDDEN,
+                "token does not allow this session id",
+            )
+                .into_response();
+        }
+        if claims.max_sessions > 0 {
+            let open = state
+                .sessions
+                .read()
+                .await
+                .iter()
+                .filter(|(k, s)| {
+                    **k != sid && s.credential.as_ref().is_some_and(|c| c.jti == claims.jti)
+                })
+                .count();
+            if open >= claims.max_sessions as usize {
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "token has reached its session limit",
+                )
+                    .into_response();
+            }
+        }
+    }
+    let (credential, client_key) = match (&access, resumed_with) {
+        (Some(claims), _) => (Some(tokens::Credential::from(claims)), proven_key.clone()),
+        (None, Some((previous, key))) if resumed => (previous, proven_key.clone().or(key)),
+        _ => (None, proven_key.clone()),
+    };
+
+    // Reserved names only attach for the key that claimed them. Without a key of its own the
+    // client's fixed token stands in, but everyone holding it shares that identity
+    let mut name_owner = false;
+    let mut shared_owner = false;
+    if !resumed {
+        let mut owner = headers
+            .get(NAME_KEY_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .or_else(|| access.as_ref().map(|claims| claims.owner_key()));
+        if let (None, AuthMode::Fixed(token)) = (&owner, &state.auth_mode) {
+            owner = Some(token.clone());
+            shared_owner = true;
+        }
+        let owner = owner
+            .or_else(|| proven_key.as_ref().map(|key| format!("key:{}", key)))
+            .map(|key| store::owner_id(&key));
+        match (state.store.name_owner(&sid).await, owner) {
+            (Some(existing), Some(owner)) if secrets_match(&existing, &owner) => name_owner = true,
+            (Some(_), _) => {
+                return (StatusCode::CONFLICT, "name is reserved by another client")
+                    .into_response();
+            }
+            (None, owner) if requested_name.is_some() => {
+                let Some(owner) = owner else {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        "claiming a name requires the x-tunly-name-key header",
+                    )
+                        .into_response();
+                };
+                // An unclaimed sid may still be someone's live session; claiming it must not
+                // become a way around the takeover checks below
+                let in_use = state.sessions.read().await.contains_key(&sid)
+                    || state
+                        .reservations
+                        .lock()
+                        .await
+                        .get(&sid)
+                        .is_some_and(|r| r.is_live(Instant::now()));
+                if in_use {
+                    return (StatusCode::CONFLICT, "session is in use by another client")
+                        .into_response();
+                }
+                match state.store.claim_name(&sid, &owner).await {
+                    Ok(()) => name_owner = true,
+                    Err(store::StoreError::Taken) => {
+                        return (StatusCode::CONFLICT, "name is reserved by another client")
+                            .into_response();
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to reserve name {}: {}", sid, e);
+                        return (StatusCode::INTERNAL_SERVER_ERROR, "failed to reserve name")
+                            .into_response();
+                    }
+                }
+                tracing::info!("Name {} reserved", sid);
+            }
+            (None, _) => {}
+        }
+    }
+
+    let visitor_auth = match headers
+        .get(VISITOR_AUTH_HEADER)
+        .map(|v| v.to_str().map_err(|_| "invalid visitor auth"))
+    {
+        Some(Ok(value)) => match access::VisitorAuth::parse(value) {
+            Ok(auth) => Some(auth),
+            Err(msg) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+        },
+        Some(Err(msg)) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+        None => None,
+    };
+
+    let mut ip_filter = access::IpFilter::default();
+    for (name, list) in [
+        (ALLOW_CIDR_HEADER, &mut ip_filter.allow),
+        (DENY_CIDR_HEADER, &mut ip_filter.deny),
+    ] {
+        let Some(value) = headers.get(name) else {
+            continue;
+        };
+        match value
+            .to_str()
+            .map_err(|_| "invalid CIDR list".to_string())
+            .and_then(access::parse_net_list)
+        {
+            Ok(nets) => *list = nets,
+            Err(msg) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+        }
+    }
+
+    // Optional features the client asked for, limited to what we support
+    let features = headers
+        .get(FEATURES_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(Features::parse)
+        .unwrap_or_default()
+        .intersect(Features::supported());
+
+    // TCP tunnel requested: checked now, its port is only allocated once the sid is ours
+    let mut tcp_config = None;
+    if params.get("tcp").is_some_and(|v| v == "1" || v == "true") {
+        let Some(config) = state.tcp.as_ref() else {
+            return (
+                StatusCode::BAD_REQUEST,
+                "tcp tunnels are disabled on this server",
+            )
+                .into_response();
+        };
+        if !features.tcp {
+            return (
+                StatusCode::BAD_REQUEST,
+                "client does not support tcp tunnels",
+            )
+                .into_response();
+        }
+        tcp_config = Some(config);
+    }
+
+    // A connected session only passes to a client that proves it owns it (resume secret, client
+    // key, or a name key of its own); the old connection is then closed in client_ws
+    let connected = state.sessions.read().await.contains_key(&sid);
+    let owns_name = name_owner && !shared_owner;
+    if connected && !resumed && !owns_name {
+        return (StatusCode::CONFLICT, "session is in use by another client").into_response();
+    }
+
+    // A fresh connect may not take over a reserved sid; the owner of a name may, e.g. after
+    // losing its resume secret. Nobody gets a sid an operator blocked
+    let reservation_conflict = |reservations: &HashMap<String, Reservation>| {
+        let live = reservations
+            .get(&sid)
+            .filter(|r| r.is_live(Instant::now()))?;
+        if live.blocked {
+            Some("session was disconnected by the operator; try again later")
+        } else if !resumed && !owns_name {
+            Some("session is reserved; reconnect with its resume secret")
+        } else {
+            None
+        }
+    };
+    let previous_port = {
+        let reservations = state.reservations.lock().await;
+        if let Some(msg) = reservation_conflict(&reservations) {
+            return (StatusCode::CONFLICT, msg).into_response();
+        }
+        reservations
+            .get(&sid)
+            .filter(|_| resumed)
+            .and_then(|r| r.tcp_port)
+    };
+
+    // Allocate the public port now so failures surface as HTTP errors
+    let mut tcp_tunnel = None;
+    if let Some(config) = tcp_config {
+        match tcp::allocate(config, previous_port).await {
+            Some(t) => tcp_tunnel = Some(t),
+            None => {
+                return (StatusCode::SERVICE_UNAVAILABLE, "no free tcp ports").into_response();
+            }
+        }
+    }
+    let tcp_port = tcp_tunnel.as_ref().map(|t| t.port);
+
+    // Reserve the sid for this connection, unless another connect took it meanwhile; dropping
+    // the tunnel then frees its port again
+    let resume_secret = generate_secret();
+    {
+        let now = Instant::now();
+        let mut reservations = state.reservations.lock().await;
+        if let Some(msg) = reservation_conflict(&reservations) {
+            return (StatusCode::CONFLICT, msg).into_response();
+        }
+        reservations.insert(
+            sid.clone(),
+            Reservation {
+                secret: resume_secret.clone(),
+                // Until the upgrade completes the reservation only lasts the grace period
+                expires: Some(now + state.settings().resume_grace()),
+                tcp_port,
+                credential: credential.clone(),
+                client_key: client_key.clone(),
+                blocked: false,
+            },
+        );
+    }
+    if resumed {
+        tracing::info!("Session {} resumed", sid);
+    }
+
+    // HTTP sessions with a DNS-safe sid are also reachable on their own subdomain
+    let public_host = match &state.domain {
+        Some(domain) if tcp_port.is_none() && is_dns_label(&sid) => {
+            Some(format!("{}.{}", sid, domain))
+        }
+        _ => None,
+    };
+    let public_urls = public_urls(
+        &state,
+        &addr,
+        &headers,
+        &sid,
+        tcp_port,
+        public_host.as_deref(),
+    );
+    let setup = SessionSetup {
+        features,
+        tcp: tcp_tunnel,
+        resume_secret: resume_secret.clone(),
+        visitor_auth,
+        ip_filter,
+        client_ip: Some(ip),
+        credential,
+        client_key,
+        public_urls,
+    };
+    let mut resp = ws.on_upgrade(move |socket| client_ws(socket, state, sid, setup));
+    if let Ok(hv) = axum::http::HeaderValue::from_str(&features.to_header_value()) {
+        resp.headers_mut().insert(FEATURES_HEADER, hv);
+    }
+    if let Some(port) = tcp_port {
+        resp.headers_mut()
+            .insert(TCP_PORT_HEADER, axum::http::HeaderValue::from(port));
+    }
+    if let Ok(hv) = axum::http::HeaderValue::from_str(&resume_secret) {
+        resp.headers_mut().insert(RESUME_HEADER, hv);
+    }
+    if let Some(hv) = public_host.and_then(|h| axum::http::HeaderValue::from_str(&h).ok()) {
+        resp.headers_mut().insert(PUBLIC_HOST_HEADER, hv);
+    }
+    resp.headers_mut().insert(
+        PROTOCOL_HEADER,
+        axum::http::HeaderValue::from(PROTOCOL_VERSION),
+    );
+    resp
+}
+
+/// Where visitors reach `sid`: under `--public-url` (or the host the client connected to) and
+/// on its own host, or the public endpoint of a TCP tunnel.
+fn public_urls(
+    state: &AppState,
+    addr: &SocketAddr,
+    headers: &HeaderMap,
+    sid: &str,
+    tcp_port: Option<u16>,
+    own_host: Option<&str>,
+) -> Vec<String> {
+    let base = match &state.public_url {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => {
+            let Some(host) = headers
+                .get(axum::http::header::HOST)
+                .and_then(|v| v.to_str().ok())
+            else {
+                return Vec::new();
+            };
+            let trusted = &state.settings().trusted_proxies;
+            let scheme = match forwarded::proto(trusted, addr.ip(), headers) {
+                Some(proto) => proto,
+                None if state.https => "https".to_string(),
+                None => "http".to_string(),
+            };
+            format!("{}://{}", scheme, host)
+        }
+    };
+    let (scheme, authority) = base.split_once("://").unwrap_or(("http", &base));
+    if let Some(port) = tcp_port {
+        let authority = authority.split('/').next().unwrap_or(authority);
+        let host = match authority.rsplit_once(':') {
+            Some((host, p)) if p.bytes().all(|b| b.is_ascii_digit()) => host,
+            _ => authority,
+        };
+        return vec![format!("tcp://{}:{}", host, port)];
+    }
+    let mut urls = vec![format!("{}/s/{}/", base, sid)];
+    if let Some(host) = own_host {
+        urls.push(format!("{}://{}/", scheme, host));
+    }
+    urls
+}
 
+/// `welcome` for a client's `hello`, or why the two cannot talk to each other.
+pub fn welcome(
+    state: &AppState,
+    sid: &str,
+    features: Features,
+    public_urls: &[String],
+    hello: &Hello,
+) -> Result<Welcome, String> {
+    if hello.protocol_version < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "client speaks protocol {}, server needs at least {}",
+            hello.protocol_version, MIN_PROTOCOL_VERSION
+        ));
+    }
+    let compression: Vec<String> = COMPRESSION_CODECS
+        .iter()
+        .filter(|c| hello.compression.iter().any(|h| h == *c))
+        .map(|c| c.to_string())
+        .collect();
+    if compression.is_empty() {
+        return Err(format!(
+            "client supports none of the compression codecs {}",
+            COMPRESSION_CODECS.join(", ")
+        ));
+    }
+    Ok(Welcome {
+        protocol_version: hello.protocol_version.min(PROTOCOL_VERSION),
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        session: sid.to_string(),
+        features: features.names().into_iter().map(String::from).collect(),
+        compression,
+        public_urls: public_urls.to_vec(),
+        limits: SessionLimits::from_limits(&state.settings().limits),
+    })
+}
+
+pub(crate) fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Compare secrets without short-circuiting on the first differing byte.
+pub(crate) fn secrets_match(expected: &str, provided: &str) -> bool {
+    expected.len() == provided.len()
+        && expected
+            .bytes()
+            .zip(provided.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Validate the bearer (or, if enabled, query) token presented on /ws for `sid`, given the key
+/// the client proved it holds. Returns the claims when the client used a long-lived access token.
+async fn check_token(
+    state: &AppState,
+    addr: &SocketAddr,
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+    sid: &str,
+    client_key: Option<&str>,
+) -> Result<Option<tokens::AccessClaims>, Response> {
     // Extract token, prefer Authorization header; only allow query token if explicitly enabled
     let auth_header = headers
         .get(axum::http::header::AUTHORIZATION)
         .and_then(|v| v.to_str().ok())
         .unwrap_or("");
     let bearer = auth_header.strip_prefix("Bearer ");
+    let settings = state.settings();
     let token_str = if let Some(tok) = bearer {
         Some(tok.to_string())
-    } else if state.allow_token_query {
+    } else if settings.allow_token_query {
         params.get("token").cloned()
     } else {
         None
     };
     let Some(token) = token_str else {
-        let msg = if state.allow_token_query {
+        let msg = if settings.allow_token_query {
             "missing token"
         } else {
             "missing token (use Authorization: Bearer <token>)"
         };
-        return (StatusCode::UNAUTHORIZED, msg).into_response();
+        return Err((StatusCode::UNAUTHORIZED, msg).into_response());
     };
 
+    // Per-holder access tokens work next to either mode
+    if let Some(claims) = tokens::verify(&token, &state.keys()) {
+        if state.store.is_revoked(&claims.jti).await {
+            tracing::warn!(
+                "Revoked access token {} ({}) refused",
+                claims.jti,
+                claims.sub
+            );
+            return Err((StatusCode::UNAUTHORIZED, "token revoked").into_response());
+        }
+        return Ok(Some(claims));
+    }
+
     let token_ok = match &state.auth_mode {
         AuthMode::Fixed(expected) => token == *expected,
         AuthMode::Ephemeral => {
-            let ip = extract_real_ip(&addr, &headers);
-            match decode::<Claims>(
-                &token,
-                &DecodingKey::from_secret(&state.jwt_secret),
-                &Validation::default(),
-            ) {
-                Ok(data) => {
-                    let claims = data.claims;
-                    if claims.ip != ip || claims.sub != sid {
+            let ip = extract_real_ip(&settings.trusted_proxies, addr, headers);
+            match state.keys().verify::<Claims>(&token) {
+                Ok(claims) => {
+                    let bound = match &claims.cnf {
+                        // Key-bound tokens work from any address, with a proof from that key
+                        Some(key) => client_key == Some(key.as_str()),
+                        // Operator-minted tokens may leave the IP unbound
+                        None => claims.ip.is_empty() || claims.ip == ip,
+                    };
+                    if !bound || claims.sub != *sid {
                         false
                     } else {
                         let mut issued = state.issued_tokens.lock().await;
@@ -317,16 +1690,16 @@ pub async fn ws_handler(
     };
 
     if !token_ok {
-        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+        return Err((StatusCode::UNAUTHORIZED, "invalid token").into_response());
     }
-
-    ws.on_upgrade(move |socket| client_ws(socket, state, sid))
+    Ok(None)
 }
 
 pub async fn token_endpoint(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
 ) -> Response {
     // Only available in Ephemeral mode
     match &state.auth_mode {
@@ -337,7 +1710,9 @@ pub async fn token_endpoint(
     }
 
     // (Optional) Internal Key check to restrict access (e.g., to frontend only)
-    if let Some(ref required_key) = state.internal_key {
+    let settings = state.settings();
+    let limits = &settings.limits;
+    if let Some(ref required_key) = settings.internal_key {
         let provided_key = headers
             .get("x-internal-key")
             .and_then(|v| v.to_str().ok())
@@ -346,98 +1721,148 @@ pub async fn token_endpoint(
         if provided_key != required_key {
             tracing::warn!(
                 "Unauthorized /token access attempt from {}",
-                extract_real_ip(&addr, &headers)
+                extract_real_ip(&settings.trusted_proxies, &addr, &headers)
             );
             return (StatusCode::UNAUTHORIZED, "unauthorized access").into_response();
         }
     }
 
     // Rate limiting per IP
-    let ip = extract_real_ip(&addr, &headers);
-    // Removed sensitive header logging for security
-    let now = Instant::now();
-    {
-        let mut rl = state.rl.lock().await;
-        use std::collections::hash_map::Entry;
-        match rl.entry(ip.clone()) {
-            Entry::Occupied(mut e) => {
-                let (ref mut count, ref mut start) = *e.get_mut();
-                let elapsed = now.duration_since(*start).as_secs();
-                if elapsed >= RL_WINDOW_SECS {
-                    *count = 1;
-                    *start = now;
-                } else if *count >= RL_MAX_PER_WINDOW {
-                    let retry_after = RL_WINDOW_SECS - elapsed;
-                    return axum::http::Response::builder()
-                        .status(StatusCode::TOO_MANY_REQUESTS)
-                        .header(
-                            axum::http::header::CONTENT_TYPE,
-                            "text/plain; charset=utf-8",
-                        )
-                        .header("retry-after", retry_after.to_string())
-                        .header("cache-control", "no-store")
-                        .header("x-robots-tag", "noindex, nofollow")
-                        .header("referrer-policy", "no-referrer")
-                        .body(axum::body::Body::from("rate limit exceeded for /token"))
-                        .unwrap();
-                } else {
-                    *count += 1;
-                }
-            }
-            Entry::Vacant(v) => {
-                v.insert((1, now));
-            }
-        }
+    let ip = extract_real_ip(&settings.trusted_proxies, &addr, &headers);
+    let rate = state.rate_limit(RouteClass::Token, &ip).await;
+    if !rate.allowed {
+        state.metrics.rate_limited.inc();
+        return token_rate_limited(&rate);
     }
 
-    // Generate random token (jti) & session, tie to requesting IP, TTL 5 minutes
-    let mut jti_bytes = [0u8; 16];
-    rand::rng().fill_bytes(&mut jti_bytes);
-    let jti = general_purpose::URL_SAFE_NO_PAD.encode(jti_bytes);
-
-    let mut sid_bytes = [0u8; 16];
-    rand::rng().fill_bytes(&mut sid_bytes);
-    let sid = general_purpose::URL_SAFE_NO_PAD.encode(sid_bytes);
-
-    let exp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-        + 300;
-
-    let claims = Claims {
-        sub: sid.clone(),
-        ip: ip.clone(),
-        exp: exp as usize,
-        jti: jti.clone(),
+    // A requested name becomes the session id; ownership is checked when the client connects
+    let sid = match params.get("name").filter(|n| !n.is_empty()) {
+        Some(name) => {
+            if let Err(msg) = store::validate_name(name) {
+                return (StatusCode::BAD_REQUEST, msg).into_response();
+            }
+            name.clone()
+        }
+        None => generate_session_id(),
     };
 
-    let token = match encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(&state.jwt_secret),
-    ) {
-        Ok(t) => t,
+    // Tie the token to the client's key if it sent one, else to the requesting IP;
+    // TTL from limits (5 minutes by default)
+    let client_key = params.get("pubkey").filter(|k| !k.is_empty());
+    if let Some(key) = client_key {
+        if let Err(msg) = pop::parse_public_key(key) {
+            return (StatusCode::BAD_REQUEST, msg).into_response();
+        }
+    }
+    let bound_ip = if client_key.is_some() {
+        ""
+    } else {
+        ip.as_str()
+    };
+    let token = match issue_token(
+        &state,
+        &sid,
+        bound_ip,
+        client_key.map(String::as_str),
+        limits.token_ttl_secs,
+    )
+    .await
+    {
+        Ok((_jti, token)) => token,
         Err(e) => {
             tracing::error!("Failed to encode JWT: {}", e);
             return (StatusCode::INTERNAL_SERVER_ERROR, "token generation failed").into_response();
         }
     };
 
-    let expiry = Instant::now() + Duration::from_secs(300);
-
-    {
-        let mut issued = state.issued_tokens.lock().await;
-        issued.insert(jti, (ip, expiry, sid.clone()));
-    }
-
-    // Return JSON with security headers
-    let resp = TokenResponse {
+    let mut resp = token_response(TokenResponse {
         token,
         session: sid,
-        expires_in: 300,
+        expires_in: limits.token_ttl_secs,
+    });
+    rate.apply(resp.headers_mut());
+    resp
+}
+
+/// `POST /token/refresh`: trade a refresh credential (as the bearer token) for a new ephemeral
+/// token for the session it was issued to. Each credential is accepted once.
+pub async fn refresh_endpoint(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let AuthMode::Fixed(_) = &state.auth_mode {
+        return (StatusCode::FORBIDDEN, "token issuance disabled").into_response();
+    }
+    let settings = state.settings();
+    let ip = extract_real_ip(&settings.trusted_proxies, &addr, &headers);
+    let rate = state.rate_limit(RouteClass::Token, &ip).await;
+    if !rate.allowed {
+        state.metrics.rate_limited.inc();
+        return token_rate_limited(&rate);
+    }
+    let Some(bearer) = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return (StatusCode::UNAUTHORIZED, "missing refresh credential").into_response();
     };
+    let Some(claims) = state
+        .keys()
+        .verify::<RefreshClaims>(bearer)
+        .ok()
+        .filter(|c| c.kind == REFRESH_KIND)
+    else {
+        return (StatusCode::UNAUTHORIZED, "invalid refresh credential").into_response();
+    };
+    // Exchanged, replaced by a newer one or dropped by an operator: refused
+    if !state.store.take_refresh(&claims.jti, &claims.sub).await {
+        tracing::warn!(
+            "Refresh credential {} for session {} not found or already used",
+            claims.jti,
+            claims.sub
+        );
+        return (StatusCode::UNAUTHORIZED, "invalid refresh credential").into_response();
+    }
 
+    // Bound like a /token answer: to the session's client key if it had one, else to this IP
+    let bound_ip = if claims.cnf.is_some() { "" } else { &ip };
+    let ttl = settings.limits.token_ttl_secs;
+    match issue_token(&state, &claims.sub, bound_ip, claims.cnf.as_deref(), ttl).await {
+        Ok((_jti, token)) => {
+            let mut resp = token_response(TokenResponse {
+                token,
+                session: claims.sub,
+                expires_in: ttl,
+            });
+            rate.apply(resp.headers_mut());
+            resp
+        }
+        Err(e) => {
+            tracing::error!("Failed to encode JWT: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "token generation failed").into_response()
+        }
+    }
+}
+
+// 429 for the token endpoints, with the same security headers as their answers
+fn token_rate_limited(rate: &Decision) -> Response {
+    let mut resp = rate.too_many_requests("rate limit exceeded for /token");
+    let headers = resp.headers_mut();
+    headers.insert(
+        axum::http::header::HeaderName::from_static("x-robots-tag"),
+        axum::http::HeaderValue::from_static("noindex, nofollow"),
+    );
+    headers.insert(
+        axum::http::header::REFERRER_POLICY,
+        axum::http::HeaderValue::from_static("no-referrer"),
+    );
+    resp
+}
+
+// Token JSON with security headers
+fn token_response(resp: TokenResponse) -> Response {
     (
         StatusCode::OK,
         [
@@ -454,7 +1879,98 @@ pub async fn token_endpoint(
         .into_response()
 }
 
-pub async fn client_ws(stream: WebSocket, state: Arc<AppState>, sid: String) {
+/// Sign a single-use ephemeral token for `sid` and record it as outstanding.
+/// An empty `ip` leaves the token usable from any address; a `client_key` makes it usable only
+/// with a proof from that key. Returns `(jti, token)`.
+pub async fn issue_token(
+    state: &AppState,
+    sid: &str,
+    ip: &str,
+    client_key: Option<&str>,
+    ttl_secs: u64,
+) -> Result<(String, String), keys::KeyError> {
+    let mut jti_bytes = [0u8; 16];
+    rand::rng().fill_bytes(&mut jti_bytes);
+    let jti = general_purpose::URL_SAFE_NO_PAD.encode(jti_bytes);
+
+    let exp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + ttl_secs;
+    let claims = Claims {
+        sub: sid.to_string(),
+        ip: ip.to_string(),
+        exp: exp as usize,
+        jti: jti.clone(),
+        cnf: client_key.map(str::to_string),
+    };
+    let token = state.keys().sign(&claims)?;
+
+    let expiry = Instant::now() + Duration::from_secs(ttl_secs);
+    state
+        .issued_tokens
+        .lock()
+        .await
+        .insert(jti.clone(), (ip.to_string(), expiry, sid.to_string()));
+    Ok((jti, token))
+}
+
+/// Sign a refresh credential for `sid`, replacing any earlier one for the same session.
+pub async fn issue_refresh_token(
+    state: &AppState,
+    sid: &str,
+    client_key: Option<&str>,
+    ttl_secs: u64,
+) -> Result<String, keys::KeyError> {
+    let exp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + ttl_secs;
+    let claims = RefreshClaims {
+        kind: REFRESH_KIND.to_string(),
+        sub: sid.to_string(),
+        exp: exp as usize,
+        jti: generate_secret(),
+        cnf: client_key.map(str::to_string),
+    };
+    let token = state.keys().sign(&claims)?;
+    // Kept in the state file so credentials still work after a server restart
+    state.store.add_refresh(&claims.jti, sid, exp).await;
+    Ok(token)
+}
+
+// Keep the client supplied with a refresh credential, renewed at half its lifetime
+async fn send_refresh_tokens(
+    state: Arc<AppState>,
+    sid: String,
+    client_key: Option<String>,
+    out_tx: mpsc::Sender<ServerToClient>,
+) {
+    loop {
+        let ttl = state.settings().limits.refresh_ttl_secs;
+        if ttl == 0 {
+            return;
+        }
+        match issue_refresh_token(&state, &sid, client_key.as_deref(), ttl).await {
+            Ok(token) => {
+                let msg = ServerToClient::RefreshToken(RefreshToken {
+                    token,
+                    expires_in: ttl,
+                });
+                if out_tx.send(msg).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => tracing::error!("Failed to sign refresh credential for {}: {}", sid, e),
+        }
+        tokio::time::sleep(Duration::from_secs((ttl / 2).max(1))).await;
+    }
+}
+
+pub async fn client_ws(stream: WebSocket, state: Arc<AppState>, sid: String, setup: SessionSetup) {
+    let features = setup.features;
     state.metrics.active_sessions.inc();
     tracing::info!("Client connected via WebSocket for session {}", sid);
 
@@ -464,24 +1980,79 @@ pub async fn client_ws(stream: WebSocket, state: Arc<AppState>, sid: String) {
     let (out_tx, mut out_rx) = mpsc::channel::<ServerToClient>(64);
 
     // Create session state and store
-    let session_state = Arc::new(SessionState {
-        outbound_tx: out_tx.clone(),
-        pending: Mutex::new(HashMap::new()),
-        _created_at: Instant::now(),
-        last_seen: Mutex::new(Instant::now()),
-        access_log: Mutex::new(Vec::new()),
-    });
-    {
+    let mut session = SessionState::new(out_tx.clone(), features);
+    session.tcp_port = setup.tcp.as_ref().map(|t| t.port);
+    session.visitor_auth = setup.visitor_auth.clone();
+    session.ip_filter = setup.ip_filter.clone();
+    session.client_ip = setup.client_ip.clone();
+    session.credential = setup.credential.clone();
+    let session_state = Arc::new(session);
+    // ws_handler only lets a connection replace a live one when it proved it owns the session
+    let replaced = {
         let mut sessions = state.sessions.write().await;
-        sessions.insert(sid.clone(), session_state.clone());
+        sessions.insert(sid.clone(), session_state.clone())
+    };
+    if let Some(old) = replaced {
+        tracing::info!("Session {} taken over by a new connection", sid);
+        old.close(CloseReason::TakenOver);
     }
+    set_reservation_expiry(&state, &sid, &setup.resume_secret, None).await;
+
+    // Public TCP listener lives as long as the WebSocket
+    let tcp_task = setup.tcp.map(|tunnel| {
+        tokio::spawn(tcp::serve(
+            tunnel,
+            state.clone(),
+            session_state.clone(),
+            sid.clone(),
+        ))
+    });
+
+    // Ephemeral-token clients get a credential for reconnecting without a new /token round trip;
+    // access token holders simply reuse their token
+    let refresh_task = (features.refresh
+        && setup.credential.is_none()
+        && matches!(state.auth_mode, AuthMode::Ephemeral))
+    .then(|| {
+        tokio::spawn(send_refresh_tokens(
+            state.clone(),
+            sid.clone(),
+            setup.client_key.clone(),
+            out_tx.clone(),
+        ))
+    });
 
     // Task: forward outbound messages to websocket
     let write_session = session_state.clone();
-    let write_task = tokio::spawn(async move {
-        while let Some(msg) = out_rx.recv().await {
-            let text = serde_json::to_string(&msg).unwrap();
-            if ws_tx.send(Message::Text(text.into())).await.is_err() {
+    let write_sid = sid.clone();
+    let mut write_task = tokio::spawn(async move {
+        loop {
+            let msg = tokio::select! {
+                msg = out_rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = write_session.terminate.notified() => {
+                    let reason = write_session
+                        .close_reason
+                        .lock()
+                        .unwrap()
+                        .unwrap_or(CloseReason::Terminated);
+                    tracing::info!("Closing session {}: {}", write_sid, reason.message());
+                    let frame = CloseFrame {
+                        code: reason.code(),
+                        reason: reason.message().into(),
+                    };
+                    let _ = ws_tx.send(Message::Close(Some(frame))).await;
+                    break;
+                }
+            };
+            let ws_msg = if features.binary {
+                Message::Binary(frame::encode_binary(msg).unwrap().into())
+            } else {
+                Message::Text(serde_json::to_string(&msg).unwrap().into())
+            };
+            if ws_tx.send(ws_msg).await.is_err() {
                 break;
             }
             // update last_seen on outbound activity
@@ -495,55 +2066,250 @@ pub async fn client_ws(stream: WebSocket, state: Arc<AppState>, sid: String) {
     // Task: read inbound messages from websocket (responses from client)
     let read_state = state.clone();
     let read_sid = sid.clone();
-    let read_task = tokio::spawn(async move {
+    let read_session = session_state.clone();
+    let read_out_tx = out_tx.clone();
+    let public_urls = setup.public_urls.clone();
+    let mut read_task = tokio::spawn(async move {
         while let Some(Ok(msg)) = ws_rx.next().await {
-            // update last_seen on any inbound WS message
-            if let Some(sess) = { read_state.sessions.read().await.get(&read_sid).cloned() } {
-                let mut ls = sess.last_seen.lock().await;
-                *ls = Instant::now();
-            }
-            if let Message::Text(txt) = msg {
-                match serde_json::from_str::<ClientToServer>(&txt) {
-                    Ok(ClientToServer::ProxyResponse(resp)) => {
-                        let maybe_session =
-                            { read_state.sessions.read().await.get(&read_sid).cloned() };
-                        if let Some(sess) = maybe_session {
-                            let mut pending = sess.pending.lock().await;
-                            if let Some(tx) = pending.remove(&resp.id) {
-                                let _ = tx.send(ClientToServer::ProxyResponse(resp));
-                            }
+            // update last_seen on any inbound WS message; this connection's own session, even
+            // if a takeover already put another one under the sid
+            *read_session.last_seen.lock().await = Instant::now();
+            let parsed = match msg {
+                Message::Text(txt) => {
+                    serde_json::from_str::<ClientToServer>(&txt).map_err(frame::FrameError::from)
+                }
+                Message::Binary(bin) => frame::decode_binary::<ClientToServer>(&bin),
+                _ => continue,
+            };
+            match parsed {
+                Ok(ClientToServer::Hello(hello)) => {
+                    match welcome(&read_state, &read_sid, features, &public_urls, &hello) {
+                        Ok(welcome) => {
+                            tracing::info!(
+                                "Session {}: tunly-client {} speaking protocol {}",
+                                read_sid,
+                                hello.client_version,
+                                welcome.protocol_version
+                            );
+                            let _ = read_out_tx.send(ServerToClient::Welcome(welcome)).await;
+                        }
+                        Err(e) => {
+                            tracing::warn!("Session {}: {}", read_sid, e);
+                            read_session.close(CloseReason::Incompatible);
                         }
                     }
-                    Err(e) => {
-                        tracing::error!("Failed to parse client message: {}", e);
+                }
+                Ok(msg) => dispatch_client_message(&read_session, msg).await,
+                Err(e) => {
+                    tracing::error!("Failed to parse client message: {}", e);
+                }
+            }
+        }
+    });
+
+    // Wait for either side to finish (disconnect); the session keeps a sender alive, so the
+    // writer would otherwise never notice the reader is gone
+    tokio::select! {
+        _ = &mut write_task => read_task.abort(),
+        _ = &mut read_task => write_task.abort(),
+    }
+    for task in [tcp_task, refresh_task].into_iter().flatten() {
+        task.abort();
+    }
+
+    // Remove session on disconnect, unless a resumed connection already replaced it
+    {
+        let mut sessions = state.sessions.write().await;
+        if sessions
+            .get(&sid)
+            .is_some_and(|s| Arc::ptr_eq(s, &session_state))
+        {
+            sessions.remove(&sid);
+        }
+    }
+    // Keep the sid reserved so the client can resume it
+    let grace_end = Instant::now() + state.settings().resume_grace();
+    set_reservation_expiry(&state, &sid, &setup.resume_secret, Some(grace_end)).await;
+    // Abort any visitor response bodies that were still streaming
+    for (_, tx) in session_state.response_bodies.lock().await.drain() {
+        let _ = tx.try_send(Err(std::io::Error::other("tunnel closed")));
+    }
+    // Dropping the senders closes relayed visitor WebSockets and TCP connections
+    session_state.streams.lock().await.clear();
+    session_state.windows.close_all();
+    // Requests still waiting for an answer fail now instead of at the upstream timeout
+    session_state.pending.lock().await.clear();
+    state.metrics.active_sessions.dec();
+
+    tracing::info!("Client disconnected for session {}", sid);
+}
+
+/// Update the reservation for `sid` if it still belongs to the connection holding `secret`.
+async fn set_reservation_expiry(
+    state: &AppState,
+    sid: &str,
+    secret: &str,
+    expires: Option<Instant>,
+) {
+    if let Some(r) = state.reservations.lock().await.get_mut(sid) {
+        if r.secret == secret {
+            r.expires = expires;
+        }
+    }
+}
+
+async fn dispatch_client_message(sess: &SessionState, msg: ClientToServer) {
+    match msg {
+        ClientToServer::ProxyResponse(ProxyResponse { id, .. })
+        | ClientToServer::ResponseStart(ResponseStart { id, .. })
+        | ClientToServer::WsOpened(WsOpened { id, .. })
+        | ClientToServer::Error(ErrorMessage { id: Some(id), .. }) => {
+            if matches!(
+                msg,
+                ClientToServer::ProxyResponse(_) | ClientToServer::Error(_)
+            ) {
+                // Answered: a request body still waiting for credit is not wanted any more
+                sess.windows.close(id);
+            }
+            let mut pending = sess.pending.lock().await;
+            if let Some(tx) = pending.remove(&id) {
+                let _ = tx.send(msg);
+            }
+        }
+        ClientToServer::ResponseBody(chunk) => {
+            // clone the sender so a slow visitor doesn't hold the map lock
+            let maybe_tx = { sess.response_bodies.lock().await.get(&chunk.id).cloned() };
+            if let Some(tx) = maybe_tx {
+                let id = chunk.id;
+                let data = axum::body::Bytes::from(chunk.into_data());
+                // Never wait for the visitor here: that would hold up every stream on the tunnel
+                if flow::offer(&tx, Ok(data)).is_err() {
+                    if !tx.is_closed() {
+                        tracing::warn!("Response {} overran its window; dropping it", id);
+                        let overrun = std::io::Error::other("response body overran its window");
+                        let _ = tx.try_send(Err(overrun));
                     }
+                    // visitor went away, or will see the body cut short
+                    sess.cancel_request(id).await;
+                }
+            }
+        }
+        ClientToServer::ResponseEnd(end) => {
+            sess.windows.close(end.id);
+            let maybe_tx = { sess.response_bodies.lock().await.remove(&end.id) };
+            if let (Some(tx), Some(err)) = (maybe_tx, end.error) {
+                let _ = tx.try_send(Err(std::io::Error::other(err)));
+            }
+        }
+        ClientToServer::WsFrame(WsFrame { id, .. })
+        | ClientToServer::WsClose(WsClose { id, .. })
+        | ClientToServer::TcpData(BodyChunk { id, .. })
+        | ClientToServer::TcpClose(StreamEnd { id, .. }) => {
+            let is_close = matches!(
+                msg,
+                ClientToServer::WsClose(_) | ClientToServer::TcpClose(_)
+            );
+            let maybe_tx = {
+                let mut streams = sess.streams.lock().await;
+                if is_close {
+                    streams.remove(&id)
+                } else {
+                    streams.get(&id).cloned()
                 }
+            };
+            let Some(tx) = maybe_tx else {
+                return;
+            };
+            if is_close {
+                // The end always fits: a slot is kept free for it
+                let _ = tx.try_send(msg);
+                return;
             }
+            let Err(msg) = flow::offer(&tx, msg) else {
+                return;
+            };
+            sess.streams.lock().await.remove(&id);
+            if tx.is_closed() {
+                return;
+            }
+            tracing::warn!("Stream {} overran its window; closing it", id);
+            let reason = "stream overran its window".to_string();
+            let end = match msg {
+                ClientToServer::TcpData(_) => ClientToServer::TcpClose(StreamEnd {
+                    id,
+                    error: Some(reason),
+                }),
+                _ => ClientToServer::WsClose(WsClose {
+                    id,
+                    code: Some(1011),
+                    reason,
+                }),
+            };
+            let _ = tx.try_send(end);
+            sess.cancel_request(id).await;
         }
-    });
-
-    // Wait for either side to finish (disconnect)
-    let _ = tokio::join!(write_task, read_task);
-
-    // Remove session on disconnect
-    {
-        let mut sessions = state.sessions.write().await;
-        sessions.remove(&sid);
+        ClientToServer::Credit(credit) => sess.windows.grant(&credit),
+        ClientToServer::Error(error) => {
+            tracing::warn!("Client error [{}]: {}", error.code.as_str(), error.message);
+        }
+        ClientToServer::Notice(notice) => match notice.severity {
+            Severity::Info => tracing::info!(
+                "Client notice [{}]: {}",
+                notice.code.as_str(),
+                notice.message
+            ),
+            Severity::Warning => {
+                tracing::warn!(
+                    "Client notice [{}]: {}",
+                    notice.code.as_str(),
+                    notice.message
+                )
+            }
+            Severity::Error => {
+                tracing::error!(
+                    "Client notice [{}]: {}",
+                    notice.code.as_str(),
+                    notice.message
+                )
+            }
+        },
+        // Answered by the connection's read loop
+        ClientToServer::Hello(_) => {}
     }
-    state.metrics.active_sessions.dec();
-
-    tracing::info!("Client disconnected for session {}", sid);
 }
 
-pub async fn health() -> &'static str {
-    "ok"
+/// Fails while draining so load balancers move traffic to other instances.
+pub async fn health(State(state): State<Arc<AppState>>) -> Response {
+    if state.is_draining() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "draining").into_response();
+    }
+    "ok".into_response()
 }
 
-pub async fn session_log(Path(sid): Path<String>, State(state): State<Arc<AppState>>) -> Response {
+pub async fn session_log(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(sid): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
     let maybe = { state.sessions.read().await.get(&sid).cloned() };
     let Some(sess) = maybe else {
         return (StatusCode::NOT_FOUND, "session not found").into_response();
     };
+    let settings = state.settings();
+    let ip = extract_real_ip(&settings.trusted_proxies, &addr, &headers);
+    let visitor_ip = ip.parse::<IpAddr>().ok();
+    if !settings.ip_filter.permits(visitor_ip) || !sess.ip_filter.permits(visitor_ip) {
+        state.metrics.ip_denied.inc();
+        return access::forbidden(&ip);
+    }
+    if let Some(auth) = &sess.visitor_auth {
+        if access::check(auth, &state.jwt_secret, &sid, &headers) == access::Access::Denied {
+            let prefix = format!("/s/{}", sid);
+            let login = format!("{}/{}", prefix, access::LOGIN_PATH);
+            return access::challenge(auth, &login, &format!("{}/_log", prefix));
+        }
+    }
 
     let log = sess.access_log.lock().await.clone();
     let mut html = String::from("<!doctype html><meta charset=\"utf-8\"><title>Tunly Session Log</title><style>body{font-family:system-ui,-apple-system,Segoe UI,Roboto,Ubuntu,\"Helvetica Neue\",Arial,sans-serif;padding:20px}table{border-collapse:collapse;width:100%}th,td{border:1px solid #ddd;padding:8px}th{background:#f7f7f7;text-align:left}code{background:#f3f3f3;padding:2px 4px;border-radius:3px}</style>");
@@ -576,6 +2342,7 @@ pub fn escape_html(s: &str) -> String {
     s.replace('&', "&amp;")
         .replace('<', "&lt;")
         .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 pub async fn proxy_handler_root(
@@ -585,7 +2352,16 @@ pub async fn proxy_handler_root(
     State(state): State<Arc<AppState>>,
     req: Request<axum::body::Body>,
 ) -> Response {
-    proxy_logic(State(state), addr, headers, sid, "".to_string(), req).await
+    proxy_logic(
+        State(state),
+        addr,
+        headers,
+        sid,
+        "".to_string(),
+        Routing::PathPrefix,
+        req,
+    )
+    .await
 }
 
 pub async fn proxy_handler_path(
@@ -595,7 +2371,80 @@ pub async fn proxy_handler_path(
     State(state): State<Arc<AppState>>,
     req: Request<axum::body::Body>,
 ) -> Response {
-    proxy_logic(State(state), addr, headers, sid, path, req).await
+    proxy_logic(
+        State(state),
+        addr,
+        headers,
+        sid,
+        path,
+        Routing::PathPrefix,
+        req,
+    )
+    .await
+}
+
+/// Visitor answer for a request the client could not complete; `x-tunly-error` tells it apart
+/// from an error page of the local app.
+fn error_response(error: &ErrorMessage) -> Response {
+    (
+        error.status(),
+        [
+            (
+                axum::http::header::CONTENT_TYPE,
+                "text/plain; charset=utf-8",
+            ),
+            (
+                axum::http::header::HeaderName::from_static(ERROR_HEADER),
+                error.code.as_str(),
+            ),
+        ],
+        format!("upstream error: {}", error.message),
+    )
+        .into_response()
+}
+
+/// New visitors and tunnels during a drain; another instance should be up shortly.
+fn shutting_down() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(axum::http::header::RETRY_AFTER, "5")],
+        "server is shutting down",
+    )
+        .into_response()
+}
+
+/// Cancels a proxied request on the client unless disarmed once its answer arrived; dropping
+/// the handler future (visitor gone) or returning early (timeout) leaves it armed.
+struct CancelOnDrop {
+    sess: Arc<SessionState>,
+    id: u64,
+    armed: bool,
+}
+
+impl CancelOnDrop {
+    fn new(sess: Arc<SessionState>, id: u64) -> Self {
+        Self {
+            sess,
+            id,
+            armed: true,
+        }
+    }
+
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let (sess, id) = (self.sess.clone(), self.id);
+        if let Ok(rt) = tokio::runtime::Handle::try_current() {
+            rt.spawn(async move { sess.cancel_request(id).await });
+        }
+    }
 }
 
 pub async fn proxy_logic(
@@ -604,45 +2453,67 @@ pub async fn proxy_logic(
     headers: HeaderMap,
     sid: String,
     path: String,
+    routing: Routing,
     req: Request<axum::body::Body>,
 ) -> Response {
     state.metrics.proxy_requests.inc();
     let _timer = state.metrics.proxy_latency_seconds.start_timer();
     tracing::info!("-> PROXY_HANDLER: sid='{}', path='{}'", sid, path);
-    let start = Instant::now();
 
-    // Rate limiting per IP
-    let ip = extract_real_ip(&addr, &headers);
-    let now = Instant::now();
-    {
-        let mut rl = state.proxy_rl.lock().await;
-        use std::collections::hash_map::Entry;
-        match rl.entry(ip.clone()) {
-            Entry::Occupied(mut e) => {
-                let (ref mut count, ref mut start_time) = *e.get_mut();
-                let elapsed = now.duration_since(*start_time).as_secs();
-                if elapsed >= PROXY_RL_WINDOW_SECS {
-                    *count = 1;
-                    *start_time = now;
-                } else if *count >= PROXY_RL_MAX_PER_WINDOW {
-                    let retry_after = PROXY_RL_WINDOW_SECS - elapsed;
-                    return axum::http::Response::builder()
-                        .status(StatusCode::TOO_MANY_REQUESTS)
-                        .header("retry-after", retry_after.to_string())
-                        .body(axum::body::Body::from(
-                            "rate limit exceeded for proxy requests",
-                        ))
-                        .unwrap()
-                        .into_response();
-                } else {
-                    *count += 1;
-                }
-            }
-            Entry::Vacant(v) => {
-                v.insert((1, now));
-            }
+    if state.is_draining() {
+        return shutting_down();
+    }
+
+    // Server-wide IP lists come first
+    let settings = state.settings();
+    let ip = extract_real_ip(&settings.trusted_proxies, &addr, &headers);
+    let visitor_ip = ip.parse::<IpAddr>().ok();
+    if !settings.ip_filter.permits(visitor_ip) {
+        state.metrics.ip_denied.inc();
+        return access::forbidden(&ip);
+    }
+
+    // Rate limiting per visitor IP, then per session. Requests the IP bucket refuses are not
+    // charged to the session, so one flooding visitor cannot lock out the others
+    let mut rate = state.rate_limit(RouteClass::Proxy, &ip).await;
+    if rate.allowed {
+        rate = rate.tighter(state.rate_limit(RouteClass::Session, &sid).await);
+    }
+    if !rate.allowed {
+        state.metrics.rate_limited.inc();
+        if let Some(sess) = state.sessions.read().await.get(&sid) {
+            sess.notify(
+                Severity::Warning,
+                MessageCode::RateLimited,
+                format!(
+                    "visitors are being rate limited ({} requests per window); retry in {}s",
+                    rate.limit, rate.retry_after
+                ),
+            );
         }
+        return rate.too_many_requests("rate limit exceeded for proxy requests");
     }
+    let mut response = proxy_to_session(state, headers, sid, path, routing, &ip, req).await;
+    rate.apply(response.headers_mut());
+    response
+}
+
+/// Everything after the server-wide checks of [`proxy_logic`]: session lookup, visitor access
+/// and the round trip through the tunnel.
+async fn proxy_to_session(
+    state: Arc<AppState>,
+    headers: HeaderMap,
+    sid: String,
+    path: String,
+    routing: Routing,
+    ip: &str,
+    mut req: Request<axum::body::Body>,
+) -> Response {
+    let start = Instant::now();
+    let settings = state.settings();
+    let limits = &settings.limits;
+    let visitor_ip = ip.parse::<IpAddr>().ok();
+    let now = Instant::now();
 
     // Prepare request snapshot pieces up front
     let method = req.method().to_string();
@@ -662,6 +2533,18 @@ pub async fn proxy_logic(
     // Lookup session
     let maybe_sess = { state.sessions.read().await.get(&sid).cloned() };
     let Some(sess) = maybe_sess else {
+        let reconnecting = {
+            let reservations = state.reservations.lock().await;
+            reservations.get(&sid).is_some_and(|r| r.is_live(now))
+        };
+        if reconnecting {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(axum::http::header::RETRY_AFTER, "5")],
+                "tunnel client is reconnecting",
+            )
+                .into_response();
+        }
         return (
             StatusCode::SERVICE_UNAVAILABLE,
             "no tunnel client for session",
@@ -669,37 +2552,113 @@ pub async fn proxy_logic(
             .into_response();
     };
 
+    if sess.tcp_port.is_some() {
+        return (
+            StatusCode::BAD_REQUEST,
+            "session is a TCP tunnel, not an HTTP tunnel",
+        )
+            .into_response();
+    }
+
+    if !sess.ip_filter.permits(visitor_ip) {
+        state.metrics.ip_denied.inc();
+        return access::forbidden(ip);
+    }
+
+    // Public path prefix of the app; empty when it is served at the root of its own host
+    let prefix = match routing {
+        Routing::PathPrefix => format!("/s/{}", sid),
+        Routing::Host => String::new(),
+    };
+    let cookie_path = if prefix.is_empty() { "/" } else { &prefix };
+
+    // Visitor access control: login cookie, basic auth, or the passphrase form
+    let mut login_cookie = None;
+    if let Some(auth) = &sess.visitor_auth {
+        let login_action = format!("{}/{}", prefix, access::LOGIN_PATH);
+        if uri.path() == login_action && method == "POST" {
+            return passphrase_login(&state, auth, &sid, &prefix, req).await;
+        }
+        match access::check(auth, &state.jwt_secret, &sid, &headers) {
+            access::Access::Cookie => {}
+            access::Access::Credentials => {
+                login_cookie = access::login_cookie(auth, &state.jwt_secret, &sid, cookie_path);
+            }
+            access::Access::Denied => {
+                return access::challenge(auth, &login_action, &format!("{}{}", prefix, uri_str));
+            }
+        }
+        // The tunnel's password and login session are none of the local app's business
+        access::strip_credentials(auth, req.headers_mut());
+    }
+
     // mark activity
     {
         let mut ls = sess.last_seen.lock().await;
         *ls = Instant::now();
     }
+    sess.requests.fetch_add(1, Ordering::Relaxed);
+
+    // Visitor WebSocket upgrade: relay frames instead of a request/response pair
+    if is_websocket_upgrade(&headers) {
+        return proxy_websocket(&state, sess, &sid, uri_str, start, req).await;
+    }
 
     // Build request snapshot
     let id = state.req_id.fetch_add(1, Ordering::SeqCst);
 
-    let headers_vec = headers_to_vec(req.headers());
-
-    // Limit proxy request body to 2 MB to prevent memory exhaustion
-    let body_bytes = match axum::body::to_bytes(req.into_body(), 2 * 1024 * 1024).await {
-        Ok(bytes) => bytes,
-        Err(_) => {
-            return (
-                StatusCode::PAYLOAD_TOO_LARGE,
-                "Request body too large (2MB limit)",
-            )
-                .into_response();
-        }
-    };
-    let (body_b64, is_compressed) = compress_body(&body_bytes);
+    let (parts, body) = req.into_parts();
+    let headers_vec = headers_to_vec(&parts.headers);
+
+    let mut response_body_rx = None;
+    let (first_msg, streamed_body) = if sess.features.streaming {
+        // Register the response body channel before the request can be answered
+        let (body_tx, body_rx) = mpsc::channel(flow::RECV_BUFFER);
+        sess.response_bodies.lock().await.insert(id, body_tx);
+        response_body_rx = Some(body_rx);
+        let has_body = !body.is_end_stream();
+        let start_msg = ServerToClient::RequestStart(RequestStart {
+            id,
+            method: method.clone(),
+            uri: uri_str.clone(),
+            headers: headers_vec,
+            has_body,
+        });
+        (start_msg, has_body.then_some(body))
+    } else {
+        // Limit buffered request bodies (2 MB by default) to prevent memory exhaustion
+        let body_bytes = match axum::body::to_bytes(body, limits.max_body_bytes).await {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                sess.notify(
+                    Severity::Warning,
+                    MessageCode::BodyTooLarge,
+                    format!(
+                        "refused {} {}: request body over the {} byte limit",
+                        method, uri_str, limits.max_body_bytes
+                    ),
+                );
+                return (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!(
+                        "Request body too large ({} byte limit)",
+                        limits.max_body_bytes
+                    ),
+                )
+                    .into_response();
+            }
+        };
+        let (body_b64, is_compressed) = compress_body(&body_bytes);
 
-    let proxy_req = ProxyRequest {
-        id,
-        method: method.clone(),
-        uri: uri_str.clone(),
-        headers: headers_vec,
-        body_b64,
-        is_compressed,
+        let proxy_req = ServerToClient::ProxyRequest(ProxyRequest {
+            id,
+            method: method.clone(),
+            uri: uri_str.clone(),
+            headers: headers_vec,
+            body_b64,
+            is_compressed,
+        });
+        (proxy_req, None)
     };
 
     // Prepare oneshot for the response
@@ -710,29 +2669,21 @@ pub async fn proxy_logic(
     }
 
     // Send to client
-    if sess
-        .outbound_tx
-        .send(ServerToClient::ProxyRequest(proxy_req))
-        .await
-        .is_err()
-    {
-        let mut pending = sess.pending.lock().await;
-        pending.remove(&id);
+    if sess.outbound_tx.send(first_msg).await.is_err() {
+        sess.pending.lock().await.remove(&id);
+        sess.response_bodies.lock().await.remove(&id);
         // log failure
         let dur_ms = start.elapsed().as_millis();
-        {
-            let mut log = sess.access_log.lock().await;
-            log.push(AccessLogEntry {
+        sess.log_access(
+            AccessLogEntry {
                 method: method.clone(),
                 uri: uri_str.clone(),
                 status: StatusCode::BAD_GATEWAY.as_u16(),
                 dur_ms,
-            });
-            if log.len() > 50 {
-                let drop_n = log.len() - 50;
-                log.drain(0..drop_n);
-            }
-        }
+            },
+            limits.access_log_entries,
+        )
+        .await;
         tracing::info!(
             "PROXY {} {} -> {} in {}ms (sid={})",
             method,
@@ -743,25 +2694,113 @@ pub async fn proxy_logic(
         );
         return (StatusCode::BAD_GATEWAY, "failed to send to tunnel client").into_response();
     }
+    // Dropped, and so cancelled, on timeout or when the visitor disconnects
+    let in_flight = CancelOnDrop::new(sess.clone(), id);
+
+    // Pump the visitor body to the client while we wait for the response
+    if let Some(body) = streamed_body {
+        let outbound_tx = sess.outbound_tx.clone();
+        let window = sess.windows.open(id, sess.features.credit);
+        tokio::spawn(async move {
+            let mut data = body.into_data_stream();
+            let mut error = None;
+            while let Some(frame) = data.next().await {
+                match frame {
+                    Ok(bytes) => {
+                        for piece in bytes.chunks(STREAM_CHUNK_SIZE) {
+                            // Released without credit once the request is answered or dropped
+                            if !window.reserve().await {
+                                return;
+                            }
+                            let msg = ServerToClient::RequestBody(BodyChunk::new(id, piece));
+                            if outbound_tx.send(msg).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error = Some(format!("visitor body error: {}", e));
+                        break;
+                    }
+                }
+            }
+            let _ = outbound_tx
+                .send(ServerToClient::RequestEnd(StreamEnd { id, error }))
+                .await;
+        });
+    }
 
     // Await response with timeout
-    let resp = match tokio::time::timeout(std::time::Duration::from_secs(30), resp_rx).await {
-        Ok(Ok(ClientToServer::ProxyResponse(r))) => r,
-        Ok(Err(_)) => {
+    let reply = tokio::time::timeout(settings.upstream_timeout(), resp_rx).await;
+    // Short of a timeout the client has answered, or the tunnel is gone; nothing to cancel
+    if reply.is_ok() {
+        in_flight.disarm();
+    }
+    let (status, resp_headers, body) = match reply {
+        Ok(Ok(ClientToServer::ProxyResponse(r))) => (
+            r.status,
+            r.headers,
+            axum::body::Body::from(decompress_body(&r.body_b64, r.is_compressed)),
+        ),
+        Ok(Ok(ClientToServer::ResponseStart(r))) => {
+            let body = match response_body_rx.take() {
+                Some(rx) => {
+                    // Hand the client credit as the visitor takes chunks
+                    let window = flow::RecvWindow::new(id, sess.features.credit);
+                    let outbound_tx = sess.outbound_tx.clone();
+                    axum::body::Body::from_stream(futures::stream::unfold(
+                        (rx, window, outbound_tx),
+                        |(mut rx, mut window, outbound_tx)| async move {
+                            let item = rx.recv().await?;
+                            if let Some(credit) = window.taken() {
+                                let _ = outbound_tx.send(ServerToClient::Credit(credit)).await;
+                            }
+                            Some((item, (rx, window, outbound_tx)))
+                        },
+                    ))
+                }
+                None => axum::body::Body::empty(),
+            };
+            (r.status, r.headers, body)
+        }
+        Ok(Ok(ClientToServer::Error(error))) => {
+            sess.response_bodies.lock().await.remove(&id);
+            let status = error.status();
             let dur_ms = start.elapsed().as_millis();
-            {
-                let mut log = sess.access_log.lock().await;
-                log.push(AccessLogEntry {
+            sess.log_access(
+                AccessLogEntry {
+                    method: method.clone(),
+                    uri: uri_str.clone(),
+                    status: status.as_u16(),
+                    dur_ms,
+                },
+                limits.access_log_entries,
+            )
+            .await;
+            tracing::info!(
+                "PROXY {} {} -> {} in {}ms (sid={}, {})",
+                method,
+                uri_str,
+                status.as_u16(),
+                dur_ms,
+                sid,
+                error.code.as_str()
+            );
+            return error_response(&error);
+        }
+        Ok(Ok(_)) | Ok(Err(_)) => {
+            sess.response_bodies.lock().await.remove(&id);
+            let dur_ms = start.elapsed().as_millis();
+            sess.log_access(
+                AccessLogEntry {
                     method: method.clone(),
                     uri: uri_str.clone(),
                     status: StatusCode::BAD_GATEWAY.as_u16(),
                     dur_ms,
-                });
-                if log.len() > 50 {
-                    let drop_n = log.len() - 50;
-                    log.drain(0..drop_n);
-                }
-            }
+                },
+                limits.access_log_entries,
+            )
+            .await;
             tracing::info!(
                 "PROXY {} {} -> {} in {}ms (sid={})",
                 method,
@@ -774,22 +2813,19 @@ pub async fn proxy_logic(
         }
         Err(_) => {
             // Timeout
-            let mut pending = sess.pending.lock().await;
-            pending.remove(&id);
+            sess.pending.lock().await.remove(&id);
+            sess.response_bodies.lock().await.remove(&id);
             let dur_ms = start.elapsed().as_millis();
-            {
-                let mut log = sess.access_log.lock().await;
-                log.push(AccessLogEntry {
+            sess.log_access(
+                AccessLogEntry {
                     method: method.clone(),
                     uri: uri_str.clone(),
                     status: StatusCode::GATEWAY_TIMEOUT.as_u16(),
                     dur_ms,
-                });
-                if log.len() > 50 {
-                    let drop_n = log.len() - 50;
-                    log.drain(0..drop_n);
-                }
-            }
+                },
+                limits.access_log_entries,
+            )
+            .await;
             tracing::info!(
                 "PROXY {} {} -> {} in {}ms (sid={})",
                 method,
@@ -803,21 +2839,21 @@ pub async fn proxy_logic(
     };
 
     // Build response to external client
-    let mut builder = axum::http::Response::builder().status(resp.status);
-    for (k, v) in resp.headers.iter() {
+    let mut builder = axum::http::Response::builder().status(status);
+    for (k, v) in resp_headers.iter() {
         // Skip hop-by-hop headers
         if is_hop_by_hop(k) {
             continue;
         }
 
-        // Rewrite relative Location headers to stay under /s/:sid/
+        // Rewrite relative Location headers to stay under the public prefix
         if k.eq_ignore_ascii_case("location") {
-            // Absolute-path Location: rewrite under /s/:sid/
+            // Absolute-path Location: rewrite under the prefix
             if v.starts_with('/') {
-                let new_loc = if v.starts_with(&format!("/s/{}/", sid)) {
+                let new_loc = if v.starts_with(&format!("{}/", prefix)) {
                     v.clone()
                 } else {
-                    format!("/s/{}/{}", sid, v.trim_start_matches('/'))
+                    format!("{}/{}", prefix, v.trim_start_matches('/'))
                 };
                 if let (Ok(name), Ok(value)) = (
                     axum::http::header::HeaderName::from_bytes(k.as_bytes()),
@@ -827,7 +2863,7 @@ pub async fn proxy_logic(
                 }
                 continue;
             }
-            // Absolute-URL Location (http/https): strip scheme+host and rewrite path+query under /s/:sid/
+            // Absolute-URL Location (http/https): strip scheme+host and rewrite path+query under the prefix
             let lower = v.to_ascii_lowercase();
             if lower.starts_with("http://") || lower.starts_with("https://") {
                 if let Some(scheme_idx) = v.find("://") {
@@ -835,10 +2871,10 @@ pub async fn proxy_logic(
                     if let Some(path_rel_idx) = v[after_scheme..].find('/') {
                         let path_start = after_scheme + path_rel_idx; // index of '/'
                         let path_q = &v[path_start..]; // includes leading '/'
-                        let new_loc = if path_q.starts_with(&format!("/s/{}/", sid)) {
+                        let new_loc = if path_q.starts_with(&format!("{}/", prefix)) {
                             path_q.to_string()
                         } else {
-                            format!("/s/{}/{}", sid, path_q.trim_start_matches('/'))
+                            format!("{}/{}", prefix, path_q.trim_start_matches('/'))
                         };
                         if let (Ok(name), Ok(value)) = (
                             axum::http::header::HeaderName::from_bytes(k.as_bytes()),
@@ -864,36 +2900,34 @@ pub async fn proxy_logic(
         .header("cache-control", "no-store")
         .header("x-robots-tag", "noindex, nofollow")
         .header("referrer-policy", "same-origin");
-    // Persist session id to a cookie for asset routing (/_next/* -> /s/:sid/_next/*)
-    if let Ok(cv) = axum::http::HeaderValue::from_str(&format!(
-        "tunly_sid={}; Path=/; Max-Age=600; HttpOnly; SameSite=Lax",
-        sid
-    )) {
+    // Persist session id to a cookie for asset routing (/_next/* -> /s/:sid/_next/*);
+    // not needed when the app owns its whole host
+    if routing == Routing::PathPrefix {
+        if let Ok(cv) = axum::http::HeaderValue::from_str(&format!(
+            "tunly_sid={}; Path=/; Max-Age=600; HttpOnly; SameSite=Lax",
+            sid
+        )) {
+            builder = builder.header(axum::http::header::SET_COOKIE, cv);
+        }
+    }
+    if let Some(cv) = login_cookie.clone() {
         builder = builder.header(axum::http::header::SET_COOKIE, cv);
     }
 
-    let body = decompress_body(&resp.body_b64, resp.is_compressed);
-    let response = builder
-        .body(axum::body::Body::from(body))
-        .unwrap()
-        .into_response();
+    let response = builder.body(body).unwrap().into_response();
 
-    // lightweight logging
+    // lightweight logging (streamed bodies are timed to the response head)
     let dur_ms = start.elapsed().as_millis();
-    {
-        // push to session ring buffer (keep last 50)
-        let mut log = sess.access_log.lock().await;
-        log.push(AccessLogEntry {
+    sess.log_access(
+        AccessLogEntry {
             method: method.clone(),
             uri: uri_str.clone(),
             status: response.status().as_u16(),
             dur_ms,
-        });
-        if log.len() > 50 {
-            let drop_n = log.len() - 50;
-            log.drain(0..drop_n);
-        }
-    }
+        },
+        limits.access_log_entries,
+    )
+    .await;
     tracing::info!(
         "PROXY {} {} -> {} in {}ms (sid={})",
         method,
@@ -906,6 +2940,276 @@ pub async fn proxy_logic(
     response
 }
 
+#[derive(Debug, Deserialize)]
+struct LoginForm {
+    passphrase: String,
+    #[serde(default)]
+    next: String,
+}
+
+/// Handle the passphrase form: set the login cookie and send the visitor back where they were.
+async fn passphrase_login(
+    state: &AppState,
+    auth: &access::VisitorAuth,
+    sid: &str,
+    prefix: &str,
+    req: Request<axum::body::Body>,
+) -> Response {
+    let login_action = format!("{}/{}", prefix, access::LOGIN_PATH);
+    let Ok(axum::Form(form)) = axum::Form::<LoginForm>::from_request(req, &()).await else {
+        return (StatusCode::BAD_REQUEST, "invalid login form").into_response();
+    };
+    // Only redirect within this session's URLs
+    let next = if form.next.starts_with(&format!("{}/", prefix)) && !form.next.starts_with("//") {
+        form.next
+    } else {
+        format!("{}/", prefix)
+    };
+    if !auth.accepts_passphrase(&form.passphrase) {
+        return access::challenge(auth, &login_action, &next);
+    }
+    let cookie_path = if prefix.is_empty() { "/" } else { prefix };
+    let mut builder = axum::http::Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(axum::http::header::LOCATION, next)
+        .header("cache-control", "no-store");
+    if let Some(cv) = access::login_cookie(auth, &state.jwt_secret, sid, cookie_path) {
+        builder = builder.header(axum::http::header::SET_COOKIE, cv);
+    }
+    builder
+        .body(axum::body::Body::empty())
+        .unwrap()
+        .into_response()
+}
+
+pub fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get(axum::http::header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+async fn proxy_websocket(
+    state: &Arc<AppState>,
+    sess: Arc<SessionState>,
+    sid: &str,
+    uri_str: String,
+    start: Instant,
+    req: Request<axum::body::Body>,
+) -> Response {
+    if !sess.features.websocket {
+        return (
+            StatusCode::BAD_GATEWAY,
+            "tunnel client does not support websocket upgrades",
+        )
+            .into_response();
+    }
+    let (mut parts, _body) = req.into_parts();
+    let ws = match WebSocketUpgrade::from_request_parts(&mut parts, state).await {
+        Ok(ws) => ws,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let id = state.req_id.fetch_add(1, Ordering::SeqCst);
+    // The client performs its own handshake with the local target; only the
+    // requested subprotocols are passed through from the visitor's handshake.
+    let headers = headers_to_vec(&parts.headers)
+        .into_iter()
+        .filter(|(k, _)| !k.starts_with("sec-websocket-") || k == "sec-websocket-protocol")
+        .collect();
+
+    // Register the frame channel before the client can start sending frames
+    let (frames_tx, frames_rx) = mpsc::channel(flow::RECV_BUFFER);
+    sess.streams.lock().await.insert(id, frames_tx);
+    let (open_tx, open_rx) = oneshot::channel::<ClientToServer>();
+    sess.pending.lock().await.insert(id, open_tx);
+
+    let open = ServerToClient::WsOpen(WsOpen {
+        id,
+        uri: uri_str.clone(),
+        headers,
+    });
+    let sent = sess.outbound_tx.send(open).await.is_ok();
+    let opened = if sent {
+        match tokio::time::timeout(state.settings().upstream_timeout(), open_rx).await {
+            Ok(Ok(ClientToServer::WsOpened(opened))) => Some(opened),
+            _ => None,
+        }
+    } else {
+        None
+    };
+
+    let status = opened.as_ref().map_or(502, |o| o.status);
+    let dur_ms = start.elapsed().as_millis();
+    sess.log_access(
+        AccessLogEntry {
+            method: "GET".to_string(),
+            uri: uri_str.clone(),
+            status,
+            dur_ms,
+        },
+        state.settings().limits.access_log_entries,
+    )
+    .await;
+    tracing::info!(
+        "PROXY WS {} -> {} in {}ms (sid={})",
+        uri_str,
+        status,
+        dur_ms,
+        sid
+    );
+
+    let protocol = match opened {
+        Some(WsOpened {
+            status: 101,
+            protocol,
+            ..
+        }) => protocol,
+        other => {
+            sess.pending.lock().await.remove(&id);
+            sess.streams.lock().await.remove(&id);
+            // Without an answer the client may still be connecting, or have connected, to
+            // the local target; have it close that socket once it gets there
+            if sent && other.is_none() {
+                close_client_ws(&sess, id, "visitor upgrade abandoned").await;
+            }
+            let reason = other
+                .and_then(|o| o.error)
+                .unwrap_or_else(|| "websocket upgrade failed".to_string());
+            let status = StatusCode::from_u16(status)
+                .ok()
+                .filter(|s| s.as_u16() != 101)
+                .unwrap_or(StatusCode::BAD_GATEWAY);
+            return (status, reason).into_response();
+        }
+    };
+    let ws = match protocol {
+        Some(p) => ws.protocols([p]),
+        None => ws,
+    };
+    let failed_sess = sess.clone();
+    ws.on_failed_upgrade(move |_| {
+        tokio::spawn(async move {
+            failed_sess.streams.lock().await.remove(&id);
+            close_client_ws(&failed_sess, id, "visitor upgrade failed").await;
+        });
+    })
+    .on_upgrade(move |socket| relay_visitor_ws(socket, sess, id, frames_rx))
+}
+
+// Tell the client to close its side of relayed WebSocket `id`
+async fn close_client_ws(sess: &SessionState, id: u64, reason: &str) {
+    let close = WsClose {
+        id,
+        code: Some(1001),
+        reason: reason.to_string(),
+    };
+    let _ = sess.outbound_tx.send(ServerToClient::WsClose(close)).await;
+}
+
+// Pump frames between an upgraded visitor socket and the tunnel until either side closes
+async fn relay_visitor_ws(
+    socket: WebSocket,
+    sess: Arc<SessionState>,
+    id: u64,
+    mut frames_rx: mpsc::Receiver<ClientToServer>,
+) {
+    let (mut visitor_tx, mut visitor_rx) = socket.split();
+
+    // Ends with whether the client's side is closed already
+    let to_visitor = async {
+        let mut window = flow::RecvWindow::new(id, sess.features.credit);
+        while let Some(msg) = frames_rx.recv().await {
+            if matches!(msg, ClientToServer::WsFrame(_)) {
+                if let Some(credit) = window.taken() {
+                    let _ = sess.outbound_tx.send(ServerToClient::Credit(credit)).await;
+                }
+            }
+            let out = match msg {
+                ClientToServer::WsFrame(frame) => match frame.kind {
+                    WsFrameKind::Text => match String::from_utf8(frame.data) {
+                        Ok(text) => Message::Text(text.into()),
+                        Err(_) => continue,
+                    },
+                    WsFrameKind::Binary => Message::Binary(frame.data.into()),
+                },
+                ClientToServer::WsClose(close) => {
+                    let _ = visitor_tx
+                        .send(Message::Close(Some(CloseFrame {
+                            code: close.code.unwrap_or(1000),
+                            reason: close.reason.into(),
+                        })))
+                        .await;
+                    return true;
+                }
+                _ => continue,
+            };
+            if visitor_tx.send(out).await.is_err() {
+                return false;
+            }
+        }
+        // Tunnel went away underneath us
+        let _ = visitor_tx
+            .send(Message::Close(Some(CloseFrame {
+                code: 1001,
+                reason: "tunnel closed".into(),
+            })))
+            .await;
+        true
+    };
+
+    let outbound_tx = sess.outbound_tx.clone();
+    let window = sess.windows.open(id, sess.features.credit);
+    let from_visitor = async {
+        let mut close = WsClose {
+            id,
+            code: None,
+            reason: String::new(),
+        };
+        while let Some(Ok(msg)) = visitor_rx.next().await {
+            let frame = match msg {
+                Message::Text(text) => WsFrame {
+                    id,
+                    kind: WsFrameKind::Text,
+                    data: text.as_bytes().to_vec(),
+                },
+                Message::Binary(data) => WsFrame {
+                    id,
+                    kind: WsFrameKind::Binary,
+                    data: data.to_vec(),
+                },
+                Message::Close(frame) => {
+                    if let Some(frame) = frame {
+                        close.code = Some(frame.code);
+                        close.reason = frame.reason.to_string();
+                    }
+                    break;
+                }
+                _ => continue,
+            };
+            if !window.reserve().await
+                || outbound_tx
+                    .send(ServerToClient::WsFrame(frame))
+                    .await
+                    .is_err()
+            {
+                return;
+            }
+        }
+        let _ = outbound_tx.send(ServerToClient::WsClose(close)).await;
+    };
+
+    // from_visitor tells the client itself when the visitor closes
+    let client_closed = tokio::select! {
+        closed = to_visitor => closed,
+        _ = from_visitor => true,
+    };
+    sess.streams.lock().await.remove(&id);
+    if !client_closed {
+        close_client_ws(&sess, id, "visitor went away").await;
+    }
+}
+
 pub fn headers_to_vec(headers: &HeaderMap) -> Vec<(String, String)> {
     headers
         .iter()
@@ -0,0 +1,213 @@
+//! Proof of possession: a tunnel client holds an Ed25519 key, sends its public key to `/token`
+//! (`?pubkey=`) and, when connecting to `/ws`, signs a fresh challenge from `/challenge` together
+//! with the session id. Tokens bound to a key this way are not tied to the requester's IP, and
+//! the same key can reclaim its session later without the resume secret.
+
+use std::{
+    io,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{generate_secret, AppState};
+
+/// base64url Ed25519 public key of the connecting client
+pub const CLIENT_KEY_HEADER: &str = "x-tunly-client-key";
+/// Challenge the client signed, as returned by `/challenge`
+pub const CHALLENGE_HEADER: &str = "x-tunly-challenge";
+/// base64url Ed25519 signature over [`proof_message`]
+pub const SIGNATURE_HEADER: &str = "x-tunly-signature";
+/// Challenges must be answered within this many seconds
+pub const CHALLENGE_TTL_SECS: u64 = 60;
+
+const CHALLENGE_KIND: &str = "challenge";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChallengeClaims {
+    kind: String,
+    jti: String,
+    exp: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeResponse {
+    pub challenge: String,
+    pub expires_in: u64,
+}
+
+/// Parse a base64url (unpadded) Ed25519 public key.
+pub fn parse_public_key(value: &str) -> Result<VerifyingKey, &'static str> {
+    let bytes = general_purpose::URL_SAFE_NO_PAD
+        .decode(value.trim())
+        .map_err(|_| "public key is not base64url")?;
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| "public key must be 32 bytes")?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| "invalid Ed25519 public key")
+}
+
+/// What the client signs: the challenge and the session id it is connecting to.
+pub fn proof_message(challenge: &str, sid: &str) -> Vec<u8> {
+    format!("tunly-pop\n{}\n{}", challenge, sid).into_bytes()
+}
+
+/// A client's signing key.
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        let mut seed = [0u8; 32];
+        rand::rng().fill_bytes(&mut seed);
+        Self {
+            key: SigningKey::from_bytes(&seed),
+        }
+    }
+
+    /// Load the key stored at `path`, creating it (readable by the owner only) if missing.
+    pub fn load_or_create(path: &Path) -> io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                let seed: [u8; 32] = general_purpose::URL_SAFE_NO_PAD
+                    .decode(text.trim())
+                    .ok()
+                    .and_then(|b| b.try_into().ok())
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "not a tunly identity key")
+                    })?;
+                Ok(Self {
+                    key: SigningKey::from_bytes(&seed),
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Self::generate();
+                let encoded = general_purpose::URL_SAFE_NO_PAD.encode(identity.key.to_bytes());
+                let mut options = std::fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                io::Write::write_all(&mut options.open(path)?, encoded.as_bytes())?;
+                Ok(identity)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// base64url public key, as sent to `/token`.
+    pub fn public_key(&self) -> String {
+        general_purpose::URL_SAFE_NO_PAD.encode(self.key.verifying_key().as_bytes())
+    }
+
+    /// Value for [`SIGNATURE_HEADER`] answering `challenge` for `sid`.
+    pub fn sign(&self, challenge: &str, sid: &str) -> String {
+        let signature = self.key.sign(&proof_message(challenge, sid));
+        general_purpose::URL_SAFE_NO_PAD.encode(signature.to_bytes())
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// `GET /challenge`: a short-lived, server-signed nonce, so nothing is stored until it is used.
+pub async fn challenge_endpoint(State(state): State<Arc<AppState>>) -> Response {
+    let claims = ChallengeClaims {
+        kind: CHALLENGE_KIND.to_string(),
+        jti: generate_secret(),
+        exp: (unix_now() + CHALLENGE_TTL_SECS) as usize,
+    };
+    match encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(&state.jwt_secret),
+    ) {
+        Ok(challenge) => (
+            [(header::CACHE_CONTROL, "no-store")],
+            Json(ChallengeResponse {
+                challenge,
+                expires_in: CHALLENGE_TTL_SECS,
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            tracing::error!("Failed to encode challenge: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "challenge generation failed",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Check the proof headers on a `/ws` request for `sid`. Returns the proven public key, `None`
+/// when the client sent no proof, or a 401 for a bad or replayed one.
+pub async fn verify(
+    state: &AppState,
+    headers: &HeaderMap,
+    sid: &str,
+) -> Result<Option<String>, Response> {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let (Some(challenge), Some(signature)) = (header(CHALLENGE_HEADER), header(SIGNATURE_HEADER))
+    else {
+        return Ok(None);
+    };
+    let Some(public_key) = header(CLIENT_KEY_HEADER) else {
+        return Err(refuse("missing client key"));
+    };
+    let key = parse_public_key(public_key).map_err(refuse)?;
+    let claims = decode::<ChallengeClaims>(
+        challenge,
+        &DecodingKey::from_secret(&state.jwt_secret),
+        &Validation::default(),
+    )
+    .ok()
+    .map(|data| data.claims)
+    .filter(|c| c.kind == CHALLENGE_KIND)
+    .ok_or_else(|| refuse("invalid or expired challenge"))?;
+    let signature = general_purpose::URL_SAFE_NO_PAD
+        .decode(signature)
+        .ok()
+        .and_then(|b| Signature::from_slice(&b).ok())
+        .ok_or_else(|| refuse("malformed signature"))?;
+    if key
+        .verify_strict(&proof_message(challenge, sid), &signature)
+        .is_err()
+    {
+        return Err(refuse("signature does not match the client key"));
+    }
+
+    // Each challenge proves possession once
+    let now = Instant::now();
+    let mut used = state.used_challenges.lock().await;
+    used.retain(|_, exp| *exp > now);
+    if used.contains_key(&claims.jti) {
+        return Err(refuse("challenge already used"));
+    }
+    used.insert(
+        claims.jti,
+        now + Duration::from_secs(CHALLENGE_TTL_SECS + 60),
+    );
+    Ok(Some(public_key.to_string()))
+}
+
+fn refuse(msg: &'static str) -> Response {
+    (StatusCode::UNAUTHORIZED, msg).into_response()
+}
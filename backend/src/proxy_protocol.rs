@@ -0,0 +1,183 @@
+//! HAProxy PROXY protocol (v1 text and v2 binary) on the public listener, for load balancers
+//! that pass the visitor's address in front of the TCP stream instead of in HTTP headers.
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use axum::serve::Listener;
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+// A proxy sends the header right away; anything slower is not one
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Longest v1 line, CRLF included
+const V1_MAX_LEN: usize = 107;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Listener that requires a PROXY header on every connection and reports the address it
+/// carries as the peer address. Headers are read off the accept loop, so a slow or silent
+/// peer does not hold up other connections.
+///
+/// `axum::serve` only provides `ConnectInfo<SocketAddr>` for plain `TcpListener`s and
+/// `tap_io` wrappers, so serve it as `listener.tap_io(|_| {})`.
+pub struct ProxyProtocolListener {
+    rx: mpsc::Receiver<(TcpStream, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl ProxyProtocolListener {
+    /// Accept connections on `listener` from peers `admit` approves; anyone able to send a
+    /// PROXY header can claim any address, so this should only let the proxies in.
+    pub fn new<F>(listener: TcpListener, admit: F) -> io::Result<Self>
+    where
+        F: Fn(IpAddr) -> bool + Send + 'static,
+    {
+        let local_addr = listener.local_addr()?;
+        let (tx, rx) = mpsc::channel(128);
+        tokio::spawn(async move {
+            loop {
+                let accepted = tokio::select! {
+                    _ = tx.closed() => break,
+                    accepted = listener.accept() => accepted,
+                };
+                let (mut stream, peer) = match accepted {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        tracing::warn!("accept error: {}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                if !admit(peer.ip()) {
+                    tracing::warn!("refusing connection from untrusted proxy {}", peer);
+                    continue;
+                }
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+                        Ok(Ok(source)) => {
+                            let _ = tx.send((stream, source.unwrap_or(peer))).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!("invalid PROXY header from {}: {}", peer, e),
+                        Err(_) => tracing::debug!("no PROXY header from {} in time", peer),
+                    }
+                });
+            }
+        });
+        Ok(Self { rx, local_addr })
+    }
+}
+
+impl Listener for ProxyProtocolListener {
+    type Io = TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.rx.recv().await {
+            Some(conn) => conn,
+            // The accept loop only stops once we are gone
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Consume the PROXY header, leaving the stream at the first byte of the proxied data.
+/// `None` means the header carries no address (`UNKNOWN`, `LOCAL`, unix sockets) and the
+/// connection's own peer should be used.
+async fn read_header(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    let mut head = [0u8; 8];
+    stream.read_exact(&mut head).await?;
+    if head.starts_with(b"PROXY ") {
+        // Byte by byte so nothing past the CRLF is consumed
+        let mut line = head.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("PROXY v1 line too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        let line = std::str::from_utf8(&line).map_err(|_| invalid("PROXY v1 line not UTF-8"))?;
+        return parse_v1(line.trim_end());
+    }
+    if head[..] == V2_SIGNATURE[..8] {
+        let mut rest = [0u8; 8];
+        stream.read_exact(&mut rest).await?;
+        if rest[..4] != V2_SIGNATURE[8..] {
+            return Err(invalid("bad PROXY v2 signature"));
+        }
+        let len = u16::from_be_bytes([rest[6], rest[7]]) as usize;
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await?;
+        return parse_v2(rest[4], rest[5], &body);
+    }
+    Err(invalid("missing PROXY header"))
+}
+
+/// `PROXY TCP4 <src> <dst> <sport> <dport>`, `PROXY TCP6 ...` or `PROXY UNKNOWN ...`.
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let mut parts = line.split(' ').skip(1);
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {
+            let src: IpAddr = parts
+                .next()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid("bad PROXY v1 source address"))?;
+            let _dst = parts.next();
+            let port: u16 = parts
+                .next()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid("bad PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(src, port)))
+        }
+        Some("UNKNOWN") => Ok(None),
+        _ => Err(invalid("unsupported PROXY v1 protocol")),
+    }
+}
+
+/// Version/command byte, family byte and the address block that follows the fixed header.
+fn parse_v2(ver_cmd: u8, family: u8, body: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported PROXY version"));
+    }
+    match ver_cmd & 0x0f {
+        // LOCAL: health checks from the proxy itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+    match family >> 4 {
+        // AF_INET: src addr, dst addr, src port, dst port
+        0x1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        0x2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        0x1 | 0x2 => Err(invalid("short PROXY v2 address block")),
+        // AF_UNSPEC, AF_UNIX
+        _ => Ok(None),
+    }
+}
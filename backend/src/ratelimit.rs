@@ -0,0 +1,229 @@
+//! Token-bucket rate limits. Every route class (`/token`, `/ws` connects, proxied requests) has
+//! its own quota, and buckets are keyed by visitor IP or by session. A quota of `max` requests per
+//! `window` is a bucket of `max` tokens refilled evenly over the window, so bursts up to `max` are
+//! allowed while the long-run rate stays at `max` per window. Buckets that have refilled are
+//! dropped by [`RateLimiter::evict_idle`], and the number of buckets is capped by evicting the
+//! least recently used one.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tokio::sync::Mutex;
+
+pub const LIMIT_HEADER: HeaderName = HeaderName::from_static("ratelimit-limit");
+pub const REMAINING_HEADER: HeaderName = HeaderName::from_static("ratelimit-remaining");
+pub const RESET_HEADER: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// What a bucket is counting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteClass {
+    /// `/token` and `/token/refresh`, per client IP
+    Token,
+    /// `/ws` connect attempts, per client IP
+    WsConnect,
+    /// Proxied visitor requests, per visitor IP
+    Proxy,
+    /// Proxied visitor requests, per session (all visitors together)
+    Session,
+}
+
+/// `max` requests per `window`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub max: u32,
+    pub window: Duration,
+}
+
+impl Quota {
+    pub fn new(max: u32, window_secs: u64) -> Self {
+        Self {
+            max,
+            window: Duration::from_secs(window_secs.max(1)),
+        }
+    }
+
+    // Tokens regained per second
+    fn rate(&self) -> f64 {
+        f64::from(self.max) / self.window.as_secs_f64()
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    quota: Quota,
+    // Position in `Table::by_use`
+    used: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, quota: Quota, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens += elapsed * self.quota.rate();
+        // A reloaded quota grants (or takes back) the difference in burst size right away
+        if quota != self.quota {
+            self.tokens = (self.tokens + f64::from(quota.max) - f64::from(self.quota.max)).max(0.0);
+            self.quota = quota;
+        }
+        self.tokens = self.tokens.min(f64::from(quota.max));
+        self.updated = now;
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * self.quota.rate() >= f64::from(self.quota.max)
+    }
+
+    // Seconds until `tokens` more are available
+    fn secs_until(&self, tokens: f64) -> u64 {
+        let missing = (tokens - self.tokens).max(0.0);
+        let rate = self.quota.rate();
+        if missing == 0.0 || rate == 0.0 {
+            return 0;
+        }
+        (missing / rate).ceil() as u64
+    }
+}
+
+/// Outcome of taking a token, with the values for the `RateLimit-*` headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again
+    pub reset: u64,
+    /// Seconds until the next request would be admitted (0 when allowed)
+    pub retry_after: u64,
+}
+
+impl Decision {
+    /// The more restrictive of two decisions, for requests limited under several keys.
+    pub fn tighter(self, other: Decision) -> Decision {
+        match (self.allowed, other.allowed) {
+            (true, false) => other,
+            (false, true) => self,
+            _ if other.remaining < self.remaining => other,
+            _ => self,
+        }
+    }
+
+    /// Add the `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        headers.insert(LIMIT_HEADER, HeaderValue::from(self.limit));
+        headers.insert(REMAINING_HEADER, HeaderValue::from(self.remaining));
+        headers.insert(RESET_HEADER, HeaderValue::from(self.reset));
+    }
+
+    /// `429` with the rate-limit headers and `Retry-After`.
+    pub fn too_many_requests(&self, msg: &'static str) -> Response {
+        let mut resp = (
+            StatusCode::TOO_MANY_REQUESTS,
+            [
+                (header::RETRY_AFTER, self.retry_after.max(1).to_string()),
+                (header::CACHE_CONTROL, "no-store".to_string()),
+            ],
+            msg,
+        )
+            .into_response();
+        self.apply(resp.headers_mut());
+        resp
+    }
+}
+
+type Key = (RouteClass, String);
+
+#[derive(Default)]
+struct Table {
+    buckets: HashMap<Key, Bucket>,
+    // Keys by last use, oldest first, so eviction never scans the whole table
+    by_use: BTreeMap<u64, Key>,
+    uses: u64,
+}
+
+/// Buckets for all route classes and keys.
+#[derive(Default)]
+pub struct RateLimiter {
+    table: Mutex<Table>,
+}
+
+impl RateLimiter {
+    /// Take one token from the `class` bucket for `key`. When `max_keys` buckets exist already,
+    /// the least recently used one is dropped.
+    pub async fn acquire(
+        &self,
+        class: RouteClass,
+        key: &str,
+        quota: Quota,
+        max_keys: usize,
+    ) -> Decision {
+        let now = Instant::now();
+        let mut guard = self.table.lock().await;
+        let table = &mut *guard;
+        let id = (class, key.to_string());
+        if !table.buckets.contains_key(&id) {
+            while table.buckets.len() >= max_keys.max(1) {
+                let Some((_, stalest)) = table.by_use.pop_first() else {
+                    break;
+                };
+                table.buckets.remove(&stalest);
+            }
+        }
+        table.uses += 1;
+        let used = table.uses;
+        let bucket = table.buckets.entry(id.clone()).or_insert_with(|| Bucket {
+            tokens: f64::from(quota.max),
+            updated: now,
+            quota,
+            used,
+        });
+        table.by_use.remove(&bucket.used);
+        bucket.used = used;
+        table.by_use.insert(used, id);
+        bucket.refill(quota, now);
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        Decision {
+            allowed,
+            limit: quota.max,
+            remaining: bucket.tokens.floor() as u32,
+            reset: bucket.secs_until(f64::from(quota.max)),
+            retry_after: if allowed { 0 } else { bucket.secs_until(1.0) },
+        }
+    }
+
+    /// Forget buckets that have refilled completely; returns how many were dropped.
+    pub async fn evict_idle(&self) -> usize {
+        let now = Instant::now();
+        let mut guard = self.table.lock().await;
+        let table = &mut *guard;
+        let before = table.buckets.len();
+        let by_use = &mut table.by_use;
+        table.buckets.retain(|_, b| {
+            let keep = !b.is_full(now);
+            if !keep {
+                by_use.remove(&b.used);
+            }
+            keep
+        });
+        before - table.buckets.len()
+    }
+
+    /// Buckets currently tracked.
+    pub async fn len(&self) -> usize {
+        self.table.lock().await.buckets.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.table.lock().await.buckets.is_empty()
+    }
+}
@@ -0,0 +1,227 @@
+//! Server state that must survive restarts (reserved names, revoked access tokens, outstanding
+//! refresh credentials), kept in a small JSON file (`--state-file`). Without a file everything
+//! lives in memory only.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("name is reserved by another owner")]
+    Taken,
+    #[error("failed to persist state: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Who reserved a name and when.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NameRecord {
+    /// `owner_id` of the key that claimed the name
+    pub owner: String,
+    /// Unix seconds
+    pub claimed_at: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Persisted {
+    #[serde(default)]
+    names: HashMap<String, NameRecord>,
+    // access token jti -> unix seconds it was revoked at
+    #[serde(default)]
+    revoked: HashMap<String, u64>,
+    // refresh credential jti -> its session; each is exchanged at most once
+    #[serde(default)]
+    refresh: HashMap<String, RefreshRecord>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RefreshRecord {
+    sid: String,
+    /// Unix seconds
+    expires_at: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Store {
+    path: Option<PathBuf>,
+    data: Mutex<Persisted>,
+}
+
+impl Store {
+    /// Load the state file; a missing file starts out empty and is created on first write.
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let data = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Persisted::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: Some(path),
+            data: Mutex::new(data),
+        })
+    }
+
+    pub async fn name_owner(&self, name: &str) -> Option<String> {
+        self.data
+            .lock()
+            .await
+            .names
+            .get(name)
+            .map(|r| r.owner.clone())
+    }
+
+    /// Reserve `name` for `owner`. Claiming a name the owner already holds is a no-op.
+    pub async fn claim_name(&self, name: &str, owner: &str) -> Result<(), StoreError> {
+        let mut data = self.data.lock().await;
+        if let Some(existing) = data.names.get(name) {
+            return if existing.owner == owner {
+                Ok(())
+            } else {
+                Err(StoreError::Taken)
+            };
+        }
+        let claimed_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        data.names.insert(
+            name.to_string(),
+            NameRecord {
+                owner: owner.to_string(),
+                claimed_at,
+            },
+        );
+        if let Err(e) = self.save(&data).await {
+            data.names.remove(name);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    pub async fn is_revoked(&self, jti: &str) -> bool {
+        self.data.lock().await.revoked.contains_key(jti)
+    }
+
+    /// Refuse the access token `jti` from now on; returns false if it already was.
+    pub async fn revoke(&self, jti: &str) -> Result<bool, StoreError> {
+        let mut data = self.data.lock().await;
+        if data.revoked.contains_key(jti) {
+            return Ok(false);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        data.revoked.insert(jti.to_string(), now);
+        if let Err(e) = self.save(&data).await {
+            data.revoked.remove(jti);
+            return Err(e.into());
+        }
+        Ok(true)
+    }
+
+    /// Record refresh credential `jti` for `sid`, replacing the session's earlier ones.
+    ///
+    /// Refresh bookkeeping stays in memory when the file cannot be written; the failure is only
+    /// logged, as the credential is renewed over the tunnel long before it expires.
+    pub async fn add_refresh(&self, jti: &str, sid: &str, expires_at: u64) {
+        let mut data = self.data.lock().await;
+        data.refresh.retain(|_, r| r.sid != sid);
+        data.refresh.insert(
+            jti.to_string(),
+            RefreshRecord {
+                sid: sid.to_string(),
+                expires_at,
+            },
+        );
+        self.save_refresh(&data).await;
+    }
+
+    /// Exchange refresh credential `jti` of `sid`: true, and forgotten, if it was outstanding.
+    pub async fn take_refresh(&self, jti: &str, sid: &str) -> bool {
+        let mut data = self.data.lock().await;
+        let Some(record) = data.refresh.remove(jti) else {
+            return false;
+        };
+        self.save_refresh(&data).await;
+        record.sid == sid && record.expires_at > unix_now()
+    }
+
+    /// Forget the refresh credentials of `sid`.
+    pub async fn drop_refresh(&self, sid: &str) {
+        let mut data = self.data.lock().await;
+        let before = data.refresh.len();
+        data.refresh.retain(|_, r| r.sid != sid);
+        if data.refresh.len() != before {
+            self.save_refresh(&data).await;
+        }
+    }
+
+    /// Forget expired refresh credentials; returns how many were dropped.
+    pub async fn expire_refresh(&self) -> usize {
+        let now = unix_now();
+        let mut data = self.data.lock().await;
+        let before = data.refresh.len();
+        data.refresh.retain(|_, r| r.expires_at > now);
+        let expired = before - data.refresh.len();
+        if expired > 0 {
+            self.save_refresh(&data).await;
+        }
+        expired
+    }
+
+    /// Refresh credentials outstanding.
+    pub async fn refresh_count(&self) -> usize {
+        self.data.lock().await.refresh.len()
+    }
+
+    async fn save_refresh(&self, data: &Persisted) {
+        if let Err(e) = self.save(data).await {
+            tracing::warn!("Failed to persist refresh credentials: {}", e);
+        }
+    }
+
+    async fn save(&self, data: &Persisted) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json = serde_json::to_vec_pretty(data)?;
+        // Write-then-rename so a crash never leaves a half-written file behind
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, json).await?;
+        tokio::fs::rename(&tmp, path).await
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Stable, non-reversible id for the key (API key or fixed token) that owns a name.
+pub fn owner_id(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Names become the session id (and subdomain), so they must be DNS labels.
+pub fn validate_name(name: &str) -> Result<(), &'static str> {
+    if name.len() >= 3 && crate::is_dns_label(name) {
+        Ok(())
+    } else {
+        Err("invalid name: use 3-63 lowercase letters, digits or dashes")
+    }
+}
@@ -0,0 +1,224 @@
+//! Raw TCP tunnels: a server-allocated public port whose connections are relayed
+//! as multiplexed streams over the session WebSocket.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::{
+    flow, AccessLogEntry, AppState, BodyChunk, ClientToServer, ServerToClient, SessionState,
+    StreamEnd, TcpOpen, STREAM_CHUNK_SIZE,
+};
+
+// Pause after a failed accept, doubled for every further failure in a row
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(50);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    /// Address public TCP listeners bind to
+    pub bind_ip: IpAddr,
+    /// Ports handed out to TCP tunnels, first free one wins
+    pub ports: RangeInclusive<u16>,
+}
+
+/// Parse `20000-20100` (or a single `20000`) into an inclusive port range.
+pub fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = match s.split_once('-') {
+        Some((a, b)) => (a.trim(), b.trim()),
+        None => (s.trim(), s.trim()),
+    };
+    let start: u16 = start
+        .parse()
+        .map_err(|_| format!("invalid port: {}", start))?;
+    let end: u16 = end.parse().map_err(|_| format!("invalid port: {}", end))?;
+    if start == 0 || start > end {
+        return Err(format!("invalid port range: {}", s));
+    }
+    Ok(start..=end)
+}
+
+/// A bound public listener for one session. Dropping it frees the port.
+#[derive(Debug)]
+pub struct TcpTunnel {
+    pub port: u16,
+    listener: TcpListener,
+}
+
+/// Bind `preferred` if it is in range and free, otherwise the first free port from the range.
+pub async fn allocate(config: &TcpConfig, preferred: Option<u16>) -> Option<TcpTunnel> {
+    let preferred = preferred.filter(|p| config.ports.contains(p));
+    for port in preferred.into_iter().chain(config.ports.clone()) {
+        if let Ok(listener) = TcpListener::bind((config.bind_ip, port)).await {
+            return Some(TcpTunnel { port, listener });
+        }
+    }
+    None
+}
+
+/// Accept public connections and announce each one to the tunnel client.
+pub async fn serve(tunnel: TcpTunnel, state: Arc<AppState>, sess: Arc<SessionState>, sid: String) {
+    tracing::info!(
+        "TCP tunnel for session {} listening on {}",
+        sid,
+        tunnel.port
+    );
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+    loop {
+        let (stream, peer) = match tunnel.listener.accept().await {
+            Ok(conn) => {
+                backoff = ACCEPT_BACKOFF_MIN;
+                conn
+            }
+            Err(e) => {
+                // Errors like EMFILE persist for a while; retrying at once would spin
+                tracing::warn!(
+                    "TCP accept failed on port {}: {} (retrying in {}ms)",
+                    tunnel.port,
+                    e,
+                    backoff.as_millis()
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                continue;
+            }
+        };
+        if state.is_draining() {
+            tracing::info!("TCP {} refused while shutting down (sid={})", peer, sid);
+            continue;
+        }
+        let ip = Some(peer.ip());
+        if !state.settings().ip_filter.permits(ip) || !sess.ip_filter.permits(ip) {
+            state.metrics.ip_denied.inc();
+            tracing::info!("TCP {} rejected by IP lists (sid={})", peer, sid);
+            continue;
+        }
+        let id = state.req_id.fetch_add(1, Ordering::SeqCst);
+        // Register the stream before the client can answer
+        let (tx, rx) = mpsc::channel(flow::RECV_BUFFER);
+        sess.streams.lock().await.insert(id, tx);
+        let open = ServerToClient::TcpOpen(TcpOpen {
+            id,
+            peer: peer.to_string(),
+        });
+        if sess.outbound_tx.send(open).await.is_err() {
+            sess.streams.lock().await.remove(&id);
+            break;
+        }
+        state.metrics.tcp_connections.inc();
+        sess.requests.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(relay(
+            stream,
+            peer,
+            state.clone(),
+            sess.clone(),
+            id,
+            rx,
+            sid.clone(),
+        ));
+    }
+}
+
+async fn relay(
+    stream: TcpStream,
+    peer: SocketAddr,
+    state: Arc<AppState>,
+    sess: Arc<SessionState>,
+    id: u64,
+    mut rx: mpsc::Receiver<ClientToServer>,
+    sid: String,
+) {
+    let start = Instant::now();
+    let (mut reader, mut writer) = stream.into_split();
+    let outbound_tx = sess.outbound_tx.clone();
+
+    // visitor -> client; EOF is forwarded as `tcp_close` (half-close)
+    let window = sess.windows.open(id, sess.features.credit);
+    let upstream = async {
+        let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+        let error = loop {
+            if !window.reserve().await {
+                return;
+            }
+            match reader.read(&mut buf).await {
+                Ok(0) => break None,
+                Ok(n) => {
+                    let msg = ServerToClient::TcpData(BodyChunk::new(id, &buf[..n]));
+                    if outbound_tx.send(msg).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => break Some(e.to_string()),
+            }
+        };
+        let _ = outbound_tx
+            .send(ServerToClient::TcpClose(StreamEnd { id, error }))
+            .await;
+    };
+
+    // client -> visitor
+    let downstream = async {
+        let mut window = flow::RecvWindow::new(id, sess.features.credit);
+        while let Some(msg) = rx.recv().await {
+            match msg {
+                ClientToServer::TcpData(chunk) => {
+                    if let Some(credit) = window.taken() {
+                        let _ = outbound_tx.send(ServerToClient::Credit(credit)).await;
+                    }
+                    if let Err(e) = writer.write_all(&chunk.into_data()).await {
+                        return Some(e.to_string());
+                    }
+                }
+                ClientToServer::TcpClose(end) => {
+                    let _ = writer.shutdown().await;
+                    return end.error;
+                }
+                _ => {}
+            }
+        }
+        Some("tunnel closed".to_string())
+    };
+
+    tokio::pin!(upstream, downstream);
+    // Keep both halves running until each side has closed, unless either one failed
+    let error = tokio::select! {
+        error = &mut downstream => {
+            if error.is_none() {
+                upstream.await;
+            }
+            error
+        }
+        _ = &mut upstream => downstream.await,
+    };
+    sess.streams.lock().await.remove(&id);
+
+    let dur_ms = start.elapsed().as_millis();
+    let status = if error.is_some() { 502 } else { 200 };
+    sess.log_access(
+        AccessLogEntry {
+            method: "TCP".to_string(),
+            uri: peer.to_string(),
+            status,
+            dur_ms,
+        },
+        state.settings().limits.access_log_entries,
+    )
+    .await;
+    match error {
+        Some(e) => tracing::info!(
+            "TCP {} closed after {}ms with error: {} (sid={})",
+            peer,
+            dur_ms,
+            e,
+            sid
+        ),
+        None => tracing::info!("TCP {} closed after {}ms (sid={})", peer, dur_ms, sid),
+    }
+}
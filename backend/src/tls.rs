@@ -0,0 +1,231 @@
+//! Native TLS termination for tunly-server (`--tls-cert` / `--tls-key`). The certificate is
+//! swapped in place on SIGHUP or when the files change; established connections keep the
+//! certificate they were opened with, so tunnels are not dropped.
+
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use axum::serve::Listener;
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+// Handshakes run off the accept loop but still should not linger
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often the certificate files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read a PEM certificate chain and private key into a key rustls can serve.
+pub fn load_certified_key(cert: &Path, key: &Path) -> io::Result<CertifiedKey> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(format!("{}: {}", cert.display(), e)))?;
+    if chain.is_empty() {
+        return Err(invalid(format!(
+            "{}: no certificates found",
+            cert.display()
+        )));
+    }
+    let key_der = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| invalid(format!("{}: {}", key.display(), e)))?;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key_der)
+        .map_err(|e| invalid(format!("{}: {}", key.display(), e)))?;
+    let certified = CertifiedKey::new(chain, signing_key);
+    certified.keys_match().map_err(|e| {
+        invalid(format!(
+            "{} does not match {}: {}",
+            key.display(),
+            cert.display(),
+            e
+        ))
+    })?;
+    Ok(certified)
+}
+
+/// Certificate that can be reloaded from its files while the server is running.
+pub struct ReloadableCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl fmt::Debug for ReloadableCert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadableCert")
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .finish()
+    }
+}
+
+impl ReloadableCert {
+    pub fn load(cert_path: PathBuf, key_path: PathBuf) -> io::Result<Self> {
+        let current = load_certified_key(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    /// Re-read the files; on error the certificate in use is kept.
+    pub fn reload(&self) -> io::Result<()> {
+        let fresh = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(fresh);
+        Ok(())
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert = std::fs::metadata(&self.cert_path).and_then(|m| m.modified());
+        let key = std::fs::metadata(&self.key_path).and_then(|m| m.modified());
+        cert.ok().zip(key.ok())
+    }
+
+    /// Reload on SIGHUP and whenever the files' modification times change.
+    pub fn watch(self: Arc<Self>) {
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            let mut hup =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+            let mut ticker = tokio::time::interval(WATCH_INTERVAL);
+            let mut seen = self.modified();
+            loop {
+                #[cfg(unix)]
+                let signalled = {
+                    let hup_recv = async {
+                        match hup.as_mut() {
+                            Some(hup) => hup.recv().await,
+                            None => std::future::pending().await,
+                        }
+                    };
+                    tokio::select! {
+                        _ = hup_recv => true,
+                        _ = ticker.tick() => false,
+                    }
+                };
+                #[cfg(not(unix))]
+                let signalled = {
+                    ticker.tick().await;
+                    false
+                };
+
+                let modified = self.modified();
+                if !signalled && modified == seen {
+                    continue;
+                }
+                seen = modified;
+                match self.reload() {
+                    Ok(()) => {
+                        tracing::info!("TLS certificate reloaded from {}", self.cert_path.display())
+                    }
+                    Err(e) => {
+                        tracing::error!("TLS reload failed, keeping current certificate: {}", e)
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// rustls server configuration serving whatever certificate `cert` currently holds.
+pub fn server_config(cert: Arc<ReloadableCert>) -> ServerConfig {
+    // Same crypto backend as the rest of the crate, whatever else is compiled in
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_no_client_auth()
+            .with_cert_resolver(cert);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    config
+}
+
+/// Listener that terminates TLS on the connections of an inner listener. Handshakes happen
+/// off the accept loop, so a slow client does not hold up others.
+///
+/// Serve it with `tap_io(|_| {})` to get `ConnectInfo<SocketAddr>`.
+pub struct TlsListener<L: Listener> {
+    rx: mpsc::Receiver<(TlsStream<L::Io>, L::Addr)>,
+    local_addr: io::Result<L::Addr>,
+}
+
+impl<L> TlsListener<L>
+where
+    L: Listener,
+    L::Addr: Clone + fmt::Debug + Send + 'static,
+{
+    pub fn new(mut inner: L, acceptor: TlsAcceptor) -> Self {
+        let local_addr = inner.local_addr();
+        let (tx, rx) = mpsc::channel(128);
+        tokio::spawn(async move {
+            loop {
+                let (io, addr) = tokio::select! {
+                    _ = tx.closed() => break,
+                    accepted = inner.accept() => accepted,
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(io)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => {
+                            tracing::debug!("TLS handshake with {:?} failed: {}", addr, e)
+                        }
+                        Err(_) => tracing::debug!("TLS handshake with {:?} timed out", addr),
+                    }
+                });
+            }
+        });
+        Self { rx, local_addr }
+    }
+}
+
+impl<L> Listener for TlsListener<L>
+where
+    L: Listener,
+    L::Io: AsyncRead + AsyncWrite + Unpin + Send,
+    L::Addr: Clone + Send + 'static,
+{
+    type Io = TlsStream<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.rx.recv().await {
+            Some(conn) => conn,
+            // The accept loop only stops once we are gone
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        match &self.local_addr {
+            Ok(addr) => Ok(addr.clone()),
+            Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Long-lived access tokens: JWTs signed with one of the server's keys, minted offline with
+//! `tunly-server token create` or by any issuer holding a configured signing key. Each one
+//! names its holder, may restrict the session ids it can use and how many tunnels it keeps
+//! open, and can be revoked on its own.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose, Engine as _};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::keys::{KeyError, KeySet};
+
+/// `kind` claim of access tokens; ephemeral `/token` JWTs have none.
+pub const ACCESS_KIND: &str = "access";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AccessClaims {
+    pub kind: String,
+    /// Holder, e.g. `alice@example.com`
+    pub sub: String,
+    pub iat: usize,
+    pub exp: usize,
+    pub jti: String,
+    /// Session ids or names the holder may use; `*` matches any run of characters. Empty
+    /// allows any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sids: Vec<String>,
+    /// Tunnels open at once with this token; 0 means unlimited
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub max_sessions: u32,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+impl AccessClaims {
+    pub fn new(sub: &str, ttl: Duration, sids: Vec<String>, max_sessions: u32) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut jti_bytes = [0u8; 16];
+        rand::rng().fill_bytes(&mut jti_bytes);
+        Self {
+            kind: ACCESS_KIND.to_string(),
+            sub: sub.to_string(),
+            iat: now as usize,
+            exp: now.saturating_add(ttl.as_secs()) as usize,
+            jti: general_purpose::URL_SAFE_NO_PAD.encode(jti_bytes),
+            sids,
+            max_sessions,
+        }
+    }
+
+    pub fn allows_sid(&self, sid: &str) -> bool {
+        self.sids.is_empty() || self.sids.iter().any(|p| glob_match(p, sid))
+    }
+
+    /// Key that owns names claimed with this token, so each holder keeps their own.
+    pub fn owner_key(&self) -> String {
+        format!("access:{}", self.sub)
+    }
+
+    pub fn sign(&self, keys: &KeySet) -> Result<String, KeyError> {
+        keys.sign(self)
+    }
+}
+
+/// Claims of a valid access token, or `None` for anything else (including ephemeral JWTs).
+pub fn verify(token: &str, keys: &KeySet) -> Option<AccessClaims> {
+    let claims = keys.verify::<AccessClaims>(token).ok()?;
+    (claims.kind == ACCESS_KIND).then_some(claims)
+}
+
+/// Match `value` against `pattern`, where `*` stands for any (possibly empty) run of
+/// characters.
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard at all
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Parse a lifetime like `3600`, `90m`, `12h` or `30d` (plain numbers are seconds).
+pub fn parse_ttl(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (digits, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => value.split_at(idx),
+        None => (value, "s"),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", value))?;
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => {
+            return Err(format!(
+                "invalid duration unit in {} (use s, m, h or d)",
+                value
+            ))
+        }
+    };
+    let secs = n
+        .checked_mul(scale)
+        .ok_or_else(|| format!("invalid duration: {}", value))?;
+    if secs == 0 {
+        return Err("duration must be positive".to_string());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// The access token a session connected with, kept so revocation and limits can find it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credential {
+    pub jti: String,
+    pub sub: String,
+}
+
+impl From<&AccessClaims> for Credential {
+    fn from(claims: &AccessClaims) -> Self {
+        Self {
+            jti: claims.jti.clone(),
+            sub: claims.sub.clone(),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use axum::http::{header, StatusCode};
+use axum_test::{TestResponse, TestServer};
+use base64::{engine::general_purpose, Engine as _};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tunly::access::{VisitorAuth, AUTH_COOKIE};
+use tunly::{
+    create_app, AppState, AuthMode, ClientToServer, Features, ProxyResponse, ServerToClient,
+    SessionState,
+};
+
+/// Server with one protected session whose mock client answers every request with 200,
+/// echoing the `authorization` and `cookie` headers it received as `x-seen-*`.
+async fn protected_server(sid: &str, auth: VisitorAuth) -> TestServer {
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, vec![7u8; 32]));
+    let (tx, mut rx) = mpsc::channel(16);
+    let mut session = SessionState::new(tx, Features::default());
+    session.visitor_auth = Some(auth);
+    let session = Arc::new(session);
+    state
+        .sessions
+        .write()
+        .await
+        .insert(sid.to_string(), session.clone());
+
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if let ServerToClient::ProxyRequest(req) = msg {
+                let seen = req
+                    .headers
+                    .iter()
+                    .filter(|(k, _)| k == "authorization" || k == "cookie")
+                    .map(|(k, v)| (format!("x-seen-{}", k), v.clone()))
+                    .collect();
+                if let Some(tx) = session.pending.lock().await.remove(&req.id) {
+                    let _ = tx.send(ClientToServer::ProxyResponse(ProxyResponse {
+                        id: req.id,
+                        status: 200,
+                        headers: seen,
+                        body_b64: String::new(),
+                        is_compressed: false,
+                    }));
+                }
+            }
+        }
+    });
+
+    let app = create_app(state).into_make_service_with_connect_info::<SocketAddr>();
+    TestServer::new(app).unwrap()
+}
+
+/// `name=value` of the login cookie set by `response`.
+fn login_cookie(response: &TestResponse) -> String {
+    let set_cookie = response
+        .iter_headers_by_name(header::SET_COOKIE)
+        .filter_map(|v| v.to_str().ok())
+        .find(|v| v.starts_with(AUTH_COOKIE))
+        .expect("login cookie")
+        .to_string();
+    set_cookie.split(';').next().unwrap().to_string()
+}
+
+#[test]
+fn test_parse_visitor_auth() {
+    assert_eq!(
+        VisitorAuth::parse("basic alice:s3cret:with:colons"),
+        Ok(VisitorAuth::Basic {
+            user: "alice".into(),
+            pass: "s3cret:with:colons".into()
+        })
+    );
+    assert_eq!(
+        VisitorAuth::parse("passphrase open sesame"),
+        Ok(VisitorAuth::Passphrase("open sesame".into()))
+    );
+    assert!(VisitorAuth::parse("basic alice").is_err());
+    assert!(VisitorAuth::parse("digest x").is_err());
+}
+
+#[tokio::test]
+async fn test_basic_auth_protects_session_and_log() {
+    let auth = VisitorAuth::Basic {
+        user: "alice".into(),
+        pass: "s3cret".into(),
+    };
+    let server = protected_server("locked", auth).await;
+
+    let response = server.get("/s/locked/app").await;
+    assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+    assert!(response
+        .header(header::WWW_AUTHENTICATE)
+        .to_str()
+        .unwrap()
+        .starts_with("Basic"));
+    assert_eq!(
+        server.get("/s/locked/_log").await.status_code(),
+        StatusCode::UNAUTHORIZED
+    );
+
+    let wrong = format!("Basic {}", general_purpose::STANDARD.encode("alice:nope"));
+    let response = server
+        .get("/s/locked/app")
+        .add_header(header::AUTHORIZATION, wrong)
+        .await;
+    assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+
+    let right = format!("Basic {}", general_purpose::STANDARD.encode("alice:s3cret"));
+    let response = server
+        .get("/s/locked/app")
+        .add_header(header::AUTHORIZATION, right)
+        .await;
+    assert_eq!(response.status_code(), StatusCode::OK);
+    let cookie = login_cookie(&response);
+
+    // The cookie alone is enough afterwards, including for the session log
+    let response = server
+        .get("/s/locked/static/app.js")
+        .add_header(header::COOKIE, cookie.clone())
+        .await;
+    assert_eq!(response.status_code(), StatusCode::OK);
+    let response = server
+        .get("/s/locked/_log")
+        .add_header(header::COOKIE, cookie)
+        .await;
+    assert_eq!(response.status_code(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_passphrase_login_sets_cookie() {
+    let server =
+        protected_server("secret-demo", VisitorAuth::Passphrase("open sesame".into())).await;
+
+    let response = server.get("/s/secret-demo/dashboard").await;
+    assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+    assert!(response.text().contains("/s/secret-demo/_tunly/auth"));
+
+    let response = server
+        .post("/s/secret-demo/_tunly/auth")
+        .form(&[
+            ("passphrase", "wrong"),
+            ("next", "/s/secret-demo/dashboard"),
+        ])
+        .await;
+    assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+
+    let response = server
+        .post("/s/secret-demo/_tunly/auth")
+        .form(&[
+            ("passphrase", "open sesame"),
+            ("next", "/s/secret-demo/dashboard"),
+        ])
+        .await;
+    assert_eq!(response.status_code(), StatusCode::SEE_OTHER);
+    assert_eq!(
+        response.header(header::LOCATION),
+        "/s/secret-demo/dashboard"
+    );
+    let cookie = login_cookie(&response);
+
+    let response = server
+        .get("/s/secret-demo/dashboard")
+        .add_header(header::COOKIE, cookie.clone())
+        .await;
+    assert_eq!(response.status_code(), StatusCode::OK);
+
+    // Redirects never leave the session
+    let response = server
+        .post("/s/secret-demo/_tunly/auth")
+        .form(&[
+            ("passphrase", "open sesame"),
+            ("next", "https://evil.example/"),
+        ])
+        .await;
+    assert_eq!(response.header(header::LOCATION), "/s/secret-demo/");
+
+    // A cookie for one session does not open another
+    let other = protected_server("other-demo", VisitorAuth::Passphrase("open sesame".into())).await;
+    let response = other
+        .get("/s/other-demo/")
+        .add_header(header::COOKIE, cookie)
+        .await;
+    assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_tunnel_credentials_not_forwarded() {
+    let auth = VisitorAuth::Basic {
+        user: "demo".into(),
+        pass: "hunter2".into(),
+    };
+    let server = protected_server("private-demo", auth).await;
+    let basic = format!("Basic {}", general_purpose::STANDARD.encode("demo:hunter2"));
+
+    let response = server
+        .get("/s/private-demo/")
+        .add_header(header::AUTHORIZATION, basic)
+        .add_header(header::COOKIE, "theme=dark")
+        .await;
+    assert_eq!(response.status_code(), StatusCode::OK);
+    assert!(response.maybe_header("x-seen-authorization").is_none());
+    assert_eq!(response.header("x-seen-cookie"), "theme=dark");
+
+    // The login cookie is dropped, the app's own cookies are kept
+    let cookie = login_cookie(&response);
+    let response = server
+        .get("/s/private-demo/")
+        .add_header(header::COOKIE, format!("theme=dark; {}; lang=en", cookie))
+        .await;
+    assert_eq!(response.status_code(), StatusCode::OK);
+    assert_eq!(response.header("x-seen-cookie"), "theme=dark; lang=en");
+}
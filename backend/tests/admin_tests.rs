@@ -0,0 +1,237 @@
+mod common;
+
+use axum::http::{header, StatusCode};
+use axum_test::TestServer;
+use common::spawn_server;
+use futures::StreamExt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tunly::admin::{MintRequest, MintedToken, Revoked, SessionInfo, Stats, TokenInfo};
+use tunly::{create_app, AccessLogEntry, AppState, AuthMode, CloseReason, Features, SessionState};
+
+const ADMIN: &str = "Bearer admin-secret";
+
+fn admin_state(auth_mode: AuthMode) -> AppState {
+    let mut state = AppState::new(auth_mode, vec![0u8; 32]);
+    state.settings_mut().admin_token = Some("admin-secret".to_string());
+    state
+}
+
+fn test_server(state: Arc<AppState>) -> TestServer {
+    let app = create_app(state).into_make_service_with_connect_info::<SocketAddr>();
+    TestServer::new(app).unwrap()
+}
+
+#[tokio::test]
+async fn test_admin_api_requires_token() {
+    let server = test_server(Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32])));
+    // Not configured: the API is not there at all
+    let response = server
+        .get("/admin/api/sessions")
+        .add_header(header::AUTHORIZATION, ADMIN)
+        .await;
+    assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+
+    let server = test_server(Arc::new(admin_state(AuthMode::Ephemeral)));
+    let response = server.get("/admin/api/sessions").await;
+    assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+    let response = server
+        .get("/admin/api/sessions")
+        .add_header(header::AUTHORIZATION, "Bearer wrong")
+        .await;
+    assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_admin_lists_sessions_and_log() {
+    let state = Arc::new(admin_state(AuthMode::Ephemeral));
+    let (tx, _rx) = mpsc::channel(16);
+    let mut session = SessionState::new(tx, Features::default());
+    session.client_ip = Some("203.0.113.7".to_string());
+    let session = Arc::new(session);
+    session
+        .log_access(
+            AccessLogEntry {
+                method: "GET".to_string(),
+                uri: "/hello".to_string(),
+                status: 200,
+                dur_ms: 3,
+            },
+            50,
+        )
+        .await;
+    state
+        .sessions
+        .write()
+        .await
+        .insert("listed".to_string(), session);
+    let server = test_server(state);
+
+    let list: Vec<SessionInfo> = server
+        .get("/admin/api/sessions")
+        .add_header(header::AUTHORIZATION, ADMIN)
+        .await
+        .json();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].sid, "listed");
+    assert_eq!(list[0].client_ip.as_deref(), Some("203.0.113.7"));
+    assert!(list[0].created_at > 0);
+
+    let log: serde_json::Value = server
+        .get("/admin/api/sessions/listed/log")
+        .add_header(header::AUTHORIZATION, ADMIN)
+        .await
+        .json();
+    assert_eq!(log["total"], 1);
+    assert_eq!(log["entries"][0]["uri"], "/hello");
+    assert_eq!(log["entries"][0]["status"], 200);
+
+    let response = server
+        .get("/admin/api/sessions/missing")
+        .add_header(header::AUTHORIZATION, ADMIN)
+        .await;
+    assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_admin_revokes_tokens() {
+    let state = Arc::new(admin_state(AuthMode::Ephemeral));
+    {
+        let exp = Instant::now() + Duration::from_secs(300);
+        let mut issued = state.issued_tokens.lock().await;
+        for (jti, sid) in [("a", "one"), ("b", "one"), ("c", "two")] {
+            issued.insert(
+                jti.to_string(),
+                ("127.0.0.1".to_string(), exp, sid.to_string()),
+            );
+        }
+    }
+    let server = test_server(state.clone());
+
+    let tokens: Vec<TokenInfo> = server
+        .get("/admin/api/tokens")
+        .add_header(header::AUTHORIZATION, ADMIN)
+        .await
+        .json();
+    assert_eq!(tokens.len(), 3);
+
+    let response = server
+        .delete("/admin/api/tokens/c")
+        .add_header(header::AUTHORIZATION, ADMIN)
+        .await;
+    assert_eq!(response.status_code(), StatusCode::NO_CONTENT);
+    assert!(!state.issued_tokens.lock().await.contains_key("c"));
+
+    let revoked: Revoked = server
+        .delete("/admin/api/tokens?sid=one")
+        .add_header(header::AUTHORIZATION, ADMIN)
+        .await
+        .json();
+    assert_eq!(revoked.revoked, 2);
+    assert!(state.issued_tokens.lock().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_admin_kill_disconnects_client() {
+    let state = Arc::new(admin_state(AuthMode::Fixed("fixed-token".to_string())));
+    let addr = spawn_server(state.clone()).await;
+
+    let mut req = format!("ws://{}/ws?sid=doomed", addr)
+        .into_client_request()
+        .unwrap();
+    req.headers_mut()
+        .insert("Authorization", "Bearer fixed-token".parse().unwrap());
+    let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    for _ in 0..50 {
+        if state.sessions.read().await.contains_key("doomed") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let server = test_server(state.clone());
+    let response = server
+        .delete("/admin/api/sessions/doomed")
+        .add_header(header::AUTHORIZATION, ADMIN)
+        .await;
+    assert_eq!(response.status_code(), StatusCode::NO_CONTENT);
+
+    // The tunnel connection ends, saying why, and the sid cannot be resumed
+    let closed = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(Ok(msg)) = ws.next().await {
+            if let Message::Close(frame) = msg {
+                return frame.map(|f| u16::from(f.code));
+            }
+        }
+        None
+    })
+    .await;
+    assert_eq!(closed.unwrap(), Some(CloseReason::Terminated.code()));
+
+    // Reconnecting with the same token is refused while the sid is blocked
+    assert!(state.reservations.lock().await["doomed"].blocked);
+    let mut req = format!("ws://{}/ws?sid=doomed", addr)
+        .into_client_request()
+        .unwrap();
+    req.headers_mut()
+        .insert("Authorization", "Bearer fixed-token".parse().unwrap());
+    match tokio_tungstenite::connect_async(req).await {
+        Err(WsError::Http(resp)) => assert_eq!(resp.status().as_u16(), 409),
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+}
+
+#[tokio::test]
+async fn test_admin_mints_unbound_token() {
+    let state = Arc::new(admin_state(AuthMode::Ephemeral));
+    let addr = spawn_server(state.clone()).await;
+
+    let server = test_server(state.clone());
+    let minted: MintedToken = server
+        .post("/admin/api/tokens")
+        .add_header(header::AUTHORIZATION, ADMIN)
+        .json(&MintRequest {
+            sid: Some("minted".to_string()),
+            ttl_secs: Some(60),
+            ip: None,
+        })
+        .await
+        .json();
+    assert_eq!(minted.session, "minted");
+    assert_eq!(minted.expires_in, 60);
+    assert!(state.issued_tokens.lock().await.contains_key(&minted.jti));
+
+    // No IP binding: the token works from wherever the client connects
+    let mut req = format!("ws://{}/ws?sid=minted", addr)
+        .into_client_request()
+        .unwrap();
+    req.headers_mut().insert(
+        "Authorization",
+        format!("Bearer {}", minted.token).parse().unwrap(),
+    );
+    assert!(tokio_tungstenite::connect_async(req).await.is_ok());
+
+    let stats: Stats = server
+        .get("/admin/api/stats")
+        .add_header(header::AUTHORIZATION, ADMIN)
+        .await
+        .json();
+    assert_eq!(stats.outstanding_tokens, 0);
+    assert!(!stats.draining);
+}
+
+#[tokio::test]
+async fn test_admin_mint_rejected_in_fixed_mode() {
+    let state = Arc::new(admin_state(AuthMode::Fixed("fixed-token".to_string())));
+    let server = test_server(state);
+    let response = server
+        .post("/admin/api/tokens")
+        .add_header(header::AUTHORIZATION, ADMIN)
+        .json(&MintRequest::default())
+        .await;
+    assert_eq!(response.status_code(), StatusCode::CONFLICT);
+}
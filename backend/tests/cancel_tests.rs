@@ -0,0 +1,166 @@
+mod common;
+
+use common::spawn_server;
+use futures::StreamExt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tunly::{
+    AppState, AuthMode, ClientToServer, ErrorMessage, Features, MessageCode, ServerToClient,
+    SessionState,
+};
+
+/// A session whose client never answers; returns what the server sends it.
+async fn silent_session(state: &AppState, sid: &str) -> mpsc::Receiver<ServerToClient> {
+    let (tx, rx) = mpsc::channel(16);
+    let features = Features {
+        cancel: true,
+        ..Features::default()
+    };
+    state
+        .sessions
+        .write()
+        .await
+        .insert(sid.to_string(), Arc::new(SessionState::new(tx, features)));
+    rx
+}
+
+/// Id of the proxied request, then of the `cancel` that follows it.
+async fn request_then_cancel(rx: &mut mpsc::Receiver<ServerToClient>) -> (u64, u64) {
+    let wait = tokio::time::timeout(Duration::from_secs(5), async {
+        let Some(ServerToClient::ProxyRequest(req)) = rx.recv().await else {
+            panic!("expected a proxy request");
+        };
+        let Some(ServerToClient::Cancel(cancel)) = rx.recv().await else {
+            panic!("expected a cancel");
+        };
+        (req.id, cancel.id)
+    });
+    wait.await.expect("no cancel sent")
+}
+
+#[tokio::test]
+async fn test_upstream_timeout_cancels_request() {
+    let mut state = AppState::new(AuthMode::Ephemeral, vec![0u8; 32]);
+    state.settings_mut().limits.upstream_timeout_secs = 1;
+    let state = Arc::new(state);
+    let mut rx = silent_session(&state, "slow").await;
+    let addr = spawn_server(state.clone()).await;
+
+    let status = reqwest::get(format!("http://{}/s/slow/report", addr))
+        .await
+        .unwrap()
+        .status();
+    assert_eq!(status.as_u16(), 504);
+    let (request, cancelled) = request_then_cancel(&mut rx).await;
+    assert_eq!(request, cancelled);
+    assert!(state.sessions.read().await["slow"]
+        .pending
+        .lock()
+        .await
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_visitor_disconnect_cancels_request() {
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]));
+    let mut rx = silent_session(&state, "slow").await;
+    let addr = spawn_server(state.clone()).await;
+
+    // The visitor gives up long before the upstream timeout
+    let visitor = reqwest::Client::builder()
+        .timeout(Duration::from_millis(300))
+        .build()
+        .unwrap();
+    assert!(visitor
+        .get(format!("http://{}/s/slow/export", addr))
+        .send()
+        .await
+        .is_err());
+    let (request, cancelled) = request_then_cancel(&mut rx).await;
+    assert_eq!(request, cancelled);
+}
+
+#[tokio::test]
+async fn test_client_disconnect_fails_waiting_requests() {
+    let state = Arc::new(AppState::new(
+        AuthMode::Fixed("fixed-token".to_string()),
+        vec![0u8; 32],
+    ));
+    let addr = spawn_server(state.clone()).await;
+    let mut req = format!("ws://{}/ws?sid=flaky", addr)
+        .into_client_request()
+        .unwrap();
+    req.headers_mut()
+        .insert("Authorization", "Bearer fixed-token".parse().unwrap());
+    let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+
+    let visitor = tokio::spawn(async move {
+        let start = Instant::now();
+        let resp = reqwest::get(format!("http://{}/s/flaky/", addr))
+            .await
+            .unwrap();
+        (resp.status().as_u16(), start.elapsed())
+    });
+    // Drop the tunnel once the request has reached the client
+    while let Some(Ok(msg)) = ws.next().await {
+        if matches!(msg, Message::Text(_) | Message::Binary(_)) {
+            break;
+        }
+    }
+    drop(ws);
+
+    let (status, elapsed) = visitor.await.unwrap();
+    assert_eq!(status, 502);
+    assert!(elapsed < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn test_answered_request_is_not_cancelled() {
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]));
+    let (tx, mut rx) = mpsc::channel(16);
+    let features = Features {
+        cancel: true,
+        notices: true,
+        ..Features::default()
+    };
+    let session = Arc::new(SessionState::new(tx, features));
+    state
+        .sessions
+        .write()
+        .await
+        .insert("down".to_string(), session.clone());
+    let addr = spawn_server(state).await;
+
+    // The mock client reports that the local target is unreachable
+    let (seen_tx, mut seen) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if let ServerToClient::ProxyRequest(req) = &msg {
+                if let Some(tx) = session.pending.lock().await.remove(&req.id) {
+                    let _ = tx.send(ClientToServer::Error(ErrorMessage {
+                        id: Some(req.id),
+                        code: MessageCode::UpstreamUnreachable,
+                        message: "connection refused".to_string(),
+                    }));
+                }
+            }
+            let _ = seen_tx.send(msg);
+        }
+    });
+
+    let status = reqwest::get(format!("http://{}/s/down/", addr))
+        .await
+        .unwrap()
+        .status();
+    assert_eq!(status.as_u16(), 502);
+    assert!(matches!(
+        seen.recv().await,
+        Some(ServerToClient::ProxyRequest(_))
+    ));
+    // A stray cancel would follow right after the answer
+    let next = tokio::time::timeout(Duration::from_millis(300), seen.recv()).await;
+    assert!(next.is_err(), "unexpected message: {:?}", next);
+}
@@ -0,0 +1,12 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tunly::{create_app, AppState};
+
+/// Serve `state` on a free loopback port.
+pub async fn spawn_server(state: Arc<AppState>) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = create_app(state).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    addr
+}
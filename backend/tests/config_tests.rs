@@ -0,0 +1,100 @@
+use axum::http::StatusCode;
+use axum_test::TestServer;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tunly::config::{ConfigFile, Limits};
+use tunly::{create_app, AppState, AuthMode};
+
+fn parse(text: &str) -> Result<ConfigFile, String> {
+    let path = std::env::temp_dir().join(format!(
+        "tunly-config-{}-{}.toml",
+        std::process::id(),
+        text.len()
+    ));
+    std::fs::write(&path, text).unwrap();
+    let result = ConfigFile::load(&path).map_err(|e| e.to_string());
+    let _ = std::fs::remove_file(&path);
+    result
+}
+
+#[test]
+fn test_example_config_matches_defaults() {
+    let example = Path::new(env!("CARGO_MANIFEST_DIR")).join("tunly.example.toml");
+    let file = ConfigFile::load(&example).unwrap();
+    assert_eq!(file.limits, Limits::default());
+    assert_eq!(file.server.port, Some(8080));
+    assert!(file.settings().unwrap().ip_filter.is_empty());
+}
+
+#[test]
+fn test_config_sections() {
+    let file = parse(
+        r#"
+        [server]
+        domain = "tunnel.example.com"
+
+        [limits]
+        proxy_rate_max = 5
+        upstream_timeout_secs = 10
+
+        [policy]
+        internal_key = "k"
+        trusted_proxies = ["10.0.0.0/8"]
+        deny_cidr = ["203.0.113.7"]
+        "#,
+    )
+    .unwrap();
+    let settings = file.settings().unwrap();
+    assert_eq!(settings.limits.proxy_rate_max, 5);
+    assert_eq!(settings.upstream_timeout().as_secs(), 10);
+    // Unset limits keep their defaults
+    assert_eq!(settings.limits.token_ttl_secs, 300);
+    assert_eq!(settings.internal_key.as_deref(), Some("k"));
+    assert_eq!(settings.trusted_proxies.len(), 1);
+    assert_eq!(settings.ip_filter.deny.len(), 1);
+
+    // Only [server] and [auth] changes need a restart
+    let mut reloaded = file.clone();
+    reloaded.limits.proxy_rate_max = 50;
+    assert!(!file.needs_restart(&reloaded));
+    reloaded.server.domain = None;
+    assert!(file.needs_restart(&reloaded));
+}
+
+#[test]
+fn test_invalid_config_is_rejected() {
+    assert!(parse("[limits]\nproxy_rate_maximum = 5\n")
+        .unwrap_err()
+        .contains("proxy_rate_maximum"));
+    assert!(parse("[policy]\nallow_cidr = [\"10.0.0.0/40\"]\n")
+        .unwrap_err()
+        .contains("10.0.0.0/40"));
+    assert!(parse("[limits]\nproxy_rate_window_secs = 0\n").is_err());
+}
+
+#[tokio::test]
+async fn test_reloaded_limits_apply_without_restart() {
+    let mut state = AppState::new(AuthMode::Ephemeral, vec![0u8; 32]);
+    state.settings_mut().limits.proxy_rate_max = 2;
+    let state = Arc::new(state);
+    let app = create_app(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+    let server = TestServer::new(app).unwrap();
+
+    for _ in 0..2 {
+        let status = server.get("/s/none/").await.status_code();
+        assert_ne!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+    assert_eq!(
+        server.get("/s/none/").await.status_code(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+
+    let mut settings = (*state.settings()).clone();
+    settings.limits.proxy_rate_max = 10;
+    state.replace_settings(settings);
+    assert_ne!(
+        server.get("/s/none/").await.status_code(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+}
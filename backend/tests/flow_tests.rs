@@ -0,0 +1,233 @@
+mod common;
+
+use common::spawn_server;
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, Semaphore};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tunly::flow::{self, RecvWindow, SendWindows};
+use tunly::{
+    frame, AppState, AuthMode, BodyChunk, ClientToServer, Credit, ResponseStart, ServerToClient,
+    StreamEnd, FEATURES_HEADER, STREAM_BUFFER_CHUNKS, STREAM_CHUNK_SIZE,
+};
+
+#[test]
+fn test_recv_window_credits_half_windows() {
+    let mut window = RecvWindow::new(7, true);
+    let credits: Vec<_> = (0..STREAM_BUFFER_CHUNKS)
+        .filter_map(|_| window.taken())
+        .collect();
+    let half = (STREAM_BUFFER_CHUNKS / 2) as u32;
+    assert_eq!(
+        credits,
+        vec![
+            Credit {
+                id: 7,
+                chunks: half
+            };
+            2
+        ]
+    );
+
+    // Peers without `credit` are never sent any
+    let mut legacy = RecvWindow::new(7, false);
+    assert!((0..STREAM_BUFFER_CHUNKS).all(|_| legacy.taken().is_none()));
+}
+
+#[tokio::test]
+async fn test_send_window_waits_for_credit() {
+    let windows = SendWindows::default();
+    let window = windows.open(3, true);
+    for _ in 0..STREAM_BUFFER_CHUNKS {
+        assert!(window.reserve().await);
+    }
+    let blocked = tokio::time::timeout(Duration::from_millis(50), window.reserve()).await;
+    assert!(blocked.is_err());
+
+    // Granting more than was spent does not grow the window
+    windows.grant(&Credit {
+        id: 3,
+        chunks: 1000,
+    });
+    for _ in 0..STREAM_BUFFER_CHUNKS {
+        assert!(window.reserve().await);
+    }
+    let blocked = tokio::time::timeout(Duration::from_millis(50), window.reserve()).await;
+    assert!(blocked.is_err());
+
+    // A dropped stream releases its sender
+    windows.close(3);
+    assert!(!window.reserve().await);
+}
+
+#[tokio::test]
+async fn test_offer_refuses_past_the_window() {
+    let (tx, mut rx) = mpsc::channel(flow::RECV_BUFFER);
+    for i in 0..STREAM_BUFFER_CHUNKS {
+        assert!(flow::offer(&tx, i).is_ok());
+    }
+    assert_eq!(flow::offer(&tx, 99), Err(99));
+
+    // Taking a chunk off makes room for one more
+    rx.recv().await;
+    assert!(flow::offer(&tx, 16).is_ok());
+    assert_eq!(flow::offer(&tx, 99), Err(99));
+    // The slot kept for the end is still free
+    assert!(tx.try_send(100).is_ok());
+}
+
+/// A fake tunnel client on session `flow` speaking `features`; returns what the server sends
+/// and a sender for its replies.
+async fn fake_client(
+    addr: SocketAddr,
+    features: &str,
+) -> (mpsc::Receiver<ServerToClient>, mpsc::Sender<ClientToServer>) {
+    let mut req = format!("ws://{}/ws?sid=flow", addr)
+        .into_client_request()
+        .unwrap();
+    req.headers_mut()
+        .insert("Authorization", "Bearer fixed-token".parse().unwrap());
+    req.headers_mut()
+        .insert(FEATURES_HEADER, features.parse().unwrap());
+    let (ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    let (mut ws_tx, mut ws_rx) = ws.split();
+
+    let (in_tx, in_rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        while let Some(Ok(Message::Binary(bin))) = ws_rx.next().await {
+            let msg = frame::decode_binary::<ServerToClient>(&bin).unwrap();
+            if in_tx.send(msg).await.is_err() {
+                return;
+            }
+        }
+    });
+    let (out_tx, mut out_rx) = mpsc::channel::<ClientToServer>(64);
+    tokio::spawn(async move {
+        while let Some(msg) = out_rx.recv().await {
+            let bin = frame::encode_binary(msg).unwrap();
+            if ws_tx.send(Message::Binary(bin.into())).await.is_err() {
+                return;
+            }
+        }
+    });
+    (in_rx, out_tx)
+}
+
+fn flow_server() -> AppState {
+    AppState::new(AuthMode::Fixed("fixed-token".to_string()), vec![0u8; 32])
+}
+
+#[tokio::test]
+async fn test_stalled_visitor_does_not_block_the_tunnel() {
+    let addr = spawn_server(Arc::new(flow_server())).await;
+    // A client that ignores flow control and pushes a large body as fast as it can
+    let (mut inbox, out) = fake_client(addr, "stream,binary").await;
+
+    // This visitor asks for the large body and never reads it
+    let mut stalled = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stalled
+        .write_all(b"GET /s/flow/big HTTP/1.1\r\nHost: tunnel\r\n\r\n")
+        .await
+        .unwrap();
+
+    let client = tokio::spawn(async move {
+        let piece = vec![7u8; STREAM_CHUNK_SIZE];
+        while let Some(msg) = inbox.recv().await {
+            let ServerToClient::RequestStart(start) = msg else {
+                continue;
+            };
+            let id = start.id;
+            let head = ClientToServer::ResponseStart(ResponseStart {
+                id,
+                status: 200,
+                headers: vec![],
+            });
+            out.send(head).await.unwrap();
+            if start.uri.ends_with("/big") {
+                let out = out.clone();
+                let piece = piece.clone();
+                tokio::spawn(async move {
+                    for _ in 0..256 {
+                        let chunk = ClientToServer::ResponseBody(BodyChunk::new(id, &piece));
+                        if out.send(chunk).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            } else {
+                let chunk = ClientToServer::ResponseBody(BodyChunk::new(id, b"small"));
+                out.send(chunk).await.unwrap();
+                let end = ClientToServer::ResponseEnd(StreamEnd { id, error: None });
+                out.send(end).await.unwrap();
+            }
+        }
+    });
+
+    // Everyone else on the tunnel is still served
+    let small = tokio::time::timeout(Duration::from_secs(10), async {
+        reqwest::get(format!("http://{}/s/flow/small", addr))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+    })
+    .await
+    .expect("tunnel stalled behind the unread body");
+    assert_eq!(small, "small");
+    client.abort();
+}
+
+#[tokio::test]
+async fn test_credit_paces_a_large_body() {
+    let addr = spawn_server(Arc::new(flow_server())).await;
+    let (mut inbox, out) = fake_client(addr, "stream,binary,credit").await;
+
+    let chunks = 4 * STREAM_BUFFER_CHUNKS;
+    let client = tokio::spawn(async move {
+        let credits = Arc::new(Semaphore::new(STREAM_BUFFER_CHUNKS));
+        while let Some(msg) = inbox.recv().await {
+            match msg {
+                ServerToClient::RequestStart(start) => {
+                    let id = start.id;
+                    let head = ClientToServer::ResponseStart(ResponseStart {
+                        id,
+                        status: 200,
+                        headers: vec![],
+                    });
+                    out.send(head).await.unwrap();
+                    let (out, credits) = (out.clone(), credits.clone());
+                    tokio::spawn(async move {
+                        for i in 0..chunks {
+                            credits.acquire().await.unwrap().forget();
+                            let piece = vec![i as u8; STREAM_CHUNK_SIZE];
+                            let chunk = ClientToServer::ResponseBody(BodyChunk::new(id, &piece));
+                            out.send(chunk).await.unwrap();
+                        }
+                        let end = ClientToServer::ResponseEnd(StreamEnd { id, error: None });
+                        out.send(end).await.unwrap();
+                    });
+                }
+                ServerToClient::Credit(credit) => credits.add_permits(credit.chunks as usize),
+                _ => {}
+            }
+        }
+    });
+
+    let body = reqwest::get(format!("http://{}/s/flow/big", addr))
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    assert_eq!(body.len(), chunks * STREAM_CHUNK_SIZE);
+    assert!(body
+        .chunks(STREAM_CHUNK_SIZE)
+        .enumerate()
+        .all(|(i, c)| c.iter().all(|b| *b == i as u8)));
+    client.abort();
+}
@@ -0,0 +1,155 @@
+use axum::http::{HeaderMap, HeaderValue};
+use axum::serve::ListenerExt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tunly::access::{parse_net_list, IpFilter};
+use tunly::forwarded::{client_ip, proto};
+use tunly::proxy_protocol::ProxyProtocolListener;
+use tunly::{create_app, AppState, AuthMode};
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for (name, value) in pairs {
+        map.append(*name, HeaderValue::from_str(value).unwrap());
+    }
+    map
+}
+
+#[test]
+fn test_forwarded_for_is_only_believed_from_trusted_proxies() {
+    let trusted = parse_net_list("10.0.0.0/8").unwrap();
+    let spoofed = headers(&[("x-forwarded-for", "1.2.3.4")]);
+
+    // Direct visitors cannot claim another address
+    assert_eq!(
+        client_ip(&[], ip("198.51.100.9"), &spoofed),
+        ip("198.51.100.9")
+    );
+    assert_eq!(
+        client_ip(&trusted, ip("198.51.100.9"), &spoofed),
+        ip("198.51.100.9")
+    );
+
+    // Right to left, stopping at the first hop that is not one of our proxies
+    let chain = headers(&[("x-forwarded-for", "1.2.3.4, 203.0.113.7, 10.0.0.2")]);
+    assert_eq!(
+        client_ip(&trusted, ip("10.0.0.1"), &chain),
+        ip("203.0.113.7")
+    );
+
+    // Repeated headers form one list
+    let split = headers(&[
+        ("x-forwarded-for", "1.2.3.4"),
+        ("x-forwarded-for", "203.0.113.7"),
+    ]);
+    assert_eq!(
+        client_ip(&trusted, ip("10.0.0.1"), &split),
+        ip("203.0.113.7")
+    );
+
+    // A garbled hop ends the chain at the last proxy
+    let garbled = headers(&[("x-forwarded-for", "203.0.113.7, nonsense")]);
+    assert_eq!(
+        client_ip(&trusted, ip("10.0.0.1"), &garbled),
+        ip("10.0.0.1")
+    );
+}
+
+#[test]
+fn test_rfc7239_forwarded_header() {
+    let trusted = parse_net_list("10.0.0.0/8").unwrap();
+    let fwd = headers(&[(
+        "forwarded",
+        "for=1.2.3.4, for=\"[2001:db8:cafe::17]:4711\";proto=https, for=10.0.0.5",
+    )]);
+    assert_eq!(
+        client_ip(&trusted, ip("10.0.0.1"), &fwd),
+        ip("2001:db8:cafe::17")
+    );
+
+    // Forwarded takes precedence over X-Forwarded-For
+    let both = headers(&[
+        ("forwarded", "for=\"198.51.100.1:8080\""),
+        ("x-forwarded-for", "203.0.113.7"),
+    ]);
+    assert_eq!(
+        client_ip(&trusted, ip("10.0.0.1"), &both),
+        ip("198.51.100.1")
+    );
+
+    let hidden = headers(&[("forwarded", "for=_hidden;by=10.0.0.1")]);
+    assert_eq!(client_ip(&trusted, ip("10.0.0.1"), &hidden), ip("10.0.0.1"));
+}
+
+#[test]
+fn test_forwarded_proto_from_nearest_trusted_proxy() {
+    let trusted = parse_net_list("10.0.0.0/8").unwrap();
+    let fwd = headers(&[(
+        "forwarded",
+        "for=1.2.3.4;proto=http, for=10.0.0.5;proto=HTTPS",
+    )]);
+    assert_eq!(
+        proto(&trusted, ip("10.0.0.1"), &fwd).as_deref(),
+        Some("https")
+    );
+    let xfp = headers(&[("x-forwarded-proto", "https")]);
+    assert_eq!(
+        proto(&trusted, ip("10.0.0.1"), &xfp).as_deref(),
+        Some("https")
+    );
+    assert_eq!(proto(&trusted, ip("203.0.113.7"), &xfp), None);
+}
+
+/// Send a PROXY header and a request for an unknown session; the server denies
+/// 198.51.100.0/24, so 403 vs 503 tells which address it saw.
+async fn status_behind_proxy(addr: SocketAddr, header: &[u8]) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(header).await.unwrap();
+    stream
+        .write_all(b"GET /s/nosuch/ HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    // A rejected connection may be reset rather than closed
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response).await;
+    response.lines().next().unwrap_or_default().to_string()
+}
+
+#[tokio::test]
+async fn test_proxy_protocol_listener() {
+    let mut state = AppState::new(AuthMode::Ephemeral, vec![0u8; 32]);
+    state.settings_mut().ip_filter = IpFilter {
+        allow: vec![],
+        deny: parse_net_list("198.51.100.0/24").unwrap(),
+    };
+    let app = create_app(Arc::new(state)).into_make_service_with_connect_info::<SocketAddr>();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let listener = ProxyProtocolListener::new(listener, |_| true).unwrap();
+    tokio::spawn(async move { axum::serve(listener.tap_io(|_| {}), app).await.unwrap() });
+
+    // v1
+    let status = status_behind_proxy(addr, b"PROXY TCP4 198.51.100.7 10.0.0.1 40000 80\r\n").await;
+    assert!(status.contains("403"), "{}", status);
+    let status = status_behind_proxy(addr, b"PROXY TCP4 203.0.113.1 10.0.0.1 40000 80\r\n").await;
+    assert!(status.contains("503"), "{}", status);
+
+    // v2, PROXY command over TCP/IPv4
+    let mut v2 = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    v2.extend_from_slice(&[0x21, 0x11, 0, 12]);
+    v2.extend_from_slice(&[198, 51, 100, 8, 10, 0, 0, 1]);
+    v2.extend_from_slice(&40000u16.to_be_bytes());
+    v2.extend_from_slice(&80u16.to_be_bytes());
+    let status = status_behind_proxy(addr, &v2).await;
+    assert!(status.contains("403"), "{}", status);
+
+    // Without a header the connection is dropped
+    let status = status_behind_proxy(addr, b"").await;
+    assert!(status.is_empty(), "{}", status);
+}
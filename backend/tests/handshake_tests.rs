@@ -0,0 +1,115 @@
+mod common;
+
+use common::spawn_server;
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tunly::{
+    AppState, AuthMode, ClientToServer, CloseReason, Hello, ServerToClient, SessionLimits, Welcome,
+    PROTOCOL_HEADER, PROTOCOL_VERSION,
+};
+
+type Ws =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+fn test_state() -> AppState {
+    AppState::new(AuthMode::Fixed("fixed-token".to_string()), vec![0u8; 32])
+}
+
+async fn connect(state: AppState, sid: &str) -> (Ws, SocketAddr) {
+    let addr = spawn_server(Arc::new(state)).await;
+
+    let mut req = format!("ws://{}/ws?sid={}", addr, sid)
+        .into_client_request()
+        .unwrap();
+    req.headers_mut()
+        .insert("Authorization", "Bearer fixed-token".parse().unwrap());
+    let (ws, resp) = tokio_tungstenite::connect_async(req).await.unwrap();
+    assert_eq!(
+        resp.headers()[PROTOCOL_HEADER],
+        PROTOCOL_VERSION.to_string().as_str()
+    );
+    (ws, addr)
+}
+
+fn hello(protocol_version: u32, compression: &[&str]) -> Message {
+    let msg = ClientToServer::Hello(Hello {
+        protocol_version,
+        client_version: "9.9.9".to_string(),
+        features: vec!["stream".to_string()],
+        compression: compression.iter().map(|c| c.to_string()).collect(),
+    });
+    Message::Text(serde_json::to_string(&msg).unwrap().into())
+}
+
+/// The server's answer to a hello: its welcome, or the code it closed the connection with.
+async fn answer(ws: &mut Ws) -> Result<Welcome, Option<u16>> {
+    let wait = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(Ok(msg)) = ws.next().await {
+            match msg {
+                Message::Text(txt) => {
+                    if let Ok(ServerToClient::Welcome(welcome)) = serde_json::from_str(&txt) {
+                        return Ok(welcome);
+                    }
+                }
+                Message::Close(frame) => return Err(frame.map(|f| u16::from(f.code))),
+                _ => {}
+            }
+        }
+        Err(None)
+    });
+    wait.await.expect("no answer to hello")
+}
+
+#[tokio::test]
+async fn test_welcome_announces_urls_and_limits() {
+    let (mut ws, addr) = connect(test_state(), "demo").await;
+    ws.send(hello(PROTOCOL_VERSION, &["zlib"])).await.unwrap();
+    let welcome = answer(&mut ws).await.unwrap();
+    assert_eq!(welcome.protocol_version, PROTOCOL_VERSION);
+    assert_eq!(welcome.session, "demo");
+    assert_eq!(welcome.compression, vec!["zlib"]);
+    assert_eq!(
+        welcome.public_urls,
+        vec![format!("http://{}/s/demo/", addr)]
+    );
+    assert_eq!(
+        welcome.limits,
+        SessionLimits::from_limits(&Default::default())
+    );
+
+    // The configured public URL wins over the Host header, and Host routing adds the own host
+    let mut state = test_state();
+    state.public_url = Some("https://tunnel.example.com".to_string());
+    state.domain = Some("tunnel.example.com".to_string());
+    let (mut ws, _) = connect(state, "acme").await;
+    // A newer client gets the version both sides speak
+    ws.send(hello(PROTOCOL_VERSION + 1, &["br", "zlib"]))
+        .await
+        .unwrap();
+    let welcome = answer(&mut ws).await.unwrap();
+    assert_eq!(welcome.protocol_version, PROTOCOL_VERSION);
+    assert_eq!(
+        welcome.public_urls,
+        vec![
+            "https://tunnel.example.com/s/acme/".to_string(),
+            "https://acme.tunnel.example.com/".to_string(),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_incompatible_hello_closes_connection() {
+    let incompatible = Some(CloseReason::Incompatible.code());
+
+    let (mut ws, _) = connect(test_state(), "old").await;
+    ws.send(hello(1, &["zlib"])).await.unwrap();
+    assert_eq!(answer(&mut ws).await.err(), Some(incompatible));
+
+    let (mut ws, _) = connect(test_state(), "plain").await;
+    ws.send(hello(PROTOCOL_VERSION, &["br"])).await.unwrap();
+    assert_eq!(answer(&mut ws).await.err(), Some(incompatible));
+}
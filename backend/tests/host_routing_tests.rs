@@ -0,0 +1,102 @@
+use axum::http::StatusCode;
+use axum_test::TestServer;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tunly::{
+    create_app, generate_session_id, is_dns_label, sid_from_host, AppState, AuthMode,
+    ClientToServer, Features, ProxyResponse, ServerToClient, SessionState,
+};
+
+/// Server with Host routing on `tunnel.test` and one session answering with a redirect.
+async fn host_routed_server(sid: &str) -> (TestServer, mpsc::Receiver<String>) {
+    let mut state = AppState::new(AuthMode::Ephemeral, vec![0u8; 32]);
+    state.domain = Some("tunnel.test".to_string());
+    let state = Arc::new(state);
+
+    let (tx, mut rx) = mpsc::channel(16);
+    let session = Arc::new(SessionState::new(tx, Features::default()));
+    state
+        .sessions
+        .write()
+        .await
+        .insert(sid.to_string(), session.clone());
+
+    // Mock client: report the URI it was asked for and redirect to an absolute path
+    let (seen_tx, seen_rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if let ServerToClient::ProxyRequest(req) = msg {
+                let _ = seen_tx.send(req.uri.clone()).await;
+                if let Some(tx) = session.pending.lock().await.remove(&req.id) {
+                    let _ = tx.send(ClientToServer::ProxyResponse(ProxyResponse {
+                        id: req.id,
+                        status: 302,
+                        headers: vec![("location".into(), "/login".into())],
+                        body_b64: String::new(),
+                        is_compressed: false,
+                    }));
+                }
+            }
+        }
+    });
+
+    let app = create_app(state).into_make_service_with_connect_info::<SocketAddr>();
+    (TestServer::new(app).unwrap(), seen_rx)
+}
+
+#[test]
+fn test_sid_from_host() {
+    assert_eq!(
+        sid_from_host("abc123.tunnel.test", "tunnel.test").as_deref(),
+        Some("abc123")
+    );
+    assert_eq!(
+        sid_from_host("ABC123.Tunnel.Test:8443", "tunnel.test").as_deref(),
+        Some("abc123")
+    );
+    assert_eq!(sid_from_host("tunnel.test", "tunnel.test"), None);
+    assert_eq!(sid_from_host("a.b.tunnel.test", "tunnel.test"), None);
+    assert_eq!(
+        sid_from_host("abc123.othertunnel.test", "tunnel.test"),
+        None
+    );
+    assert!(is_dns_label(&generate_session_id()));
+}
+
+#[tokio::test]
+async fn test_host_routing_serves_app_at_root() {
+    let (server, mut seen) = host_routed_server("abc123").await;
+
+    // Even paths the server uses itself belong to the app on a session host
+    let response = server
+        .get("/token")
+        .add_query_param("x", "1")
+        .add_header(axum::http::header::HOST, "abc123.tunnel.test")
+        .await;
+    assert_eq!(response.status_code(), StatusCode::FOUND);
+    assert_eq!(seen.recv().await.unwrap(), "/token?x=1");
+    // No /s/<sid> prefix and no session cookie needed
+    assert_eq!(response.header("location"), "/login");
+    assert!(response.maybe_header("set-cookie").is_none());
+
+    // Trailing slashes are the app's business
+    server
+        .get("/docs/")
+        .add_header(axum::http::header::HOST, "abc123.tunnel.test")
+        .await;
+    assert_eq!(seen.recv().await.unwrap(), "/docs/");
+}
+
+#[tokio::test]
+async fn test_path_routing_still_works_with_domain() {
+    let (server, mut seen) = host_routed_server("abc123").await;
+
+    let response = server
+        .get("/s/abc123/page")
+        .add_header(axum::http::header::HOST, "tunnel.test")
+        .await;
+    assert_eq!(response.status_code(), StatusCode::FOUND);
+    assert_eq!(seen.recv().await.unwrap(), "/page");
+    assert_eq!(response.header("location"), "/s/abc123/login");
+}
@@ -0,0 +1,139 @@
+use axum::http::StatusCode;
+use axum_test::TestServer;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tunly::access::{parse_net, parse_net_list, IpFilter};
+use tunly::{
+    create_app, AppState, AuthMode, ClientToServer, Features, ProxyResponse, ServerToClient,
+    SessionState,
+};
+
+fn ip(s: &str) -> Option<IpAddr> {
+    Some(s.parse().unwrap())
+}
+
+/// Server with one session whose mock client answers every request with 200.
+async fn filtered_server(server_filter: IpFilter, session_filter: IpFilter) -> Arc<AppState> {
+    let mut state = AppState::new(AuthMode::Ephemeral, vec![0u8; 32]);
+    state.settings_mut().ip_filter = server_filter;
+    // The test client connects over loopback and plays the reverse proxy
+    state.settings_mut().trusted_proxies = parse_net_list("127.0.0.1,::1").unwrap();
+    let state = Arc::new(state);
+
+    let (tx, mut rx) = mpsc::channel(16);
+    let mut session = SessionState::new(tx, Features::default());
+    session.ip_filter = session_filter;
+    let session = Arc::new(session);
+    state
+        .sessions
+        .write()
+        .await
+        .insert("office".to_string(), session.clone());
+
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if let ServerToClient::ProxyRequest(req) = msg {
+                if let Some(tx) = session.pending.lock().await.remove(&req.id) {
+                    let _ = tx.send(ClientToServer::ProxyResponse(ProxyResponse {
+                        id: req.id,
+                        status: 200,
+                        headers: vec![],
+                        body_b64: String::new(),
+                        is_compressed: false,
+                    }));
+                }
+            }
+        }
+    });
+    state
+}
+
+fn test_server(state: Arc<AppState>) -> TestServer {
+    let app = create_app(state).into_make_service_with_connect_info::<SocketAddr>();
+    TestServer::new(app).unwrap()
+}
+
+#[test]
+fn test_ip_filter_semantics() {
+    assert_eq!(
+        parse_net("203.0.113.7").unwrap(),
+        parse_net("203.0.113.7/32").unwrap()
+    );
+    assert!(parse_net("10.0.0.0/33").is_err());
+    assert!(parse_net_list("10.0.0.0/8, 2001:db8::/32,").is_ok_and(|nets| nets.len() == 2));
+
+    let open = IpFilter::default();
+    assert!(open.permits(ip("198.51.100.1")));
+    assert!(open.permits(None));
+
+    let filter = IpFilter {
+        allow: parse_net_list("10.0.0.0/8").unwrap(),
+        deny: parse_net_list("10.1.0.0/16").unwrap(),
+    };
+    assert!(filter.permits(ip("10.2.3.4")));
+    // Deny wins over a broader allow
+    assert!(!filter.permits(ip("10.1.2.3")));
+    assert!(!filter.permits(ip("192.168.1.1")));
+    // IPv4-mapped IPv6 addresses are matched as IPv4
+    assert!(filter.permits(ip("::ffff:10.2.3.4")));
+    // Unknown addresses never get past a list
+    assert!(!filter.permits(None));
+
+    let deny_only = IpFilter {
+        allow: vec![],
+        deny: parse_net_list("2001:db8::/32").unwrap(),
+    };
+    assert!(deny_only.permits(ip("203.0.113.7")));
+    assert!(!deny_only.permits(ip("2001:db8::1")));
+}
+
+#[tokio::test]
+async fn test_session_allow_list_rejects_other_visitors() {
+    let session_filter = IpFilter {
+        allow: parse_net_list("203.0.113.0/24").unwrap(),
+        deny: vec![],
+    };
+    let state = filtered_server(IpFilter::default(), session_filter).await;
+    let server = test_server(state.clone());
+
+    let response = server
+        .get("/s/office/")
+        .add_header("x-forwarded-for", "198.51.100.9")
+        .await;
+    assert_eq!(response.status_code(), StatusCode::FORBIDDEN);
+    assert!(response.text().contains("198.51.100.9"));
+    assert_eq!(
+        server
+            .get("/s/office/_log")
+            .add_header("x-forwarded-for", "198.51.100.9")
+            .await
+            .status_code(),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(state.metrics.ip_denied.get(), 2.0);
+
+    let response = server
+        .get("/s/office/")
+        .add_header("x-forwarded-for", "203.0.113.50")
+        .await;
+    assert_eq!(response.status_code(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_server_deny_list_applies_to_every_session() {
+    let server_filter = IpFilter {
+        allow: vec![],
+        deny: parse_net_list("127.0.0.0/8,::1").unwrap(),
+    };
+    let state = filtered_server(server_filter, IpFilter::default()).await;
+    let server = test_server(state.clone());
+
+    // No forwarding header: the peer address (loopback) is checked
+    let response = server.get("/s/office/").await;
+    assert_eq!(response.status_code(), StatusCode::FORBIDDEN);
+    // Even unknown sessions are rejected before anything else is revealed
+    let response = server.get("/s/unknown/").await;
+    assert_eq!(response.status_code(), StatusCode::FORBIDDEN);
+    assert_eq!(state.metrics.ip_denied.get(), 2.0);
+}
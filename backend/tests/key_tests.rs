@@ -0,0 +1,249 @@
+mod common;
+
+use common::spawn_server;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tunly::config::ConfigFile;
+use tunly::keys::{KeyAlgorithm, KeyConfig, KeyError, KeySet};
+use tunly::tokens::{verify, AccessClaims};
+use tunly::{AppState, AuthMode};
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn hs256(kid: &str, secret: &str) -> KeyConfig {
+    KeyConfig {
+        kid: kid.to_string(),
+        alg: KeyAlgorithm::HS256,
+        secret: Some(secret.to_string()),
+        public_key: None,
+        private_key: None,
+        not_before: None,
+        not_after: None,
+    }
+}
+
+/// `name` is `ed25519` or `rsa`; without `private` the key only verifies.
+fn pem_key(kid: &str, alg: KeyAlgorithm, name: &str, private: bool) -> KeyConfig {
+    KeyConfig {
+        kid: kid.to_string(),
+        alg,
+        secret: None,
+        public_key: Some(fixture(&format!("jwt-{}.pub", name))),
+        private_key: private.then(|| fixture(&format!("jwt-{}.key", name))),
+        not_before: None,
+        not_after: None,
+    }
+}
+
+fn claims() -> AccessClaims {
+    AccessClaims::new("alice@example.com", Duration::from_secs(600), vec![], 0)
+}
+
+fn kid_of(token: &str) -> Option<String> {
+    jsonwebtoken::decode_header(token).unwrap().kid
+}
+
+#[test]
+fn test_asymmetric_keys_verify_with_public_key_only() {
+    for (alg, name) in [
+        (KeyAlgorithm::EdDSA, "ed25519"),
+        (KeyAlgorithm::RS256, "rsa"),
+    ] {
+        let issuer = KeySet::load(&[pem_key("issuer", alg, name, true)], None).unwrap();
+        let server = KeySet::load(&[pem_key("issuer", alg, name, false)], None).unwrap();
+        assert!(!server.can_sign());
+        assert!(matches!(
+            server.sign(&claims()),
+            Err(KeyError::NoSigningKey)
+        ));
+
+        let claims = claims();
+        let token = claims.sign(&issuer).unwrap();
+        assert_eq!(kid_of(&token).as_deref(), Some("issuer"));
+        assert_eq!(verify(&token, &server), Some(claims));
+    }
+}
+
+#[test]
+fn test_rotation_overlap() {
+    let old = KeySet::load(&[hs256("2025", "old-secret")], None).unwrap();
+    let old_token = claims().sign(&old).unwrap();
+
+    // The new key is listed first and signs; the old one keeps verifying
+    let rotated = KeySet::load(
+        &[
+            pem_key("2026", KeyAlgorithm::EdDSA, "ed25519", true),
+            hs256("2025", "old-secret"),
+        ],
+        None,
+    )
+    .unwrap();
+    let new_token = claims().sign(&rotated).unwrap();
+    assert_eq!(kid_of(&new_token).as_deref(), Some("2026"));
+    assert!(verify(&old_token, &rotated).is_some());
+    assert!(verify(&new_token, &rotated).is_some());
+    assert!(verify(&new_token, &old).is_none());
+
+    // Once retired, tokens from the old key are refused
+    let mut retired = hs256("2025", "old-secret");
+    retired.not_after = Some(now() - 1);
+    let after = KeySet::load(
+        &[
+            pem_key("2026", KeyAlgorithm::EdDSA, "ed25519", true),
+            retired,
+        ],
+        None,
+    )
+    .unwrap();
+    assert!(matches!(
+        after.verify::<AccessClaims>(&old_token),
+        Err(KeyError::Retired(_))
+    ));
+}
+
+#[test]
+fn test_scheduled_key_waits_for_not_before() {
+    let mut next = hs256("next", "next-secret");
+    next.not_before = Some(now() + 3600);
+    let keys = KeySet::load(&[hs256("current", "current-secret"), next.clone()], None).unwrap();
+    assert_eq!(
+        kid_of(&claims().sign(&keys).unwrap()).as_deref(),
+        Some("current")
+    );
+
+    // Another replica already signing with it is still understood
+    next.not_before = None;
+    let early = KeySet::load(&[next], None).unwrap();
+    assert!(verify(&claims().sign(&early).unwrap(), &keys).is_some());
+}
+
+#[test]
+fn test_legacy_secret_and_unknown_kids() {
+    let legacy = KeySet::from_secret(b"legacy-secret");
+    let legacy_token = claims().sign(&legacy).unwrap();
+    assert_eq!(kid_of(&legacy_token), None);
+
+    let keys = KeySet::load(&[hs256("k1", "k1-secret")], Some(b"legacy-secret")).unwrap();
+    assert_eq!(keys.kids(), vec!["k1"]);
+    assert!(verify(&legacy_token, &keys).is_some());
+    // Configured keys sign ahead of the legacy secret
+    assert_eq!(
+        kid_of(&claims().sign(&keys).unwrap()).as_deref(),
+        Some("k1")
+    );
+
+    let stranger = KeySet::load(&[hs256("k2", "k1-secret")], None).unwrap();
+    let token = claims().sign(&stranger).unwrap();
+    assert!(matches!(
+        keys.verify::<AccessClaims>(&token),
+        Err(KeyError::UnknownKey(kid)) if kid == "k2"
+    ));
+    // A kid whose algorithm does not match the configured key is not accepted
+    let confused = KeySet::load(&[hs256("k1", "k1-secret")], None).unwrap();
+    let public_only = KeySet::load(
+        &[pem_key("k1", KeyAlgorithm::EdDSA, "ed25519", false)],
+        None,
+    )
+    .unwrap();
+    assert!(verify(&claims().sign(&confused).unwrap(), &public_only).is_none());
+}
+
+#[test]
+fn test_invalid_key_configs() {
+    let mut no_secret = hs256("a", "");
+    no_secret.secret = None;
+    assert!(KeySet::load(&[no_secret], None).is_err());
+    assert!(KeySet::load(&[hs256("a", "x"), hs256("a", "y")], None).is_err());
+
+    let mut no_public = pem_key("b", KeyAlgorithm::RS256, "rsa", true);
+    no_public.public_key = None;
+    assert!(KeySet::load(&[no_public], None).is_err());
+
+    let mut missing = pem_key("c", KeyAlgorithm::EdDSA, "ed25519", false);
+    missing.public_key = Some(fixture("missing.pub"));
+    assert!(matches!(
+        KeySet::load(&[missing], None),
+        Err(KeyError::Io(..))
+    ));
+}
+
+#[test]
+fn test_keys_in_config_file_reload_without_restart() {
+    let path = std::env::temp_dir().join(format!("tunly-keys-{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        format!(
+            r#"
+            [[auth.keys]]
+            kid = "2026"
+            alg = "EdDSA"
+            public_key = "{}"
+            not_after = 1900000000
+
+            [[auth.keys]]
+            kid = "2025"
+            secret = "old-secret"
+            "#,
+            fixture("jwt-ed25519.pub").display()
+        ),
+    )
+    .unwrap();
+    let file = ConfigFile::load(&path);
+    let _ = std::fs::remove_file(&path);
+    let file = file.unwrap();
+    assert_eq!(file.auth.keys.len(), 2);
+    assert_eq!(file.auth.keys[0].alg, KeyAlgorithm::EdDSA);
+    assert_eq!(file.auth.keys[1].alg, KeyAlgorithm::HS256);
+    assert!(KeySet::load(&file.auth.keys, None).is_ok());
+
+    let mut rotated = file.clone();
+    rotated.auth.keys.pop();
+    assert!(!file.needs_restart(&rotated));
+}
+
+#[tokio::test]
+async fn test_server_accepts_token_from_external_issuer() {
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]));
+    state.replace_keys(
+        KeySet::load(
+            &[pem_key("issuer", KeyAlgorithm::EdDSA, "ed25519", false)],
+            Some(&[0u8; 32]),
+        )
+        .unwrap(),
+    );
+    let addr = spawn_server(state.clone()).await;
+
+    let issuer = KeySet::load(
+        &[pem_key("issuer", KeyAlgorithm::EdDSA, "ed25519", true)],
+        None,
+    )
+    .unwrap();
+    let connect = |token: String| {
+        let mut req = format!("ws://{}/ws?sid=issued", addr)
+            .into_client_request()
+            .unwrap();
+        req.headers_mut().insert(
+            "Authorization",
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        tokio_tungstenite::connect_async(req)
+    };
+    assert!(connect(claims().sign(&issuer).unwrap()).await.is_ok());
+
+    // After the issuer key is dropped on reload, its tokens stop working
+    state.replace_keys(KeySet::from_secret(&[0u8; 32]));
+    assert!(connect(claims().sign(&issuer).unwrap()).await.is_err());
+}
@@ -0,0 +1,126 @@
+mod common;
+
+use common::spawn_server;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tunly::store::{owner_id, validate_name, Store, StoreError};
+use tunly::{AppState, AuthMode, TokenResponse, NAME_KEY_HEADER};
+
+async fn connect_named(
+    addr: SocketAddr,
+    name: &str,
+    token: &str,
+    key: Option<&str>,
+) -> Result<(), u16> {
+    let mut req = format!("ws://{}/ws?name={}", addr, name)
+        .into_client_request()
+        .unwrap();
+    req.headers_mut().insert(
+        "Authorization",
+        format!("Bearer {}", token).parse().unwrap(),
+    );
+    if let Some(key) = key {
+        req.headers_mut()
+            .insert(NAME_KEY_HEADER, key.parse().unwrap());
+    }
+    match tokio_tungstenite::connect_async(req).await {
+        Ok(_) => Ok(()),
+        Err(WsError::Http(resp)) => Err(resp.status().as_u16()),
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+#[tokio::test]
+async fn test_name_store_persists_claims() {
+    let path = std::env::temp_dir().join(format!("tunly-names-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let store = Store::open(path.clone()).unwrap();
+    store
+        .claim_name("acme-demo", &owner_id("alice"))
+        .await
+        .unwrap();
+    // Reclaiming by the owner is fine, anyone else is refused
+    store
+        .claim_name("acme-demo", &owner_id("alice"))
+        .await
+        .unwrap();
+    assert!(matches!(
+        store.claim_name("acme-demo", &owner_id("bob")).await,
+        Err(StoreError::Taken)
+    ));
+
+    let reopened = Store::open(path.clone()).unwrap();
+    assert_eq!(
+        reopened.name_owner("acme-demo").await,
+        Some(owner_id("alice"))
+    );
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_validate_name() {
+    assert!(validate_name("acme-demo").is_ok());
+    assert!(validate_name("ab").is_err());
+    assert!(validate_name("Acme").is_err());
+    assert!(validate_name("acme_demo").is_err());
+    assert!(validate_name("-acme").is_err());
+}
+
+#[tokio::test]
+async fn test_named_session_is_reserved_for_its_key() {
+    let state = Arc::new(AppState::new(
+        AuthMode::Fixed("fixed-token".to_string()),
+        vec![0u8; 32],
+    ));
+    let addr = spawn_server(state.clone()).await;
+
+    assert_eq!(
+        connect_named(addr, "Not_A_Name", "fixed-token", None).await,
+        Err(400)
+    );
+
+    connect_named(addr, "acme-demo", "fixed-token", Some("alice-key"))
+        .await
+        .unwrap();
+    assert_eq!(
+        state.store.name_owner("acme-demo").await,
+        Some(owner_id("alice-key"))
+    );
+
+    // Same token, different key: conflict
+    assert_eq!(
+        connect_named(addr, "acme-demo", "fixed-token", Some("bob-key")).await,
+        Err(409)
+    );
+    // Without a key the fixed token is the owner key, which does not match either
+    assert_eq!(
+        connect_named(addr, "acme-demo", "fixed-token", None).await,
+        Err(409)
+    );
+}
+
+#[tokio::test]
+async fn test_token_endpoint_issues_named_session() {
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]));
+    let addr = spawn_server(state).await;
+
+    let resp = reqwest::get(format!("http://{}/token?name=acme-demo", addr))
+        .await
+        .unwrap();
+    let token: TokenResponse = resp.json().await.unwrap();
+    assert_eq!(token.session, "acme-demo");
+
+    // Ephemeral tokens are single-use, so claiming a name needs an explicit key
+    assert_eq!(
+        connect_named(addr, "acme-demo", &token.token, None).await,
+        Err(400)
+    );
+
+    let resp = reqwest::get(format!("http://{}/token?name=a_b", addr))
+        .await
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 400);
+}
@@ -0,0 +1,177 @@
+use axum::http::StatusCode;
+use axum_test::TestServer;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tunly::{
+    create_app, AppState, AuthMode, ClientToServer, ErrorMessage, Features, MessageCode, Notice,
+    ServerToClient, SessionState, Severity, ERROR_HEADER,
+};
+
+fn test_server(state: Arc<AppState>) -> TestServer {
+    let app = create_app(state).into_make_service_with_connect_info::<SocketAddr>();
+    TestServer::new(app).unwrap()
+}
+
+/// A session that negotiated notices; returns what the server sends its client.
+async fn notified_session(state: &AppState, sid: &str) -> mpsc::Receiver<ServerToClient> {
+    let (tx, rx) = mpsc::channel(16);
+    let features = Features {
+        notices: true,
+        ..Features::default()
+    };
+    state
+        .sessions
+        .write()
+        .await
+        .insert(sid.to_string(), Arc::new(SessionState::new(tx, features)));
+    rx
+}
+
+fn notices(rx: &mut mpsc::Receiver<ServerToClient>) -> Vec<Notice> {
+    let mut notices = Vec::new();
+    while let Ok(msg) = rx.try_recv() {
+        if let ServerToClient::Notice(notice) = msg {
+            notices.push(notice);
+        }
+    }
+    notices
+}
+
+#[test]
+fn test_notice_wire_format() {
+    let notice = ServerToClient::Notice(Notice {
+        severity: Severity::Warning,
+        code: MessageCode::IdleTimeout,
+        message: "idle".to_string(),
+    });
+    let json = serde_json::to_value(&notice).unwrap();
+    assert_eq!(json["type"], "notice");
+    assert_eq!(json["severity"], "warning");
+    assert_eq!(json["code"], "idle_timeout");
+
+    // Session-level errors carry no request id
+    let error = serde_json::to_string(&ClientToServer::Error(ErrorMessage {
+        id: None,
+        code: MessageCode::UpstreamFailed,
+        message: "boom".to_string(),
+    }))
+    .unwrap();
+    assert!(!error.contains("\"id\""));
+
+    // Codes added by newer peers still parse
+    let parsed: ClientToServer = serde_json::from_str(
+        r#"{"type":"error","id":7,"code":"quota_exhausted","message":"later"}"#,
+    )
+    .unwrap();
+    let ClientToServer::Error(error) = parsed else {
+        panic!("expected an error");
+    };
+    assert_eq!(error.id, Some(7));
+    assert_eq!(error.code, MessageCode::Other);
+    assert_eq!(error.status(), 502);
+}
+
+#[tokio::test]
+async fn test_rate_limited_session_is_notified_once() {
+    let mut state = AppState::new(AuthMode::Ephemeral, vec![0u8; 32]);
+    state.settings_mut().limits.session_rate_max = 1;
+    state.settings_mut().limits.upstream_timeout_secs = 1;
+    let state = Arc::new(state);
+    let mut rx = notified_session(&state, "busy").await;
+    let server = test_server(state);
+
+    server.get("/s/busy/").await;
+    for _ in 0..3 {
+        let limited = server.get("/s/busy/").await;
+        assert_eq!(limited.status_code(), StatusCode::TOO_MANY_REQUESTS);
+    }
+    let notices = notices(&mut rx);
+    // Repeats within the notice interval are dropped
+    assert_eq!(notices.len(), 1);
+    assert_eq!(notices[0].severity, Severity::Warning);
+    assert_eq!(notices[0].code, MessageCode::RateLimited);
+}
+
+#[tokio::test]
+async fn test_oversized_body_is_notified() {
+    let mut state = AppState::new(AuthMode::Ephemeral, vec![0u8; 32]);
+    state.settings_mut().limits.max_body_bytes = 16;
+    let state = Arc::new(state);
+    let mut rx = notified_session(&state, "upload").await;
+    let server = test_server(state);
+
+    let resp = server
+        .post("/s/upload/files")
+        .bytes(vec![0u8; 64].into())
+        .await;
+    assert_eq!(resp.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+    let notices = notices(&mut rx);
+    assert_eq!(notices.len(), 1);
+    assert_eq!(notices[0].code, MessageCode::BodyTooLarge);
+    assert!(notices[0].message.contains("/files"));
+}
+
+#[tokio::test]
+async fn test_clients_without_notices_are_not_told() {
+    let (tx, mut rx) = mpsc::channel(16);
+    let session = SessionState::new(tx, Features::default());
+    session.notify(
+        Severity::Error,
+        MessageCode::IdleTimeout,
+        "gone".to_string(),
+    );
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_client_error_becomes_gateway_status() {
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]));
+    let (tx, mut rx) = mpsc::channel(16);
+    let features = Features {
+        notices: true,
+        ..Features::default()
+    };
+    let session = Arc::new(SessionState::new(tx, features));
+    state
+        .sessions
+        .write()
+        .await
+        .insert("office".to_string(), session.clone());
+
+    // The mock client times out on /slow, is too busy for /busy and cannot reach anything else
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if let ServerToClient::ProxyRequest(req) = msg {
+                let code = if req.uri.starts_with("/slow") {
+                    MessageCode::UpstreamTimeout
+                } else if req.uri.starts_with("/busy") {
+                    MessageCode::Overloaded
+                } else {
+                    MessageCode::UpstreamUnreachable
+                };
+                if let Some(tx) = session.pending.lock().await.remove(&req.id) {
+                    let _ = tx.send(ClientToServer::Error(ErrorMessage {
+                        id: Some(req.id),
+                        code,
+                        message: "connection refused".to_string(),
+                    }));
+                }
+            }
+        }
+    });
+    let server = test_server(state);
+
+    let slow = server.get("/s/office/slow").await;
+    assert_eq!(slow.status_code(), StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(slow.headers()[ERROR_HEADER], "upstream_timeout");
+
+    let busy = server.get("/s/office/busy").await;
+    assert_eq!(busy.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(busy.headers()[ERROR_HEADER], "overloaded");
+
+    let down = server.get("/s/office/").await;
+    assert_eq!(down.status_code(), StatusCode::BAD_GATEWAY);
+    assert_eq!(down.headers()[ERROR_HEADER], "upstream_unreachable");
+    assert!(down.text().contains("connection refused"));
+}
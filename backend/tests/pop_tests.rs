@@ -0,0 +1,216 @@
+mod common;
+
+use common::spawn_server;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tunly::pop::{self, ChallengeResponse, Identity};
+use tunly::{AppState, AuthMode, TokenResponse};
+
+async fn token_for(addr: SocketAddr, identity: &Identity) -> TokenResponse {
+    reqwest::get(format!(
+        "http://{}/token?pubkey={}",
+        addr,
+        identity.public_key()
+    ))
+    .await
+    .unwrap()
+    .json()
+    .await
+    .unwrap()
+}
+
+async fn challenge(addr: SocketAddr) -> String {
+    let body: ChallengeResponse = reqwest::get(format!("http://{}/challenge", addr))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    body.challenge
+}
+
+fn ws_request(addr: SocketAddr, sid: &str, token: Option<&str>) -> Request {
+    let mut req = format!("ws://{}/ws?sid={}", addr, sid)
+        .into_client_request()
+        .unwrap();
+    if let Some(token) = token {
+        req.headers_mut().insert(
+            "Authorization",
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+    }
+    req
+}
+
+/// Attach a proof from `identity` answering `challenge` for `sid`.
+fn prove(mut req: Request, identity: &Identity, challenge: &str, sid: &str) -> Request {
+    let headers = req.headers_mut();
+    headers.insert(
+        pop::CLIENT_KEY_HEADER,
+        identity.public_key().parse().unwrap(),
+    );
+    headers.insert(pop::CHALLENGE_HEADER, challenge.parse().unwrap());
+    headers.insert(
+        pop::SIGNATURE_HEADER,
+        identity.sign(challenge, sid).parse().unwrap(),
+    );
+    req
+}
+
+async fn status(req: Request) -> u16 {
+    match tokio_tungstenite::connect_async(req).await {
+        Ok(_) => 101,
+        Err(WsError::Http(resp)) => resp.status().as_u16(),
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+#[tokio::test]
+async fn test_key_bound_token_needs_proof() {
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]));
+    let addr = spawn_server(state.clone()).await;
+    let identity = Identity::generate();
+    let token = token_for(addr, &identity).await;
+    let sid = token.session.as_str();
+    // Not tied to the requesting IP
+    assert!(state
+        .issued_tokens
+        .lock()
+        .await
+        .values()
+        .all(|(ip, _, _)| ip.is_empty()));
+
+    // No proof, or a proof from another key, is not enough
+    assert_eq!(status(ws_request(addr, sid, Some(&token.token))).await, 401);
+    let stranger = Identity::generate();
+    let c = challenge(addr).await;
+    let req = prove(
+        ws_request(addr, sid, Some(&token.token)),
+        &stranger,
+        &c,
+        sid,
+    );
+    assert_eq!(status(req).await, 401);
+
+    // A signature over another sid does not verify
+    let c = challenge(addr).await;
+    let mut req = prove(
+        ws_request(addr, sid, Some(&token.token)),
+        &identity,
+        &c,
+        sid,
+    );
+    req.headers_mut().insert(
+        pop::SIGNATURE_HEADER,
+        identity.sign(&c, "other-sid").parse().unwrap(),
+    );
+    assert_eq!(status(req).await, 401);
+
+    let c = challenge(addr).await;
+    let req = prove(
+        ws_request(addr, sid, Some(&token.token)),
+        &identity,
+        &c,
+        sid,
+    );
+    assert_eq!(status(req).await, 101);
+}
+
+#[tokio::test]
+async fn test_challenge_is_single_use() {
+    let state = Arc::new(AppState::new(
+        AuthMode::Fixed("fixed-token".to_string()),
+        vec![0u8; 32],
+    ));
+    let addr = spawn_server(state).await;
+    let identity = Identity::generate();
+    let c = challenge(addr).await;
+
+    let req = prove(
+        ws_request(addr, "once", Some("fixed-token")),
+        &identity,
+        &c,
+        "once",
+    );
+    assert_eq!(status(req).await, 101);
+    let req = prove(
+        ws_request(addr, "once", Some("fixed-token")),
+        &identity,
+        &c,
+        "once",
+    );
+    assert_eq!(status(req).await, 401);
+
+    let req = prove(
+        ws_request(addr, "forged", Some("fixed-token")),
+        &identity,
+        "not-a-challenge",
+        "forged",
+    );
+    assert_eq!(status(req).await, 401);
+}
+
+#[tokio::test]
+async fn test_same_key_reclaims_session() {
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]));
+    let addr = spawn_server(state.clone()).await;
+    let identity = Identity::generate();
+    let token = token_for(addr, &identity).await;
+    let sid = token.session.clone();
+
+    let c = challenge(addr).await;
+    let req = prove(
+        ws_request(addr, &sid, Some(&token.token)),
+        &identity,
+        &c,
+        &sid,
+    );
+    let (ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    drop(ws);
+    for _ in 0..50 {
+        if !state.sessions.read().await.contains_key(&sid) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // Another key has neither a token nor the resume secret
+    let c = challenge(addr).await;
+    let req = prove(
+        ws_request(addr, &sid, None),
+        &Identity::generate(),
+        &c,
+        &sid,
+    );
+    assert_eq!(status(req).await, 401);
+
+    // The key that opened the session reattaches without either
+    let c = challenge(addr).await;
+    let req = prove(ws_request(addr, &sid, None), &identity, &c, &sid);
+    assert_eq!(status(req).await, 101);
+}
+
+#[tokio::test]
+async fn test_token_rejects_bad_public_key() {
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]));
+    let addr = spawn_server(state).await;
+    let resp = reqwest::get(format!("http://{}/token?pubkey=abc", addr))
+        .await
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 400);
+}
+
+#[test]
+fn test_identity_file_roundtrip() {
+    let path = std::env::temp_dir().join(format!("tunly-identity-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let created = Identity::load_or_create(&path).unwrap();
+    let loaded = Identity::load_or_create(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(created.public_key(), loaded.public_key());
+    assert!(pop::parse_public_key(&loaded.public_key()).is_ok());
+}
@@ -0,0 +1,64 @@
+use tunly::frame::{decode_binary, encode_binary, FrameError};
+use tunly::{BodyChunk, ClientToServer, ResponseStart, ServerToClient, StreamEnd};
+
+#[test]
+fn test_binary_frame_carries_raw_payload() {
+    let data: Vec<u8> = (0..=255u8).cycle().take(4096).collect();
+    let msg = ServerToClient::RequestBody(BodyChunk {
+        id: 7,
+        data: data.clone(),
+        is_compressed: false,
+    });
+
+    let frame = encode_binary(msg).unwrap();
+    // header length prefix + JSON header + payload without base64 inflation
+    let header_len = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+    assert_eq!(&frame[4 + header_len..], data.as_slice());
+
+    match decode_binary::<ServerToClient>(&frame).unwrap() {
+        ServerToClient::RequestBody(chunk) => {
+            assert_eq!(chunk.id, 7);
+            assert_eq!(chunk.data, data);
+        }
+        other => panic!("unexpected message: {:?}", other),
+    }
+}
+
+#[test]
+fn test_binary_frame_without_payload() {
+    let frame = encode_binary(ClientToServer::ResponseStart(ResponseStart {
+        id: 3,
+        status: 204,
+        headers: vec![("x-test".into(), "1".into())],
+    }))
+    .unwrap();
+    match decode_binary::<ClientToServer>(&frame).unwrap() {
+        ClientToServer::ResponseStart(head) => {
+            assert_eq!(head.status, 204);
+            assert_eq!(head.headers.len(), 1);
+        }
+        other => panic!("unexpected message: {:?}", other),
+    }
+
+    let end = encode_binary(ClientToServer::ResponseEnd(StreamEnd {
+        id: 3,
+        error: None,
+    }))
+    .unwrap();
+    assert!(matches!(
+        decode_binary::<ClientToServer>(&end).unwrap(),
+        ClientToServer::ResponseEnd(StreamEnd { id: 3, .. })
+    ));
+}
+
+#[test]
+fn test_truncated_binary_frame_is_rejected() {
+    assert!(matches!(
+        decode_binary::<ServerToClient>(&[0, 0]),
+        Err(FrameError::Truncated)
+    ));
+    assert!(matches!(
+        decode_binary::<ServerToClient>(&[0, 0, 0, 50, b'{']),
+        Err(FrameError::Truncated)
+    ));
+}
@@ -0,0 +1,174 @@
+mod common;
+
+use axum::http::StatusCode;
+use axum_test::TestServer;
+use common::spawn_server;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tunly::access::parse_net_list;
+use tunly::ratelimit::{Quota, RateLimiter, RouteClass, LIMIT_HEADER, REMAINING_HEADER};
+use tunly::{create_app, AppState, AuthMode};
+
+fn test_server(state: AppState) -> TestServer {
+    let app = create_app(Arc::new(state)).into_make_service_with_connect_info::<SocketAddr>();
+    TestServer::new(app).unwrap()
+}
+
+#[tokio::test]
+async fn test_bucket_allows_burst_then_refuses() {
+    let limiter = RateLimiter::default();
+    let quota = Quota::new(3, 60);
+    for remaining in [2, 1, 0] {
+        let decision = limiter
+            .acquire(RouteClass::Token, "1.2.3.4", quota, 10)
+            .await;
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, remaining);
+        assert_eq!(decision.limit, 3);
+    }
+    let denied = limiter
+        .acquire(RouteClass::Token, "1.2.3.4", quota, 10)
+        .await;
+    assert!(!denied.allowed);
+    // One token comes back every 20 seconds
+    assert_eq!(denied.retry_after, 20);
+    assert_eq!(denied.reset, 60);
+
+    // Other keys and other route classes have their own buckets
+    assert!(
+        limiter
+            .acquire(RouteClass::Token, "5.6.7.8", quota, 10)
+            .await
+            .allowed
+    );
+    assert!(
+        limiter
+            .acquire(RouteClass::WsConnect, "1.2.3.4", quota, 10)
+            .await
+            .allowed
+    );
+}
+
+#[tokio::test]
+async fn test_bucket_table_is_bounded() {
+    let limiter = RateLimiter::default();
+    let quota = Quota::new(5, 60);
+    for i in 0..10 {
+        let key = format!("10.0.0.{}", i);
+        limiter.acquire(RouteClass::Proxy, &key, quota, 4).await;
+    }
+    assert_eq!(limiter.len().await, 4);
+    // The least recently used key goes first: 10.0.0.6 is touched again and keeps its bucket
+    let reused = limiter
+        .acquire(RouteClass::Proxy, "10.0.0.6", quota, 4)
+        .await;
+    assert_eq!(reused.remaining, 3);
+    limiter
+        .acquire(RouteClass::Proxy, "10.0.0.10", quota, 4)
+        .await;
+    let kept = limiter
+        .acquire(RouteClass::Proxy, "10.0.0.6", quota, 4)
+        .await;
+    assert_eq!(kept.remaining, 2);
+    let evicted = limiter
+        .acquire(RouteClass::Proxy, "10.0.0.7", quota, 4)
+        .await;
+    assert_eq!(evicted.remaining, 4);
+
+    // Buckets that have refilled carry no state worth keeping
+    let fast = Quota::new(1, 1);
+    limiter.acquire(RouteClass::Proxy, "fast", fast, 100).await;
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(limiter.evict_idle().await, 1);
+    assert_eq!(limiter.len().await, 4);
+}
+
+#[tokio::test]
+async fn test_token_endpoint_reports_and_enforces_limit() {
+    let mut state = AppState::new(AuthMode::Ephemeral, vec![0u8; 32]);
+    state.settings_mut().limits.token_rate_max = 2;
+    let server = test_server(state);
+
+    let ok = server.get("/token").await;
+    assert_eq!(ok.status_code(), StatusCode::OK);
+    assert_eq!(ok.headers()[LIMIT_HEADER], "2");
+    assert_eq!(ok.headers()[REMAINING_HEADER], "1");
+    server.get("/token").await;
+
+    let limited = server.get("/token").await;
+    assert_eq!(limited.status_code(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(limited.headers()[REMAINING_HEADER], "0");
+    assert_eq!(limited.headers()["retry-after"], "30");
+}
+
+#[tokio::test]
+async fn test_session_limit_spans_visitor_ips() {
+    let mut state = AppState::new(AuthMode::Ephemeral, vec![0u8; 32]);
+    state.settings_mut().limits.session_rate_max = 3;
+    // The test client connects over loopback and plays the reverse proxy
+    state.settings_mut().trusted_proxies = parse_net_list("127.0.0.1,::1").unwrap();
+    let server = test_server(state);
+
+    for i in 0..3 {
+        let resp = server
+            .get("/s/busy/")
+            .add_header("x-forwarded-for", format!("198.51.100.{}", i))
+            .await;
+        assert_eq!(resp.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[LIMIT_HEADER], "3");
+    }
+    let limited = server
+        .get("/s/busy/")
+        .add_header("x-forwarded-for", "198.51.100.9")
+        .await;
+    assert_eq!(limited.status_code(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Other sessions are unaffected
+    let other = server
+        .get("/s/quiet/")
+        .add_header("x-forwarded-for", "198.51.100.9")
+        .await;
+    assert_eq!(other.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn test_ws_connects_are_limited_per_ip() {
+    let mut state = AppState::new(AuthMode::Fixed("fixed-token".to_string()), vec![0u8; 32]);
+    state.settings_mut().limits.ws_rate_max = 2;
+    let addr = spawn_server(Arc::new(state)).await;
+
+    // Refused before the missing sid is even looked at
+    let status = || async {
+        match tokio_tungstenite::connect_async(format!("ws://{}/ws", addr)).await {
+            Err(WsError::Http(resp)) => resp.status().as_u16(),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    };
+    assert_eq!(status().await, 400);
+    assert_eq!(status().await, 400);
+    assert_eq!(status().await, 429);
+}
+
+#[tokio::test]
+async fn test_refused_visitor_does_not_drain_session() {
+    let mut state = AppState::new(AuthMode::Ephemeral, vec![0u8; 32]);
+    state.settings_mut().limits.proxy_rate_max = 2;
+    state.settings_mut().limits.session_rate_max = 4;
+    state.settings_mut().trusted_proxies = parse_net_list("127.0.0.1,::1").unwrap();
+    let server = test_server(state);
+
+    // One visitor keeps going after its own bucket is empty
+    for _ in 0..10 {
+        server
+            .get("/s/shared/")
+            .add_header("x-forwarded-for", "198.51.100.1")
+            .await;
+    }
+    let other = server
+        .get("/s/shared/")
+        .add_header("x-forwarded-for", "198.51.100.2")
+        .await;
+    assert_eq!(other.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+}
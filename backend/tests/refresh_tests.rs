@@ -0,0 +1,184 @@
+mod common;
+
+use common::spawn_server;
+use futures::StreamExt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tunly::store::Store;
+use tunly::{
+    issue_refresh_token, AppState, AuthMode, ServerToClient, TokenResponse, FEATURES_HEADER,
+};
+
+type Ws =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Ephemeral-mode state whose sids are released as soon as their client disconnects.
+fn test_state() -> AppState {
+    let mut state = AppState::new(AuthMode::Ephemeral, vec![0u8; 32]);
+    state.settings_mut().limits.resume_grace_secs = 0;
+    state
+}
+
+async fn connect(addr: SocketAddr, sid: &str, token: &str, features: &str) -> Result<Ws, u16> {
+    let mut req = format!("ws://{}/ws?sid={}", addr, sid)
+        .into_client_request()
+        .unwrap();
+    let headers = req.headers_mut();
+    headers.insert(
+        "Authorization",
+        format!("Bearer {}", token).parse().unwrap(),
+    );
+    headers.insert(FEATURES_HEADER, features.parse().unwrap());
+    match tokio_tungstenite::connect_async(req).await {
+        Ok((ws, _)) => Ok(ws),
+        Err(WsError::Http(resp)) => Err(resp.status().as_u16()),
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+/// The next refresh credential the server sends, if one arrives shortly.
+async fn next_refresh(ws: &mut Ws) -> Option<String> {
+    let wait = tokio::time::timeout(Duration::from_millis(500), async {
+        while let Some(Ok(msg)) = ws.next().await {
+            if let Message::Text(txt) = msg {
+                if let Ok(ServerToClient::RefreshToken(refresh)) = serde_json::from_str(&txt) {
+                    return Some(refresh.token);
+                }
+            }
+        }
+        None
+    });
+    wait.await.ok().flatten()
+}
+
+async fn exchange(addr: SocketAddr, credential: &str) -> Result<TokenResponse, u16> {
+    let resp = reqwest::Client::new()
+        .post(format!("http://{}/token/refresh", addr))
+        .bearer_auth(credential)
+        .send()
+        .await
+        .unwrap();
+    match resp.status().as_u16() {
+        200 => Ok(resp.json().await.unwrap()),
+        code => Err(code),
+    }
+}
+
+async fn wait_disconnected(state: &AppState, sid: &str) {
+    for _ in 0..50 {
+        if !state.sessions.read().await.contains_key(sid) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_reconnect_with_refresh_credential() {
+    let state = Arc::new(test_state());
+    let addr = spawn_server(state.clone()).await;
+    let token: TokenResponse = reqwest::get(format!("http://{}/token", addr))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let sid = token.session.clone();
+
+    let mut ws = connect(addr, &sid, &token.token, "refresh").await.unwrap();
+    let credential = next_refresh(&mut ws).await.expect("refresh credential");
+    drop(ws);
+    wait_disconnected(&state, &sid).await;
+
+    // The used token is gone, but the credential gets a new one for the same session
+    assert_eq!(
+        connect(addr, &sid, &token.token, "refresh").await.err(),
+        Some(401)
+    );
+    let fresh = exchange(addr, &credential).await.unwrap();
+    assert_eq!(fresh.session, sid);
+    assert_eq!(exchange(addr, &credential).await.err(), Some(401));
+    let mut ws = connect(addr, &sid, &fresh.token, "refresh").await.unwrap();
+    assert!(next_refresh(&mut ws).await.is_some());
+}
+
+#[tokio::test]
+async fn test_refresh_only_for_clients_that_ask() {
+    let state = Arc::new(test_state());
+    let addr = spawn_server(state.clone()).await;
+    let token: TokenResponse = reqwest::get(format!("http://{}/token", addr))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let mut ws = connect(addr, &token.session, &token.token, "stream")
+        .await
+        .unwrap();
+    assert!(next_refresh(&mut ws).await.is_none());
+    assert_eq!(state.store.refresh_count().await, 0);
+
+    let fixed = Arc::new(AppState::new(
+        AuthMode::Fixed("fixed-token".to_string()),
+        vec![0u8; 32],
+    ));
+    let addr = spawn_server(fixed.clone()).await;
+    let mut ws = connect(addr, "fixed", "fixed-token", "refresh")
+        .await
+        .unwrap();
+    assert!(next_refresh(&mut ws).await.is_none());
+    assert_eq!(exchange(addr, "anything").await.err(), Some(403));
+}
+
+#[tokio::test]
+async fn test_newer_credential_replaces_older() {
+    let state = Arc::new(test_state());
+    let addr = spawn_server(state.clone()).await;
+    let older = issue_refresh_token(&state, "listener", None, 3600)
+        .await
+        .unwrap();
+    let newer = issue_refresh_token(&state, "listener", None, 3600)
+        .await
+        .unwrap();
+    assert_eq!(exchange(addr, &older).await.err(), Some(401));
+    assert_eq!(exchange(addr, &newer).await.unwrap().session, "listener");
+
+    // Ephemeral tokens are not refresh credentials
+    let token: TokenResponse = reqwest::get(format!("http://{}/token", addr))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(exchange(addr, &token.token).await.err(), Some(401));
+}
+
+#[tokio::test]
+async fn test_credentials_survive_restart() {
+    let path = std::env::temp_dir().join(format!("tunly-refresh-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let with_store = || {
+        let mut state = test_state();
+        state.store = Store::open(path.clone()).unwrap();
+        Arc::new(state)
+    };
+
+    let before = with_store();
+    let credential = issue_refresh_token(&before, "overnight", None, 3600)
+        .await
+        .unwrap();
+    drop(before);
+
+    // A restarted server still honours the credential, once
+    let addr = spawn_server(with_store()).await;
+    assert_eq!(
+        exchange(addr, &credential).await.unwrap().session,
+        "overnight"
+    );
+    let addr = spawn_server(with_store()).await;
+    assert_eq!(exchange(addr, &credential).await.err(), Some(401));
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,122 @@
+mod common;
+
+use common::spawn_server;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tunly::{AppState, AuthMode, RESUME_HEADER};
+
+fn ws_request(
+    addr: SocketAddr,
+    sid: &str,
+    token: Option<&str>,
+    resume: Option<&str>,
+) -> tokio_tungstenite::tungstenite::handshake::client::Request {
+    let mut req = format!("ws://{}/ws?sid={}", addr, sid)
+        .into_client_request()
+        .unwrap();
+    if let Some(token) = token {
+        req.headers_mut().insert(
+            "Authorization",
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+    }
+    if let Some(secret) = resume {
+        req.headers_mut()
+            .insert(RESUME_HEADER, secret.parse().unwrap());
+    }
+    req
+}
+
+fn rejected_status(err: WsError) -> u16 {
+    match err {
+        WsError::Http(resp) => resp.status().as_u16(),
+        other => panic!("unexpected error: {}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_session_resume_keeps_sid() {
+    let state = Arc::new(AppState::new(
+        AuthMode::Fixed("fixed-token".to_string()),
+        vec![0u8; 32],
+    ));
+    let addr = spawn_server(state.clone()).await;
+
+    let (ws, resp) =
+        tokio_tungstenite::connect_async(ws_request(addr, "resume-me", Some("fixed-token"), None))
+            .await
+            .unwrap();
+    let secret = resp.headers()[RESUME_HEADER].to_str().unwrap().to_string();
+
+    // Simulate a network drop and wait for the server to notice
+    drop(ws);
+    for _ in 0..50 {
+        if !state.sessions.read().await.contains_key("resume-me") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // Visitors are told to retry while the sid is reserved
+    let http = reqwest::get(format!("http://{}/s/resume-me/", addr))
+        .await
+        .unwrap();
+    assert_eq!(http.status().as_u16(), 503);
+    assert_eq!(http.headers()["retry-after"], "5");
+
+    // A valid token alone cannot take over a reserved sid
+    let err =
+        tokio_tungstenite::connect_async(ws_request(addr, "resume-me", Some("fixed-token"), None))
+            .await
+            .unwrap_err();
+    assert_eq!(rejected_status(err), 409);
+
+    // A wrong secret without a token is rejected
+    let err = tokio_tungstenite::connect_async(ws_request(addr, "resume-me", None, Some("not-it")))
+        .await
+        .unwrap_err();
+    assert_eq!(rejected_status(err), 401);
+
+    // The resume secret alone reattaches to the same sid and is rotated
+    let (_ws, resp) =
+        tokio_tungstenite::connect_async(ws_request(addr, "resume-me", None, Some(&secret)))
+            .await
+            .unwrap();
+    let rotated = resp.headers()[RESUME_HEADER].to_str().unwrap();
+    assert_ne!(rotated, secret);
+    for _ in 0..50 {
+        if state.sessions.read().await.contains_key("resume-me") {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("resumed session was not registered");
+}
+
+#[tokio::test]
+async fn test_expired_reservation_frees_sid() {
+    let mut state = AppState::new(AuthMode::Fixed("fixed-token".to_string()), vec![0u8; 32]);
+    state.settings_mut().limits.resume_grace_secs = 0;
+    let state = Arc::new(state);
+    let addr = spawn_server(state.clone()).await;
+
+    let (ws, _) =
+        tokio_tungstenite::connect_async(ws_request(addr, "short", Some("fixed-token"), None))
+            .await
+            .unwrap();
+    drop(ws);
+    for _ in 0..50 {
+        if !state.sessions.read().await.contains_key("short") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // Without a grace period the sid is free for a fresh connect right away
+    tokio_tungstenite::connect_async(ws_request(addr, "short", Some("fixed-token"), None))
+        .await
+        .unwrap();
+}
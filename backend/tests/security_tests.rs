@@ -1,39 +1,18 @@
 use axum::http::StatusCode;
 use axum_test::TestServer;
-use std::collections::HashMap;
-use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::sync::mpsc;
 use tunly::{
-    create_app, AppState, AuthMode, ClientToServer, Metrics, ProxyResponse, ServerToClient,
+    create_app, AppState, AuthMode, ClientToServer, Features, ProxyResponse, ServerToClient,
     SessionState,
 };
 
 #[tokio::test]
 async fn test_concurrent_proxy_flooding() {
-    let state = Arc::new(AppState {
-        _token: String::new(),
-        req_id: AtomicU64::new(1),
-        auth_mode: AuthMode::Ephemeral,
-        jwt_secret: vec![0u8; 32],
-        issued_tokens: Mutex::new(HashMap::new()),
-        sessions: RwLock::new(HashMap::new()),
-        rl: Mutex::new(HashMap::new()),
-        proxy_rl: Mutex::new(HashMap::new()),
-        allow_token_query: false,
-        internal_key: None,
-        metrics: Metrics::new(),
-    });
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]));
 
     let (tx, mut rx) = mpsc::channel(100);
-    let session = Arc::new(SessionState {
-        outbound_tx: tx,
-        pending: Mutex::new(HashMap::new()),
-        _created_at: Instant::now(),
-        last_seen: Mutex::new(Instant::now()),
-        access_log: Mutex::new(Vec::new()),
-    });
+    let session = Arc::new(SessionState::new(tx, Features::default()));
 
     let sid = "concurrent-test-session".to_string();
     state
@@ -46,18 +25,16 @@ async fn test_concurrent_proxy_flooding() {
     let session_clone = session.clone();
     tokio::spawn(async move {
         while let Some(msg) = rx.recv().await {
-            match msg {
-                ServerToClient::ProxyRequest(req) => {
-                    let mut pending = session_clone.pending.lock().await;
-                    if let Some(otx) = pending.remove(&req.id) {
-                        let _ = otx.send(ClientToServer::ProxyResponse(ProxyResponse {
-                            id: req.id,
-                            status: 200,
-                            headers: vec![],
-                            body_b64: String::new(),
-                            is_compressed: false,
-                        }));
-                    }
+            if let ServerToClient::ProxyRequest(req) = msg {
+                let mut pending = session_clone.pending.lock().await;
+                if let Some(otx) = pending.remove(&req.id) {
+                    let _ = otx.send(ClientToServer::ProxyResponse(ProxyResponse {
+                        id: req.id,
+                        status: 200,
+                        headers: vec![],
+                        body_b64: String::new(),
+                        is_compressed: false,
+                    }));
                 }
             }
         }
@@ -85,19 +62,7 @@ async fn test_concurrent_proxy_flooding() {
 
 #[tokio::test]
 async fn test_server_health_check() {
-    let state = Arc::new(AppState {
-        _token: String::new(),
-        req_id: AtomicU64::new(1),
-        auth_mode: AuthMode::Ephemeral,
-        jwt_secret: vec![0u8; 32],
-        issued_tokens: Mutex::new(HashMap::new()),
-        sessions: RwLock::new(HashMap::new()),
-        rl: Mutex::new(HashMap::new()),
-        proxy_rl: Mutex::new(HashMap::new()),
-        allow_token_query: false,
-        internal_key: None,
-        metrics: Metrics::new(),
-    });
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]));
 
     let app =
         create_app(state.clone()).into_make_service_with_connect_info::<std::net::SocketAddr>();
@@ -111,19 +76,7 @@ async fn test_server_health_check() {
 
 #[tokio::test]
 async fn test_proxy_rate_limiting() {
-    let state = Arc::new(AppState {
-        _token: String::new(),
-        req_id: AtomicU64::new(1),
-        auth_mode: AuthMode::Ephemeral,
-        jwt_secret: vec![0u8; 32],
-        issued_tokens: Mutex::new(HashMap::new()),
-        sessions: RwLock::new(HashMap::new()),
-        rl: Mutex::new(HashMap::new()),
-        proxy_rl: Mutex::new(HashMap::new()),
-        internal_key: None,
-        allow_token_query: false,
-        metrics: Metrics::new(),
-    });
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]));
 
     let app =
         create_app(state.clone()).into_make_service_with_connect_info::<std::net::SocketAddr>();
@@ -143,29 +96,11 @@ async fn test_proxy_rate_limiting() {
 
 #[tokio::test]
 async fn test_body_size_limit() {
-    let state = Arc::new(AppState {
-        _token: String::new(),
-        req_id: AtomicU64::new(1),
-        auth_mode: AuthMode::Ephemeral,
-        jwt_secret: vec![0u8; 32],
-        issued_tokens: Mutex::new(HashMap::new()),
-        sessions: RwLock::new(HashMap::new()),
-        rl: Mutex::new(HashMap::new()),
-        proxy_rl: Mutex::new(HashMap::new()),
-        allow_token_query: false,
-        internal_key: None,
-        metrics: Metrics::new(),
-    });
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]));
 
     // Mock a session to reach the body size limit check in proxy_logic
     let (tx, _rx) = mpsc::channel(1);
-    let session = Arc::new(SessionState {
-        outbound_tx: tx,
-        pending: Mutex::new(HashMap::new()),
-        _created_at: Instant::now(),
-        last_seen: Mutex::new(Instant::now()),
-        access_log: Mutex::new(Vec::new()),
-    });
+    let session = Arc::new(SessionState::new(tx, Features::default()));
 
     state
         .sessions
@@ -191,19 +126,9 @@ async fn test_body_size_limit() {
 
 #[tokio::test]
 async fn test_jwt_auth_flow() {
-    let state = Arc::new(AppState {
-        _token: String::new(),
-        req_id: AtomicU64::new(1),
-        auth_mode: AuthMode::Ephemeral,
-        jwt_secret: vec![0u8; 32],
-        issued_tokens: Mutex::new(HashMap::new()),
-        sessions: RwLock::new(HashMap::new()),
-        rl: Mutex::new(HashMap::new()),
-        proxy_rl: Mutex::new(HashMap::new()),
-        allow_token_query: true,
-        internal_key: None,
-        metrics: Metrics::new(),
-    });
+    let mut state = AppState::new(AuthMode::Ephemeral, vec![0u8; 32]);
+    state.settings_mut().allow_token_query = true;
+    let state = Arc::new(state);
 
     let app =
         create_app(state.clone()).into_make_service_with_connect_info::<std::net::SocketAddr>();
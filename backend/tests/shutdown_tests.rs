@@ -0,0 +1,70 @@
+use axum::http::StatusCode;
+use axum_test::TestServer;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tunly::{create_app, AppState, AuthMode, Features, ServerToClient, SessionState};
+
+fn state_with_session(
+    sid: &str,
+) -> (
+    Arc<AppState>,
+    Arc<SessionState>,
+    mpsc::Receiver<ServerToClient>,
+) {
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]));
+    let (tx, rx) = mpsc::channel(16);
+    let session = Arc::new(SessionState::new(tx, Features::default()));
+    state
+        .sessions
+        .try_write()
+        .unwrap()
+        .insert(sid.to_string(), session.clone());
+    (state, session, rx)
+}
+
+#[tokio::test]
+async fn test_drain_notifies_clients_and_rejects_visitors() {
+    let (state, _session, mut rx) = state_with_session("drain-me");
+    let app =
+        create_app(state.clone()).into_make_service_with_connect_info::<std::net::SocketAddr>();
+    let server = TestServer::new(app).unwrap();
+
+    assert_eq!(server.get("/healthz").await.status_code(), StatusCode::OK);
+    assert!(state.drain(Duration::from_secs(1)).await);
+
+    match rx.recv().await {
+        Some(ServerToClient::GoingAway(notice)) => assert_eq!(notice.drain_secs, 1),
+        other => panic!("expected GoingAway, got {:?}", other),
+    }
+    assert_eq!(
+        server.get("/healthz").await.status_code(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    let response = server.get("/s/drain-me/").await;
+    assert_eq!(response.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().contains_key("retry-after"));
+}
+
+#[tokio::test]
+async fn test_drain_waits_for_pending_requests() {
+    let (state, session, _rx) = state_with_session("busy");
+    let (otx, _orx) = oneshot::channel();
+    session.pending.lock().await.insert(7, otx);
+
+    let finisher = session.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        finisher.pending.lock().await.remove(&7);
+    });
+    assert!(state.drain(Duration::from_secs(5)).await);
+}
+
+#[tokio::test]
+async fn test_drain_gives_up_after_timeout() {
+    let (state, session, _rx) = state_with_session("stuck");
+    let (otx, _orx) = oneshot::channel();
+    session.pending.lock().await.insert(1, otx);
+
+    assert!(!state.drain(Duration::from_millis(300)).await);
+}
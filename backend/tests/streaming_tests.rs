@@ -0,0 +1,98 @@
+use axum::http::StatusCode;
+use axum_test::TestServer;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tunly::{
+    create_app, AppState, AuthMode, BodyChunk, ClientToServer, Credit, Features, ResponseStart,
+    ServerToClient, SessionState,
+};
+
+fn test_state() -> Arc<AppState> {
+    Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]))
+}
+
+#[tokio::test]
+async fn test_streamed_body_round_trip() {
+    let state = test_state();
+
+    let (tx, mut rx) = mpsc::channel(64);
+    let session = Arc::new(SessionState::new(tx, Features::supported()));
+    state
+        .sessions
+        .write()
+        .await
+        .insert("stream-session".to_string(), session.clone());
+
+    // Mock client: collect the streamed request body, then echo it back in chunks
+    let client_session = session.clone();
+    tokio::spawn(async move {
+        let mut received = Vec::new();
+        let mut chunks = 0usize;
+        while let Some(msg) = rx.recv().await {
+            match msg {
+                ServerToClient::RequestStart(start) => assert!(start.has_body),
+                ServerToClient::RequestBody(chunk) => {
+                    chunks += 1;
+                    // Take each chunk as it comes, handing its credit back
+                    let credit = Credit {
+                        id: chunk.id,
+                        chunks: 1,
+                    };
+                    client_session.windows.grant(&credit);
+                    received.extend(chunk.into_data());
+                }
+                ServerToClient::RequestEnd(end) => {
+                    assert!(end.error.is_none());
+                    assert!(chunks > 1, "large body should arrive in several chunks");
+                    let tx = client_session.pending.lock().await.remove(&end.id).unwrap();
+                    let _ = tx.send(ClientToServer::ResponseStart(ResponseStart {
+                        id: end.id,
+                        status: 200,
+                        headers: vec![("content-type".into(), "application/octet-stream".into())],
+                    }));
+                    let body_tx = client_session
+                        .response_bodies
+                        .lock()
+                        .await
+                        .remove(&end.id)
+                        .unwrap();
+                    for piece in received.chunks(tunly::STREAM_CHUNK_SIZE) {
+                        let data = BodyChunk::new(end.id, piece).into_data();
+                        body_tx.send(Ok(data.into())).await.unwrap();
+                    }
+                    return;
+                }
+                _ => {}
+            }
+        }
+    });
+
+    let app =
+        create_app(state.clone()).into_make_service_with_connect_info::<std::net::SocketAddr>();
+    let server = TestServer::new(app).unwrap();
+
+    // Larger than the 2 MB buffered limit; streaming sessions are not capped
+    let payload: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let response = server
+        .post("/s/stream-session/upload")
+        .bytes(payload.clone().into())
+        .await;
+
+    assert_eq!(response.status_code(), StatusCode::OK);
+    assert_eq!(response.as_bytes().as_ref(), payload.as_slice());
+}
+
+#[test]
+fn test_feature_negotiation() {
+    let offered = Features::parse("stream, something-new");
+    assert!(offered.streaming);
+    assert_eq!(
+        offered.intersect(Features::supported()).to_header_value(),
+        "stream"
+    );
+    assert!(
+        !Features::parse("")
+            .intersect(Features::supported())
+            .streaming
+    );
+}
@@ -0,0 +1,244 @@
+mod common;
+
+use common::spawn_server;
+use futures::{SinkExt, StreamExt};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tunly::tcp::TcpConfig;
+use tunly::{
+    AppState, AuthMode, ClientToServer, CloseReason, ProxyResponse, FEATURES_HEADER,
+    NAME_KEY_HEADER, RESUME_HEADER,
+};
+
+type Ws =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+async fn fixed_server() -> (Arc<AppState>, SocketAddr) {
+    let state = Arc::new(AppState::new(
+        AuthMode::Fixed("fixed-token".to_string()),
+        vec![0u8; 32],
+    ));
+    let addr = spawn_server(state.clone()).await;
+    (state, addr)
+}
+
+/// `query` is `sid=...` or `name=...`; every request carries the shared fixed token.
+fn ws_request(addr: SocketAddr, query: &str) -> Request {
+    let mut req = format!("ws://{}/ws?{}", addr, query)
+        .into_client_request()
+        .unwrap();
+    req.headers_mut()
+        .insert("Authorization", "Bearer fixed-token".parse().unwrap());
+    req
+}
+
+/// Connect and wait until the server has registered the session.
+async fn connect(state: &AppState, req: Request, sid: &str) -> Result<(Ws, Option<String>), u16> {
+    let (ws, resp) = match tokio_tungstenite::connect_async(req).await {
+        Ok(ok) => ok,
+        Err(WsError::Http(resp)) => return Err(resp.status().as_u16()),
+        Err(e) => panic!("unexpected error: {}", e),
+    };
+    for _ in 0..50 {
+        if state.sessions.read().await.contains_key(sid) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let resume = resp
+        .headers()
+        .get(RESUME_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    Ok((ws, resume))
+}
+
+/// Close code the server ends `ws` with.
+async fn close_code(ws: &mut Ws) -> Option<u16> {
+    let wait = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(Ok(msg)) = ws.next().await {
+            if let Message::Close(frame) = msg {
+                return frame.map(|f| u16::from(f.code));
+            }
+        }
+        None
+    });
+    wait.await.ok().flatten()
+}
+
+#[tokio::test]
+async fn test_connected_session_cannot_be_hijacked() {
+    let (state, addr) = fixed_server().await;
+    let (_alice, _) = connect(&state, ws_request(addr, "sid=alice-demo"), "alice-demo")
+        .await
+        .unwrap();
+    assert_eq!(
+        connect(&state, ws_request(addr, "sid=alice-demo"), "alice-demo")
+            .await
+            .err(),
+        Some(409)
+    );
+
+    // A name claimed with just the shared token can be reattached after a disconnect, but is not
+    // handed over while its client is connected
+    let (_team, _) = connect(&state, ws_request(addr, "name=team-demo"), "team-demo")
+        .await
+        .unwrap();
+    assert_eq!(
+        connect(&state, ws_request(addr, "name=team-demo"), "team-demo")
+            .await
+            .err(),
+        Some(409)
+    );
+}
+
+#[tokio::test]
+async fn test_authorized_takeover_closes_old_connection() {
+    let (state, addr) = fixed_server().await;
+
+    // The resume secret proves ownership
+    let (mut old, resume) = connect(&state, ws_request(addr, "sid=resumed"), "resumed")
+        .await
+        .unwrap();
+    let mut req = ws_request(addr, "sid=resumed");
+    req.headers_mut()
+        .insert(RESUME_HEADER, resume.unwrap().parse().unwrap());
+    let (_new, _) = connect(&state, req, "resumed").await.unwrap();
+    assert_eq!(
+        close_code(&mut old).await,
+        Some(CloseReason::TakenOver.code())
+    );
+
+    // So does the name's own key
+    let named = |key: &str| {
+        let mut req = ws_request(addr, "name=acme-demo");
+        req.headers_mut()
+            .insert(NAME_KEY_HEADER, key.parse().unwrap());
+        req
+    };
+    let (mut old, _) = connect(&state, named("alice-key"), "acme-demo")
+        .await
+        .unwrap();
+    assert_eq!(
+        connect(&state, named("bob-key"), "acme-demo").await.err(),
+        Some(409)
+    );
+    let (_new, _) = connect(&state, named("alice-key"), "acme-demo")
+        .await
+        .unwrap();
+    assert_eq!(
+        close_code(&mut old).await,
+        Some(CloseReason::TakenOver.code())
+    );
+    assert!(state.sessions.read().await.contains_key("acme-demo"));
+}
+
+#[tokio::test]
+async fn test_name_claim_cannot_take_live_sid() {
+    let (state, addr) = fixed_server().await;
+    let (mut victim, _) = connect(&state, ws_request(addr, "sid=k3x9vq2m"), "k3x9vq2m")
+        .await
+        .unwrap();
+
+    // Claiming the victim's sid as a name with a fresh key does not make it the owner
+    let mut req = ws_request(addr, "name=k3x9vq2m");
+    req.headers_mut()
+        .insert(NAME_KEY_HEADER, "mallory-key".parse().unwrap());
+    assert_eq!(connect(&state, req, "k3x9vq2m").await.err(), Some(409));
+    assert!(state.store.name_owner("k3x9vq2m").await.is_none());
+
+    // The victim's connection is left alone
+    let still_open =
+        tokio::time::timeout(Duration::from_millis(300), close_code(&mut victim)).await;
+    assert!(still_open.is_err());
+}
+
+#[tokio::test]
+async fn test_rejected_takeover_does_not_need_a_tcp_port() {
+    let mut state = AppState::new(AuthMode::Fixed("fixed-token".to_string()), vec![0u8; 32]);
+    state.tcp = Some(TcpConfig {
+        bind_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        ports: 29250..=29250,
+    });
+    let state = Arc::new(state);
+    let addr = spawn_server(state.clone()).await;
+    let tcp_request = || {
+        let mut req = ws_request(addr, "sid=ssh-box&tcp=1");
+        req.headers_mut()
+            .insert(FEATURES_HEADER, "tcp".parse().unwrap());
+        req
+    };
+
+    // The only port is held by the connected client; a second connect is refused as a conflict
+    // before any port is allocated for it
+    let (_owner, _) = connect(&state, tcp_request(), "ssh-box").await.unwrap();
+    assert_eq!(
+        connect(&state, tcp_request(), "ssh-box").await.err(),
+        Some(409)
+    );
+    let session = state.sessions.read().await["ssh-box"].clone();
+    assert_eq!(session.tcp_port, Some(29250));
+}
+
+#[tokio::test]
+async fn test_connection_answers_its_own_session_after_losing_the_sid() {
+    let (state, addr) = fixed_server().await;
+    let (mut ws, _) = connect(&state, ws_request(addr, "sid=bob-demo"), "bob-demo")
+        .await
+        .unwrap();
+    // As after a takeover: the sid no longer points at this connection's session
+    let session = state.sessions.write().await.remove("bob-demo").unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    session.pending.lock().await.insert(42, tx);
+
+    let answer = ClientToServer::ProxyResponse(ProxyResponse {
+        id: 42,
+        status: 204,
+        headers: vec![],
+        body_b64: String::new(),
+        is_compressed: false,
+    });
+    let text = serde_json::to_string(&answer).unwrap();
+    ws.send(Message::Text(text.into())).await.unwrap();
+
+    let reply = tokio::time::timeout(Duration::from_secs(5), rx)
+        .await
+        .expect("answer dispatched to the connection's own session")
+        .unwrap();
+    assert!(matches!(reply, ClientToServer::ProxyResponse(r) if r.status == 204));
+}
+
+#[tokio::test]
+async fn test_fixed_token_does_not_own_a_reserved_name() {
+    let (state, addr) = fixed_server().await;
+    // Claimed without a name key: the shared fixed token stands in as the owner
+    let (ws, resume) = connect(&state, ws_request(addr, "name=team-demo"), "team-demo")
+        .await
+        .unwrap();
+    drop(ws);
+    for _ in 0..50 {
+        if !state.sessions.read().await.contains_key("team-demo") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(!state.sessions.read().await.contains_key("team-demo"));
+
+    // Anyone else holding the token cannot take the reserved sid during the grace period
+    assert_eq!(
+        connect(&state, ws_request(addr, "name=team-demo"), "team-demo")
+            .await
+            .err(),
+        Some(409)
+    );
+
+    // Its resume secret still brings the owner back
+    let mut req = ws_request(addr, "name=team-demo");
+    req.headers_mut()
+        .insert(RESUME_HEADER, resume.unwrap().parse().unwrap());
+    assert!(connect(&state, req, "team-demo").await.is_ok());
+}
@@ -0,0 +1,153 @@
+use axum::http::StatusCode;
+use axum_test::TestServer;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tunly::tcp::{self, TcpConfig};
+use tunly::{
+    create_app, AppState, AuthMode, ClientToServer, Credit, Features, ServerToClient, SessionState,
+    StreamEnd, STREAM_BUFFER_CHUNKS,
+};
+
+fn test_state() -> Arc<AppState> {
+    Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]))
+}
+
+#[test]
+fn test_parse_port_range() {
+    assert_eq!(tcp::parse_port_range("20000-20010").unwrap(), 20000..=20010);
+    assert_eq!(tcp::parse_port_range("2222").unwrap(), 2222..=2222);
+    assert!(tcp::parse_port_range("20010-20000").is_err());
+    assert!(tcp::parse_port_range("0-10").is_err());
+    assert!(tcp::parse_port_range("ssh").is_err());
+}
+
+#[tokio::test]
+async fn test_tcp_connection_is_relayed() {
+    let state = test_state();
+    let config = TcpConfig {
+        bind_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        ports: 29000..=29100,
+    };
+    let tunnel = tcp::allocate(&config, None)
+        .await
+        .expect("free port in range");
+    let port = tunnel.port;
+
+    let (tx, mut rx) = mpsc::channel(64);
+    let mut session = SessionState::new(tx, Features::supported());
+    session.tcp_port = Some(port);
+    let session = Arc::new(session);
+    tokio::spawn(tcp::serve(
+        tunnel,
+        state.clone(),
+        session.clone(),
+        "tcp-session".to_string(),
+    ));
+
+    // Mock client: echo every chunk back and close when the visitor does
+    let client_session = session.clone();
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            match msg {
+                ServerToClient::TcpOpen(open) => assert!(open.peer.starts_with("127.0.0.1:")),
+                ServerToClient::TcpData(chunk) => {
+                    let stream = client_session.streams.lock().await.get(&chunk.id).cloned();
+                    if let Some(stream) = stream {
+                        let _ = stream.send(ClientToServer::TcpData(chunk)).await;
+                    }
+                }
+                ServerToClient::TcpClose(end) => {
+                    let stream = client_session.streams.lock().await.get(&end.id).cloned();
+                    if let Some(stream) = stream {
+                        let _ = stream
+                            .send(ClientToServer::TcpClose(StreamEnd {
+                                id: end.id,
+                                error: None,
+                            }))
+                            .await;
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
+
+    let mut visitor = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    visitor.write_all(b"ping over tcp").await.unwrap();
+    visitor.shutdown().await.unwrap();
+
+    let mut echoed = Vec::new();
+    visitor.read_to_end(&mut echoed).await.unwrap();
+    assert_eq!(echoed, b"ping over tcp");
+}
+
+#[tokio::test]
+async fn test_http_proxy_rejected_for_tcp_session() {
+    let state = test_state();
+    let (tx, _rx) = mpsc::channel(1);
+    let mut session = SessionState::new(tx, Features::supported());
+    session.tcp_port = Some(29999);
+    state
+        .sessions
+        .write()
+        .await
+        .insert("tcp-only".to_string(), Arc::new(session));
+
+    let app = create_app(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+    let server = TestServer::new(app).unwrap();
+
+    let response = server.get("/s/tcp-only/").await;
+    assert_eq!(response.status_code(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_tcp_data_waits_for_client_credit() {
+    let state = test_state();
+    let config = TcpConfig {
+        bind_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        ports: 29300..=29400,
+    };
+    let tunnel = tcp::allocate(&config, None)
+        .await
+        .expect("free port in range");
+    let port = tunnel.port;
+    let (tx, mut rx) = mpsc::channel(256);
+    let session = Arc::new(SessionState::new(tx, Features::supported()));
+    tokio::spawn(tcp::serve(
+        tunnel,
+        state.clone(),
+        session.clone(),
+        "paced".to_string(),
+    ));
+
+    let mut visitor = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        let _ = visitor.write_all(&vec![1u8; 4 * 1024 * 1024]).await;
+        std::future::pending::<()>().await;
+    });
+
+    // Count the chunks the server sends until it goes quiet
+    async fn drain(rx: &mut mpsc::Receiver<ServerToClient>, id: &mut u64) -> usize {
+        let mut chunks = 0;
+        let quiet = std::time::Duration::from_millis(300);
+        while let Ok(Some(msg)) = tokio::time::timeout(quiet, rx.recv()).await {
+            match msg {
+                ServerToClient::TcpOpen(open) => *id = open.id,
+                ServerToClient::TcpData(_) => chunks += 1,
+                _ => {}
+            }
+        }
+        chunks
+    }
+    let mut id = 0;
+    assert_eq!(drain(&mut rx, &mut id).await, STREAM_BUFFER_CHUNKS);
+
+    session.windows.grant(&Credit { id, chunks: 4 });
+    assert_eq!(drain(&mut rx, &mut id).await, 4);
+}
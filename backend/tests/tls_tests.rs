@@ -0,0 +1,150 @@
+use axum::serve::ListenerExt;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{client::TlsStream, TlsAcceptor, TlsConnector};
+use tunly::tls::{load_certified_key, server_config, ReloadableCert, TlsListener};
+use tunly::{create_app, AppState, AuthMode};
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+/// Accepts any certificate; the tests look at which one was served.
+#[derive(Debug)]
+struct AnyCert;
+
+impl ServerCertVerifier for AnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+async fn connect(addr: SocketAddr) -> TlsStream<TcpStream> {
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AnyCert))
+            .with_no_client_auth();
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), tcp)
+        .await
+        .unwrap()
+}
+
+fn served_cert(stream: &TlsStream<TcpStream>) -> Vec<u8> {
+    stream.get_ref().1.peer_certificates().unwrap()[0].to_vec()
+}
+
+async fn healthz(stream: &mut TlsStream<TcpStream>) -> String {
+    stream
+        .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buf = vec![0u8; 4096];
+    let n = stream.read(&mut buf).await.unwrap();
+    String::from_utf8_lossy(&buf[..n])
+        .lines()
+        .next()
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn test_load_certified_key() {
+    assert!(load_certified_key(&fixture("tls-a.crt"), &fixture("tls-a.key")).is_ok());
+    // A key for another certificate is refused instead of failing every handshake later
+    assert!(load_certified_key(&fixture("tls-a.crt"), &fixture("tls-b.key")).is_err());
+    assert!(load_certified_key(&fixture("tls-a.key"), &fixture("tls-a.key")).is_err());
+}
+
+#[tokio::test]
+async fn test_tls_certificate_hot_reload() {
+    let dir = std::env::temp_dir().join(format!("tunly-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::copy(fixture("tls-a.crt"), &cert_path).unwrap();
+    std::fs::copy(fixture("tls-a.key"), &key_path).unwrap();
+
+    let cert = Arc::new(ReloadableCert::load(cert_path.clone(), key_path.clone()).unwrap());
+    let acceptor = TlsAcceptor::from(Arc::new(server_config(cert.clone())));
+    let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = tcp.local_addr().unwrap();
+    let app = create_app(Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32])))
+        .into_make_service_with_connect_info::<SocketAddr>();
+    let listener = TlsListener::new(tcp, acceptor);
+    tokio::spawn(async move { axum::serve(listener.tap_io(|_| {}), app).await.unwrap() });
+
+    let cert_a = CertificateDer::from_pem_file(fixture("tls-a.crt")).unwrap();
+    let cert_b = CertificateDer::from_pem_file(fixture("tls-b.crt")).unwrap();
+
+    let mut before = connect(addr).await;
+    assert_eq!(served_cert(&before), cert_a.to_vec());
+    assert!(healthz(&mut before).await.contains("200"));
+
+    // A broken file keeps the current certificate
+    std::fs::write(&cert_path, "not a certificate").unwrap();
+    assert!(cert.reload().is_err());
+    assert_eq!(served_cert(&connect(addr).await), cert_a.to_vec());
+
+    std::fs::copy(fixture("tls-b.crt"), &cert_path).unwrap();
+    std::fs::copy(fixture("tls-b.key"), &key_path).unwrap();
+    cert.reload().unwrap();
+    assert_eq!(served_cert(&connect(addr).await), cert_b.to_vec());
+
+    // Connections opened before the reload keep working
+    assert!(healthz(&mut before).await.contains("200"));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use common::spawn_server;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tunly::keys::KeySet;
+use tunly::tokens::{glob_match, parse_ttl, verify, AccessClaims};
+use tunly::{AppState, AuthMode};
+
+const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+type Ws =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+async fn connect(addr: SocketAddr, query: &str, token: &str) -> Result<Ws, u16> {
+    let mut req = format!("ws://{}/ws?{}", addr, query)
+        .into_client_request()
+        .unwrap();
+    req.headers_mut().insert(
+        "Authorization",
+        format!("Bearer {}", token).parse().unwrap(),
+    );
+    match tokio_tungstenite::connect_async(req).await {
+        Ok((ws, _)) => Ok(ws),
+        Err(WsError::Http(resp)) => Err(resp.status().as_u16()),
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+fn access_token(sids: &[&str], max_sessions: u32) -> (AccessClaims, String) {
+    let claims = AccessClaims::new(
+        "alice@example.com",
+        Duration::from_secs(3600),
+        sids.iter().map(|s| s.to_string()).collect(),
+        max_sessions,
+    );
+    let token = claims.sign(&KeySet::from_secret(SECRET)).unwrap();
+    (claims, token)
+}
+
+#[test]
+fn test_glob_and_ttl_parsing() {
+    assert!(glob_match("alice-*", "alice-demo"));
+    assert!(glob_match("alice-*", "alice-"));
+    assert!(glob_match("*-demo", "bob-demo"));
+    assert!(glob_match("a*c*e", "abcde"));
+    assert!(glob_match("exact", "exact"));
+    assert!(!glob_match("exact", "exactly"));
+    assert!(!glob_match("alice-*", "bob-demo"));
+
+    assert_eq!(parse_ttl("90").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_ttl("12h").unwrap(), Duration::from_secs(12 * 3600));
+    assert_eq!(parse_ttl("30d").unwrap(), Duration::from_secs(30 * 86400));
+    assert!(parse_ttl("0").is_err());
+    assert!(parse_ttl("5w").is_err());
+    // Too large to count in seconds
+    assert!(parse_ttl("18446744073709551615d").is_err());
+    assert!(parse_ttl("307445734561825861m").is_err());
+    assert!(parse_ttl("18446744073709551615s").is_ok());
+}
+
+#[test]
+fn test_access_token_roundtrip() {
+    let (claims, token) = access_token(&["alice-*"], 2);
+    assert_eq!(verify(&token, &KeySet::from_secret(SECRET)), Some(claims));
+    assert!(verify(&token, &KeySet::from_secret(b"another-secret")).is_none());
+}
+
+#[tokio::test]
+async fn test_access_token_accepted_next_to_fixed_token() {
+    let state = Arc::new(AppState::new(
+        AuthMode::Fixed("shared".to_string()),
+        SECRET.to_vec(),
+    ));
+    let addr = spawn_server(state.clone()).await;
+    let (_, token) = access_token(&["alice-*"], 0);
+
+    assert!(connect(addr, "sid=alice-one", &token).await.is_ok());
+    assert_eq!(connect(addr, "sid=bob-one", &token).await.err(), Some(403));
+    assert!(connect(addr, "sid=bob-one", "shared").await.is_ok());
+}
+
+#[tokio::test]
+async fn test_access_token_session_limit() {
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, SECRET.to_vec()));
+    let addr = spawn_server(state.clone()).await;
+    let (_, token) = access_token(&[], 1);
+
+    let _first = connect(addr, "sid=limit-one", &token).await.unwrap();
+    for _ in 0..50 {
+        if state.sessions.read().await.contains_key("limit-one") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(
+        connect(addr, "sid=limit-two", &token).await.err(),
+        Some(429)
+    );
+}
+
+#[tokio::test]
+async fn test_revoked_access_token_refused() {
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, SECRET.to_vec()));
+    let addr = spawn_server(state.clone()).await;
+    let (claims, token) = access_token(&[], 0);
+
+    assert!(state.store.revoke(&claims.jti).await.unwrap());
+    assert_eq!(connect(addr, "sid=revoked", &token).await.err(), Some(401));
+}
+
+#[tokio::test]
+async fn test_access_token_holder_owns_names() {
+    let state = Arc::new(AppState::new(AuthMode::Ephemeral, SECRET.to_vec()));
+    let addr = spawn_server(state.clone()).await;
+    let (_, alice) = access_token(&[], 0);
+    let bob = AccessClaims::new("bob@example.com", Duration::from_secs(3600), vec![], 0)
+        .sign(&KeySet::from_secret(SECRET))
+        .unwrap();
+
+    drop(connect(addr, "name=alice-demo", &alice).await.unwrap());
+    assert!(state.store.name_owner("alice-demo").await.is_some());
+    assert_eq!(
+        connect(addr, "name=alice-demo", &bob).await.err(),
+        Some(409)
+    );
+}
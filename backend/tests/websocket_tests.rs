@@ -0,0 +1,133 @@
+mod common;
+
+use axum::http::StatusCode;
+use axum_test::TestServer;
+use common::spawn_server;
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tunly::{
+    create_app, AppState, AuthMode, ClientToServer, Features, ServerToClient, SessionState,
+    WsOpened,
+};
+
+fn test_state() -> Arc<AppState> {
+    Arc::new(AppState::new(AuthMode::Ephemeral, vec![0u8; 32]))
+}
+
+#[tokio::test]
+async fn test_visitor_websocket_is_relayed() {
+    let state = test_state();
+    let (tx, mut rx) = mpsc::channel(64);
+    let session = Arc::new(SessionState::new(tx, Features::supported()));
+    state
+        .sessions
+        .write()
+        .await
+        .insert("ws-session".to_string(), session.clone());
+
+    // Mock client: accept the upgrade and echo every frame back
+    let client_session = session.clone();
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            match msg {
+                ServerToClient::WsOpen(open) => {
+                    assert_eq!(open.uri, "/live");
+                    let tx = client_session
+                        .pending
+                        .lock()
+                        .await
+                        .remove(&open.id)
+                        .unwrap();
+                    let _ = tx.send(ClientToServer::WsOpened(WsOpened {
+                        id: open.id,
+                        status: 101,
+                        protocol: None,
+                        error: None,
+                    }));
+                }
+                ServerToClient::WsFrame(frame) => {
+                    let stream = client_session
+                        .streams
+                        .lock()
+                        .await
+                        .get(&frame.id)
+                        .cloned()
+                        .unwrap();
+                    stream.send(ClientToServer::WsFrame(frame)).await.unwrap();
+                }
+                _ => {}
+            }
+        }
+    });
+
+    let addr = spawn_server(state.clone()).await;
+
+    let url = format!("ws://{}/s/ws-session/live", addr);
+    let (mut visitor, resp) = tokio_tungstenite::connect_async(url).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 101);
+
+    visitor.send(Message::Text("hello".into())).await.unwrap();
+    let echoed = visitor.next().await.unwrap().unwrap();
+    assert_eq!(echoed, Message::Text("hello".into()));
+}
+
+#[tokio::test]
+async fn test_websocket_upgrade_requires_client_support() {
+    let state = test_state();
+    let (tx, _rx) = mpsc::channel(1);
+    let session = Arc::new(SessionState::new(tx, Features::default()));
+    state
+        .sessions
+        .write()
+        .await
+        .insert("legacy-session".to_string(), session);
+
+    let app = create_app(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+    let server = TestServer::new(app).unwrap();
+
+    let response = server
+        .get("/s/legacy-session/live")
+        .add_header(axum::http::header::UPGRADE, "websocket")
+        .add_header(axum::http::header::CONNECTION, "upgrade")
+        .add_header(
+            axum::http::header::SEC_WEBSOCKET_KEY,
+            "dGhlIHNhbXBsZSBub25jZQ==",
+        )
+        .add_header(axum::http::header::SEC_WEBSOCKET_VERSION, "13")
+        .await;
+    assert_eq!(response.status_code(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn test_abandoned_upgrade_closes_client_socket() {
+    let mut state = AppState::new(AuthMode::Ephemeral, vec![0u8; 32]);
+    state.settings_mut().limits.upstream_timeout_secs = 1;
+    let state = Arc::new(state);
+    let (tx, mut rx) = mpsc::channel(64);
+    let session = Arc::new(SessionState::new(tx, Features::supported()));
+    state
+        .sessions
+        .write()
+        .await
+        .insert("slow-ws".to_string(), session.clone());
+    let addr = spawn_server(state.clone()).await;
+
+    // The client never answers the upgrade in time
+    let url = format!("ws://{}/s/slow-ws/live", addr);
+    let err = tokio_tungstenite::connect_async(url).await.unwrap_err();
+    assert!(matches!(err, WsError::Http(resp) if resp.status() == 502));
+
+    // ...and is told to drop the local socket it may open late
+    let Some(ServerToClient::WsOpen(open)) = rx.recv().await else {
+        panic!("expected ws_open");
+    };
+    let next = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv()).await;
+    let Ok(Some(ServerToClient::WsClose(close))) = next else {
+        panic!("expected ws_close");
+    };
+    assert_eq!(close.id, open.id);
+    assert!(session.streams.lock().await.is_empty());
+}
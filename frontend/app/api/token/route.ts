@@ -3,9 +3,17 @@ import { NextResponse } from 'next/server'
 export const dynamic = 'force-dynamic'
 export const revalidate = 0
 
-export async function GET() {
+export async function GET(request: Request) {
   const base = process.env.BACKEND_BASE_URL || 'https://app.tunly.online'
-  const target = `${base.replace(/\/$/, '')}/token`
+  // Forward the client's public key (proof of possession) and requested name
+  const params = new URLSearchParams()
+  const incoming = new URL(request.url).searchParams
+  for (const key of ['pubkey', 'name']) {
+    const value = incoming.get(key)
+    if (value) params.set(key, value)
+  }
+  const query = params.toString()
+  const target = `${base.replace(/\/$/, '')}/token${query ? `?${query}` : ''}`
 
   try {
     const res = await fetch(target, { 